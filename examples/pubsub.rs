@@ -0,0 +1,81 @@
+//! A small pub/sub broker and clients
+//!
+//! ```text
+//! cargo run --example pubsub -- broker 0.0.0.0:9000
+//! cargo run --example pubsub -- subscribe 127.0.0.1:9000 'quotes.>'
+//! cargo run --example pubsub -- publish 127.0.0.1:9000 quotes.nyse.IBM
+//! ```
+//!
+//! The publisher sends every line read from stdin as a message, with a TTL of 500ms.  The broker
+//! prints the lag of each subscriber every few seconds.
+
+use std::env;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use udt::pubsub::{BrokerConfig, Publisher, Subscriber};
+
+fn usage() -> ! {
+    eprintln!("usage: pubsub broker ADDR");
+    eprintln!("       pubsub subscribe ADDR PATTERN...");
+    eprintln!("       pubsub publish ADDR TOPIC");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage();
+    }
+    let addr: SocketAddr = args[1].parse().unwrap_or_else(|_| usage());
+    udt::init();
+
+    match args[0].as_str() {
+        "broker" => {
+            let broker = BrokerConfig::new()
+                .max_lag(10_000)
+                .bind(addr)
+                .expect("failed to start the broker");
+            println!("listening on {}", broker.local_addr().unwrap());
+            loop {
+                thread::sleep(Duration::from_secs(5));
+                println!("{} messages published", broker.published());
+                for sub in broker.subscribers() {
+                    println!(
+                        "  {} {:?}: lag {} packets, {} delivered, {} skipped",
+                        sub.addr, sub.patterns, sub.lag, sub.delivered, sub.skipped
+                    );
+                }
+            }
+        }
+        "subscribe" if args.len() > 2 => {
+            let sub = Subscriber::connect(addr).expect("failed to connect");
+            for pattern in &args[2..] {
+                sub.subscribe(pattern).expect("failed to subscribe");
+            }
+            loop {
+                match sub.recv() {
+                    Ok(msg) => println!("{}: {}", msg.topic, String::from_utf8_lossy(&msg.payload)),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        break;
+                    }
+                }
+            }
+        }
+        "publish" if args.len() == 3 => {
+            let publisher = Publisher::connect(addr).expect("failed to connect");
+            let ttl = Some(Duration::from_millis(500));
+            for line in io::stdin().lock().lines() {
+                let line = line.expect("failed to read stdin");
+                publisher
+                    .publish(&args[2], line.as_bytes(), ttl)
+                    .expect("failed to publish");
+            }
+        }
+        _ => usage(),
+    }
+}
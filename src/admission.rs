@@ -0,0 +1,472 @@
+//! Connection admission control for a `UdtListener`
+//!
+//! UDT completes the handshake with every peer before `accept` returns, so the only way to
+//! refuse a peer is to close the socket right after accepting it.  An `AdmissionPolicy` does
+//! that for you: it checks each accepted peer against IP allow/deny lists, a per-source
+//! connection limit and a global accept rate, closes the rejected sockets and keeps count of
+//! why they were rejected.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::admission::AdmissionPolicy;
+//!
+//! init();
+//! let listener = UdtListener::bind("0.0.0.0:9000".parse().unwrap(), 16).unwrap();
+//! let policy = AdmissionPolicy::new()
+//!     .deny("10.1.2.0/24".parse().unwrap())
+//!     .max_per_source(4)
+//!     .rate_limit(100, Duration::from_secs(1));
+//!
+//! loop {
+//!     let (conn, peer) = policy.accept(&listener).unwrap();
+//!     println!("admitted {:?}", peer);
+//!     // `conn` releases its per-source slot when dropped
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{UdtError, UdtListener, UdtStream};
+
+/// An IPv4 or IPv6 network in CIDR notation, such as `192.168.0.0/16`
+///
+/// A bare address without a prefix length matches only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Creates a network from an address and a prefix length.
+    ///
+    /// Returns `None` if the prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<IpNet> {
+        let max = match addr {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+        if prefix > max {
+            None
+        } else {
+            Some(IpNet { addr, prefix })
+        }
+    }
+
+    /// Returns true if `ip` lies within this network.
+    ///
+    /// IPv4 networks never match IPv6 addresses and vice versa.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Error returned when parsing an `IpNet` fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetParseError(String);
+
+impl fmt::Display for IpNetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid network {:?}", self.0)
+    }
+}
+
+impl std::error::Error for IpNetParseError {}
+
+impl FromStr for IpNet {
+    type Err = IpNetParseError;
+
+    fn from_str(s: &str) -> Result<IpNet, IpNetParseError> {
+        let err = || IpNetParseError(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| err())?;
+        let prefix = match parts.next() {
+            Some(p) => p.parse().map_err(|_| err())?,
+            None => match addr {
+                IpAddr::V4(..) => 32,
+                IpAddr::V6(..) => 128,
+            },
+        };
+        IpNet::new(addr, prefix).ok_or_else(err)
+    }
+}
+
+/// Why a peer was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// The peer matched an entry in the deny list
+    Denied,
+    /// An allow list is configured and the peer matched none of its entries
+    NotAllowed,
+    /// The peer already has the maximum number of open connections
+    TooManyFromSource,
+    /// The global accept rate limit was exceeded
+    RateLimited,
+}
+
+/// Counters kept by an `AdmissionPolicy`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+    /// Number of connections handed to the application
+    pub admitted: u64,
+    /// Rejections because of the deny list
+    pub denied: u64,
+    /// Rejections because the peer was not on the allow list
+    pub not_allowed: u64,
+    /// Rejections because of the per-source connection limit
+    pub too_many_from_source: u64,
+    /// Rejections because of the accept rate limit
+    pub rate_limited: u64,
+}
+
+impl AdmissionStats {
+    /// Total number of rejected connections, for any reason.
+    pub fn rejected(&self) -> u64 {
+        self.denied + self.not_allowed + self.too_many_from_source + self.rate_limited
+    }
+
+    fn count(&mut self, reason: RejectReason) {
+        match reason {
+            RejectReason::Denied => self.denied += 1,
+            RejectReason::NotAllowed => self.not_allowed += 1,
+            RejectReason::TooManyFromSource => self.too_many_from_source += 1,
+            RejectReason::RateLimited => self.rate_limited += 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimit {
+    burst: f64,
+    per_sec: f64,
+}
+
+#[derive(Debug)]
+struct State {
+    open: HashMap<IpAddr, usize>,
+    tokens: f64,
+    refilled: Instant,
+    stats: AdmissionStats,
+}
+
+/// Decides which accepted peers are handed to the application
+///
+/// An `AdmissionPolicy` is cheap to clone; clones share their counters and connection limits,
+/// so one policy can guard several accept threads.
+#[derive(Debug, Clone)]
+pub struct AdmissionPolicy {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    max_per_source: Option<usize>,
+    rate: Option<Arc<RateLimit>>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> AdmissionPolicy {
+        AdmissionPolicy::new()
+    }
+}
+
+impl AdmissionPolicy {
+    /// Creates a policy that admits everyone.
+    pub fn new() -> AdmissionPolicy {
+        AdmissionPolicy {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_per_source: None,
+            rate: None,
+            state: Arc::new(Mutex::new(State {
+                open: HashMap::new(),
+                tokens: 0.0,
+                refilled: Instant::now(),
+                stats: AdmissionStats::default(),
+            })),
+        }
+    }
+
+    /// Adds a network to the allow list.
+    ///
+    /// Once the allow list is non-empty, only peers within one of its networks are admitted.
+    pub fn allow(mut self, net: IpNet) -> AdmissionPolicy {
+        self.allow.push(net);
+        self
+    }
+
+    /// Adds a network to the deny list.  The deny list is checked before the allow list.
+    pub fn deny(mut self, net: IpNet) -> AdmissionPolicy {
+        self.deny.push(net);
+        self
+    }
+
+    /// Limits the number of open connections admitted from a single IP address.
+    pub fn max_per_source(mut self, max: usize) -> AdmissionPolicy {
+        self.max_per_source = Some(max);
+        self
+    }
+
+    /// Admits at most `count` connections per `period`, with bursts of up to `count`.
+    pub fn rate_limit(mut self, count: u32, period: Duration) -> AdmissionPolicy {
+        self.rate = Some(Arc::new(RateLimit {
+            burst: f64::from(count),
+            per_sec: f64::from(count) / period.as_secs_f64(),
+        }));
+        self.state.lock().unwrap().tokens = f64::from(count);
+        self
+    }
+
+    /// Returns a snapshot of the admission counters.
+    pub fn stats(&self) -> AdmissionStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Returns the number of admitted connections from `ip` that are still open.
+    pub fn open_connections(&self, ip: IpAddr) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .open
+            .get(&ip)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Checks a peer address against the policy.
+    ///
+    /// On success the returned `AdmissionSlot` counts towards the per-source limit until it is
+    /// dropped.  This is the building block for `accept`; use it directly when accepting with
+    /// `UdtSocket::accept` or an `Epoll` loop.
+    pub fn admit(&self, peer: SocketAddr) -> Result<AdmissionSlot, RejectReason> {
+        let ip = peer.ip();
+        let mut state = self.state.lock().unwrap();
+        let verdict = self.check(&mut state, ip);
+        match verdict {
+            Ok(()) => {
+                state.stats.admitted += 1;
+                *state.open.entry(ip).or_insert(0) += 1;
+                Ok(AdmissionSlot {
+                    ip,
+                    state: self.state.clone(),
+                })
+            }
+            Err(reason) => {
+                state.stats.count(reason);
+                Err(reason)
+            }
+        }
+    }
+
+    fn check(&self, state: &mut State, ip: IpAddr) -> Result<(), RejectReason> {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return Err(RejectReason::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(ip)) {
+            return Err(RejectReason::NotAllowed);
+        }
+        if let Some(max) = self.max_per_source {
+            if state.open.get(&ip).cloned().unwrap_or(0) >= max {
+                return Err(RejectReason::TooManyFromSource);
+            }
+        }
+        if let Some(ref rate) = self.rate {
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate.per_sec).min(rate.burst);
+            state.refilled = now;
+            if state.tokens < 1.0 {
+                return Err(RejectReason::RateLimited);
+            }
+            state.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    /// Accepts connections from `listener` until one passes the policy.
+    ///
+    /// Rejected sockets are closed immediately.
+    pub fn accept(&self, listener: &UdtListener) -> Result<(Admitted, SocketAddr), UdtError> {
+        loop {
+            let (stream, peer) = listener.accept()?;
+            if let Some(admitted) = self.filter(stream, peer) {
+                return Ok((admitted, peer));
+            }
+        }
+    }
+
+    /// Like `accept`, but gives up after `timeout` with an `ETIMEOUT` error.
+    pub fn accept_timeout(
+        &self,
+        listener: &UdtListener,
+        timeout: Duration,
+    ) -> Result<(Admitted, SocketAddr), UdtError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let (stream, peer) = listener.accept_timeout(left)?;
+            if let Some(admitted) = self.filter(stream, peer) {
+                return Ok((admitted, peer));
+            }
+        }
+    }
+
+    fn filter(&self, stream: UdtStream, peer: SocketAddr) -> Option<Admitted> {
+        match self.admit(peer) {
+            Ok(slot) => Some(Admitted { stream, slot }),
+            Err(reason) => {
+                debug!("rejecting connection from {:?}: {:?}", peer, reason);
+                let _ = stream.close();
+                None
+            }
+        }
+    }
+}
+
+/// Holds a per-source connection slot of an `AdmissionPolicy` until dropped
+#[derive(Debug)]
+pub struct AdmissionSlot {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for AdmissionSlot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let gone = match state.open.get_mut(&self.ip) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            None => false,
+        };
+        if gone {
+            state.open.remove(&self.ip);
+        }
+    }
+}
+
+/// A `UdtStream` admitted by an `AdmissionPolicy`
+///
+/// Dereferences to the `UdtStream`.  Dropping it closes the stream and frees its per-source
+/// slot.
+#[derive(Debug)]
+pub struct Admitted {
+    stream: UdtStream,
+    slot: AdmissionSlot,
+}
+
+impl Admitted {
+    /// Splits into the stream and the slot, e.g. to keep the slot alive in another structure.
+    pub fn into_parts(self) -> (UdtStream, AdmissionSlot) {
+        (self.stream, self.slot)
+    }
+}
+
+impl Deref for Admitted {
+    type Target = UdtStream;
+
+    fn deref(&self) -> &UdtStream {
+        &self.stream
+    }
+}
+
+impl DerefMut for Admitted {
+    fn deref_mut(&mut self) -> &mut UdtStream {
+        &mut self.stream
+    }
+}
+
+impl io::Read for Admitted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl io::Write for Admitted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[test]
+fn test_ipnet() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let net: IpNet = "192.168.10.0/23".parse().unwrap();
+    assert!(net.contains(Ipv4Addr::new(192, 168, 11, 200).into()));
+    assert!(!net.contains(Ipv4Addr::new(192, 168, 12, 1).into()));
+    assert!(!net.contains(Ipv6Addr::LOCALHOST.into()));
+
+    let host: IpNet = "127.0.0.2".parse().unwrap();
+    assert_eq!(host.to_string(), "127.0.0.2/32");
+    assert!(host.contains(Ipv4Addr::new(127, 0, 0, 2).into()));
+    assert!(!host.contains(Ipv4Addr::new(127, 0, 0, 3).into()));
+
+    let any: IpNet = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains(Ipv4Addr::new(8, 8, 8, 8).into()));
+
+    let v6: IpNet = "fe80::/10".parse().unwrap();
+    assert!(v6.contains("fe80::1".parse().unwrap()));
+    assert!(!v6.contains(Ipv6Addr::LOCALHOST.into()));
+
+    assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+    assert!("not-an-ip/8".parse::<IpNet>().is_err());
+}
+
+#[test]
+fn test_admit_without_socket() {
+    let policy = AdmissionPolicy::new()
+        .deny("10.0.0.0/8".parse().unwrap())
+        .max_per_source(2);
+    let peer: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+
+    assert_eq!(
+        policy.admit("10.1.1.1:9000".parse().unwrap()).unwrap_err(),
+        RejectReason::Denied
+    );
+    let a = policy.admit(peer).unwrap();
+    let _b = policy.admit(peer).unwrap();
+    assert_eq!(
+        policy.admit(peer).unwrap_err(),
+        RejectReason::TooManyFromSource
+    );
+    drop(a);
+    let _c = policy.admit(peer).unwrap();
+
+    let stats = policy.stats();
+    assert_eq!(stats.admitted, 3);
+    assert_eq!(stats.rejected(), 2);
+}
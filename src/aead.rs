@@ -0,0 +1,583 @@
+//! Authenticated encryption for `Datagram` sockets
+//!
+//! TLS needs a reliable byte stream, so it can't protect a `SocketType::Datagram` socket whose
+//! messages may expire (see `UdtSocket::sendmsg_ttl`) or be delivered out of order.  An
+//! `AeadSocket` instead encrypts every message on its own with ChaCha20-Poly1305:
+//!
+//! * the keys are agreed with an ephemeral X25519 exchange right after the connection is set
+//!   up, optionally mixed with a pre-shared key so that both sides are authenticated;
+//! * each direction uses its own key, and every message carries a 64-bit counter that is used
+//!   as the nonce, so a nonce is never reused;
+//! * the receiver keeps a sliding window of recently seen counters and rejects duplicates and
+//!   messages that are too old (replays);
+//! * gaps in the counter sequence are reported to the application, since with a TTL they
+//!   usually mean that UDT dropped the messages in between.
+//!
+//! Without a pre-shared key the exchange is anonymous: it protects against eavesdroppers, but
+//! not against an active man in the middle.
+//!
+//! This module requires the `aead` feature.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::aead::AeadConfig;
+//!
+//! init();
+//! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+//! sock.connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//!
+//! let secure = AeadConfig::new().psk(b"shared secret").connect(sock).unwrap();
+//! secure.send_ttl(b"quote 101.5", Some(Duration::from_millis(200)), false).unwrap();
+//!
+//! let mut buf = [0u8; 1500];
+//! let msg = secure.recv(&mut buf).unwrap();
+//! if msg.skipped > 0 {
+//!     println!("{} messages were lost before #{}", msg.skipped, msg.seq);
+//! }
+//! ```
+//!
+//! # Wire format
+//!
+//! Key exchange: each side sends `"UDTAEAD1"` followed by its 32 byte X25519 public key (the
+//! connecting side first).  The two directional keys are derived with HKDF-SHA256 from the
+//! shared secret, using the pre-shared key (if any) as salt and both public keys as info.  Each
+//! side then sends a confirmation message with counter 0, so a key mismatch is detected during
+//! setup rather than on the first data message.
+//!
+//! Messages: an 8 byte big-endian counter, followed by the ciphertext and the 16 byte
+//! Poly1305 tag.  The counter is authenticated as associated data.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::stream::OwnedSocket;
+use crate::{UdtError, UdtOpts, UdtSocket};
+
+const MAGIC: &[u8; 8] = b"UDTAEAD1";
+const KEY_INFO: &[u8] = b"udt aead v1";
+const CONFIRM: &[u8] = b"key confirmation";
+const HEADER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Bytes added to every message by the encryption
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+/// Errors from an `AeadSocket`
+///
+/// `BadMessage`, `Replayed` and `Truncated` only concern a single message, which has been
+/// discarded; the socket can still be used afterwards.
+#[derive(Debug)]
+pub enum AeadError {
+    /// The underlying socket failed
+    Udt(UdtError),
+    /// The key exchange failed, or the two sides derived different keys (e.g. because their
+    /// pre-shared keys differ)
+    Handshake(&'static str),
+    /// A message failed authentication, because it was modified or not sent by the peer
+    BadMessage,
+    /// A message was a duplicate, or too old for the replay window
+    Replayed(u64),
+    /// A message didn't fit into the receive buffer
+    Truncated,
+    /// All 2^64 message counters have been used; a new connection is required
+    CounterExhausted,
+}
+
+impl fmt::Display for AeadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AeadError::Udt(ref e) => write!(f, "{}", e),
+            AeadError::Handshake(msg) => write!(f, "key exchange failed: {}", msg),
+            AeadError::BadMessage => write!(f, "message failed authentication"),
+            AeadError::Replayed(seq) => write!(f, "message {} was replayed or is too old", seq),
+            AeadError::Truncated => write!(f, "message larger than the receive buffer"),
+            AeadError::CounterExhausted => write!(f, "message counter exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for AeadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            AeadError::Udt(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UdtError> for AeadError {
+    fn from(e: UdtError) -> AeadError {
+        AeadError::Udt(e)
+    }
+}
+
+/// Settings for setting up an `AeadSocket`
+#[derive(Clone)]
+pub struct AeadConfig {
+    psk: Option<Vec<u8>>,
+    replay_window: u64,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for AeadConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AeadConfig")
+            .field("psk", &self.psk.as_ref().map(|_| "<redacted>"))
+            .field("replay_window", &self.replay_window)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Default for AeadConfig {
+    fn default() -> AeadConfig {
+        AeadConfig::new()
+    }
+}
+
+impl AeadConfig {
+    /// Creates the default configuration: no pre-shared key, a replay window of 1024 messages
+    /// and a 10 second key exchange timeout.
+    pub fn new() -> AeadConfig {
+        AeadConfig {
+            psk: None,
+            replay_window: 1024,
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Mixes a pre-shared key into the key derivation.
+    ///
+    /// Both sides must use the same key, otherwise the setup fails with
+    /// `AeadError::Handshake`.
+    pub fn psk(mut self, key: &[u8]) -> AeadConfig {
+        self.psk = Some(key.to_vec());
+        self
+    }
+
+    /// Sets how far behind the newest message an older one may still be accepted.
+    ///
+    /// Messages sent without in-order delivery can overtake each other; a late message is only
+    /// accepted if fewer than `size` newer messages have been received since.  The size is
+    /// rounded up to a multiple of 64.
+    pub fn replay_window(mut self, size: u64) -> AeadConfig {
+        assert!(size > 0, "replay window must not be empty");
+        self.replay_window = size.div_ceil(64) * 64;
+        self
+    }
+
+    /// Sets how long to wait for the peer during key exchange.  `None` waits forever.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> AeadConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the key exchange as the connecting side, on a connected `Datagram` socket.
+    ///
+    /// On failure the socket is closed.
+    pub fn connect(&self, sock: UdtSocket) -> Result<AeadSocket, AeadError> {
+        self.setup(sock, true)
+    }
+
+    /// Runs the key exchange as the accepting side, on a socket returned by `accept`.
+    ///
+    /// On failure the socket is closed.
+    pub fn accept(&self, sock: UdtSocket) -> Result<AeadSocket, AeadError> {
+        self.setup(sock, false)
+    }
+
+    fn setup(&self, sock: UdtSocket, initiator: bool) -> Result<AeadSocket, AeadError> {
+        // dropped, and so closed, if anything below fails
+        let sock = OwnedSocket::new(sock);
+        let old = sock.getsockopt(UdtOpts::UDT_RCVTIMEO)?;
+        if let Some(t) = self.timeout {
+            sock.setsockopt(UdtOpts::UDT_RCVTIMEO, Some(t))?;
+        }
+        let secure = self.exchange(sock, initiator)?;
+        secure.sock.setsockopt(UdtOpts::UDT_RCVTIMEO, old)?;
+        Ok(secure)
+    }
+
+    fn exchange(&self, sock: OwnedSocket, initiator: bool) -> Result<AeadSocket, AeadError> {
+        let secret = EphemeralSecret::random();
+        let ours = PublicKey::from(&secret);
+
+        let mut hello = [0u8; 40];
+        hello[..8].copy_from_slice(MAGIC);
+        hello[8..].copy_from_slice(ours.as_bytes());
+        let mut buf = [0u8; 64];
+        let theirs = if initiator {
+            sock.sendmsg(&hello)?;
+            read_hello(*sock, &mut buf)?
+        } else {
+            let theirs = read_hello(*sock, &mut buf)?;
+            sock.sendmsg(&hello)?;
+            theirs
+        };
+
+        let shared = secret.diffie_hellman(&theirs);
+        if !shared.was_contributory() {
+            return Err(AeadError::Handshake("peer sent a low order public key"));
+        }
+
+        let (client_pk, server_pk) = if initiator {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        let mut info = Vec::with_capacity(KEY_INFO.len() + 64);
+        info.extend_from_slice(KEY_INFO);
+        info.extend_from_slice(client_pk.as_bytes());
+        info.extend_from_slice(server_pk.as_bytes());
+        let hk = Hkdf::<Sha256>::new(self.psk.as_deref(), shared.as_bytes());
+        let mut okm = [0u8; 64];
+        hk.expand(&info, &mut okm)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        let (c2s, s2c) = okm.split_at(32);
+        let (send_key, recv_key) = if initiator { (c2s, s2c) } else { (s2c, c2s) };
+
+        let secure = AeadSocket {
+            sock,
+            send: ChaCha20Poly1305::new(Key::from_slice(send_key)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(recv_key)),
+            next_seq: AtomicU64::new(1),
+            window: Mutex::new(ReplayWindow::new(self.replay_window)),
+            stats: Mutex::new(AeadStats::default()),
+        };
+
+        // counter 0 is reserved for the key confirmation, so it can never collide with data
+        let confirm = secure.seal(0, CONFIRM);
+        let check = |secure: &AeadSocket| -> Result<(), AeadError> {
+            let mut buf = [0u8; 64];
+            let len = secure.sock.recvmsg(&mut buf)?;
+            match secure.open(&buf[..len]) {
+                Ok((0, ref plain)) if plain == CONFIRM => Ok(()),
+                _ => Err(AeadError::Handshake("key confirmation failed")),
+            }
+        };
+        if initiator {
+            secure.sock.sendmsg(&confirm)?;
+            check(&secure)?;
+        } else {
+            check(&secure)?;
+            secure.sock.sendmsg(&confirm)?;
+        }
+        debug!("AEAD keys established on {:?}", *secure.sock);
+        Ok(secure)
+    }
+}
+
+fn read_hello(sock: UdtSocket, buf: &mut [u8]) -> Result<PublicKey, AeadError> {
+    let len = sock.recvmsg(buf)?;
+    if len != 40 || &buf[..8] != MAGIC {
+        return Err(AeadError::Handshake("unexpected key exchange message"));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&buf[8..40]);
+    Ok(PublicKey::from(key))
+}
+
+/// Counters kept by an `AeadSocket`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AeadStats {
+    /// Messages sent
+    pub sent: u64,
+    /// Messages received and accepted
+    pub received: u64,
+    /// Accepted messages that arrived after a newer one
+    pub late: u64,
+    /// Sequence numbers that were skipped when a newer message arrived.  With a TTL these were
+    /// usually dropped by UDT; the ones that arrive later anyway are also counted in `late`.
+    pub skipped: u64,
+    /// Messages rejected as duplicates or as too old
+    pub replayed: u64,
+    /// Messages that failed authentication
+    pub bad: u64,
+}
+
+/// A message received by `AeadSocket::recv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    /// Length of the decrypted message in the buffer
+    pub len: usize,
+    /// The sender's counter for this message, starting at 1
+    pub seq: u64,
+    /// How many sequence numbers were skipped between the newest message received before this
+    /// one and this message.  Always 0 for late messages.
+    pub skipped: u64,
+    /// True if a newer message had already been received
+    pub late: bool,
+}
+
+/// Sliding window of recently seen message counters
+#[derive(Debug)]
+struct ReplayWindow {
+    highest: u64,
+    bits: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new(size: u64) -> ReplayWindow {
+        // the confirmation message used counter 0
+        let mut w = ReplayWindow {
+            highest: 0,
+            bits: vec![0; (size / 64) as usize],
+        };
+        w.set(0);
+        w
+    }
+
+    fn size(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn bit(&self, seq: u64) -> (usize, u64) {
+        let i = seq % self.size();
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    fn set(&mut self, seq: u64) {
+        let (word, mask) = self.bit(seq);
+        self.bits[word] |= mask;
+    }
+
+    fn check(&self, seq: u64) -> bool {
+        if seq > self.highest {
+            return true;
+        }
+        if self.highest - seq >= self.size() {
+            return false;
+        }
+        let (word, mask) = self.bit(seq);
+        self.bits[word] & mask == 0
+    }
+
+    // must only be called for messages that passed `check` and authentication
+    fn mark(&mut self, seq: u64) -> (u64, bool) {
+        if seq <= self.highest {
+            self.set(seq);
+            return (0, true);
+        }
+        let skipped = seq - self.highest - 1;
+        if skipped >= self.size() {
+            for w in self.bits.iter_mut() {
+                *w = 0;
+            }
+        } else {
+            for s in self.highest + 1..seq {
+                let (word, mask) = self.bit(s);
+                self.bits[word] &= !mask;
+            }
+        }
+        self.highest = seq;
+        self.set(seq);
+        (skipped, false)
+    }
+}
+
+/// A `Datagram` socket whose messages are encrypted and authenticated
+///
+/// Created by [`AeadConfig::connect`](struct.AeadConfig.html#method.connect) or
+/// [`AeadConfig::accept`](struct.AeadConfig.html#method.accept).  It can be shared between a
+/// sending and a receiving thread.  The socket is closed when the `AeadSocket` is dropped.
+pub struct AeadSocket {
+    sock: OwnedSocket,
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    next_seq: AtomicU64,
+    window: Mutex<ReplayWindow>,
+    stats: Mutex<AeadStats>,
+}
+
+impl fmt::Debug for AeadSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AeadSocket")
+            .field("sock", &self.sock)
+            .field("next_seq", &self.next_seq)
+            .finish()
+    }
+}
+
+fn nonce(seq: u64) -> [u8; 12] {
+    let mut n = [0u8; 12];
+    n[4..].copy_from_slice(&seq.to_be_bytes());
+    n
+}
+
+impl AeadSocket {
+    fn seal(&self, seq: u64, msg: &[u8]) -> Vec<u8> {
+        let header = seq.to_be_bytes();
+        let sealed = self
+            .send
+            .encrypt(
+                Nonce::from_slice(&nonce(seq)),
+                Payload { msg, aad: &header },
+            )
+            .expect("ChaCha20-Poly1305 encryption can't fail");
+        let mut out = Vec::with_capacity(HEADER_LEN + sealed.len());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&sealed);
+        out
+    }
+
+    fn open(&self, data: &[u8]) -> Result<(u64, Vec<u8>), AeadError> {
+        if data.len() < OVERHEAD {
+            return Err(AeadError::BadMessage);
+        }
+        let (header, sealed) = data.split_at(HEADER_LEN);
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(header);
+        let seq = u64::from_be_bytes(seq);
+        let plain = self
+            .recv
+            .decrypt(
+                Nonce::from_slice(&nonce(seq)),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .map_err(|_| AeadError::BadMessage)?;
+        Ok((seq, plain))
+    }
+
+    /// Returns the underlying socket.
+    ///
+    /// Sending or receiving on it directly will confuse the peer.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Encrypts and sends a message, with no TTL and in-order delivery.
+    ///
+    /// Returns the message's sequence number.
+    pub fn send(&self, msg: &[u8]) -> Result<u64, AeadError> {
+        self.send_ttl(msg, None, true)
+    }
+
+    /// Encrypts and sends a message with the given TTL and ordering (see
+    /// `UdtSocket::sendmsg_ttl`).
+    ///
+    /// Returns the message's sequence number.  If the message expires before it is delivered,
+    /// the peer will see its sequence number as skipped.
+    pub fn send_ttl(
+        &self,
+        msg: &[u8],
+        ttl: Option<Duration>,
+        inorder: bool,
+    ) -> Result<u64, AeadError> {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        if seq == u64::MAX {
+            self.next_seq.store(u64::MAX, Ordering::SeqCst);
+            return Err(AeadError::CounterExhausted);
+        }
+        let sealed = self.seal(seq, msg);
+        self.sock.sendmsg_ttl(&sealed, ttl, inorder)?;
+        self.stats.lock().unwrap().sent += 1;
+        Ok(seq)
+    }
+
+    /// Receives and decrypts the next message into `buf`.
+    ///
+    /// Messages that fail authentication, are replayed or don't fit into `buf` are discarded
+    /// and reported as errors; call `recv` again to get the next message.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<Received, AeadError> {
+        // one spare byte tells us whether UDT had to truncate the message
+        let mut data = vec![0u8; buf.len() + OVERHEAD + 1];
+        let len = self.sock.recvmsg(&mut data)?;
+        if len == data.len() {
+            return Err(AeadError::Truncated);
+        }
+        let data = &data[..len];
+        if data.len() >= HEADER_LEN {
+            let mut seq = [0u8; 8];
+            seq.copy_from_slice(&data[..HEADER_LEN]);
+            let seq = u64::from_be_bytes(seq);
+            if !self.window.lock().unwrap().check(seq) {
+                self.stats.lock().unwrap().replayed += 1;
+                return Err(AeadError::Replayed(seq));
+            }
+        }
+        let (seq, plain) = match self.open(data) {
+            Ok(r) => r,
+            Err(e) => {
+                self.stats.lock().unwrap().bad += 1;
+                return Err(e);
+            }
+        };
+
+        // check again: another thread may have received a copy in the meantime
+        let mut window = self.window.lock().unwrap();
+        if !window.check(seq) {
+            self.stats.lock().unwrap().replayed += 1;
+            return Err(AeadError::Replayed(seq));
+        }
+        let (skipped, late) = window.mark(seq);
+        drop(window);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.received += 1;
+        stats.skipped += skipped;
+        if late {
+            stats.late += 1;
+        }
+        if skipped > 0 {
+            debug!("{} messages skipped before #{}", skipped, seq);
+        }
+
+        buf[..plain.len()].copy_from_slice(&plain);
+        Ok(Received {
+            len: plain.len(),
+            seq,
+            skipped,
+            late,
+        })
+    }
+
+    /// Returns a snapshot of the message counters.
+    pub fn stats(&self) -> AeadStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Closes the socket, reporting any error from UDT.
+    pub fn close(self) -> Result<(), UdtError> {
+        self.sock.close()
+    }
+}
+
+#[test]
+fn test_replay_window() {
+    let mut w = ReplayWindow::new(128);
+    assert!(!w.check(0));
+
+    assert!(w.check(1));
+    assert_eq!(w.mark(1), (0, false));
+    assert!(!w.check(1));
+
+    // 2..=4 are skipped, e.g. because their TTL expired
+    assert!(w.check(5));
+    assert_eq!(w.mark(5), (3, false));
+
+    // a late message inside the window is accepted once
+    assert!(w.check(3));
+    assert_eq!(w.mark(3), (0, true));
+    assert!(!w.check(3));
+    assert!(w.check(4));
+
+    // jumping far ahead forgets everything older than the window
+    assert_eq!(w.mark(1000), (994, false));
+    assert!(!w.check(1000 - 128));
+    assert!(w.check(1000 - 127));
+    assert!(!w.check(5));
+}
@@ -0,0 +1,432 @@
+//! Mutual pre-shared key authentication for `UdtStream`s
+//!
+//! UDT does no authentication of its own: `accept` hands out a connected socket to anyone who
+//! completes the UDT handshake.  The `Handshake` in this module runs a challenge-response
+//! exchange on top of a freshly connected stream, in which both sides prove that they know the
+//! same pre-shared key (using HMAC-SHA256 over fresh random nonces), and agree on an
+//! application protocol version.  The stream is only returned once both sides have been
+//! verified; otherwise it is closed and `HandshakeError::AuthFailed` is returned.
+//!
+//! The key itself never crosses the wire, but the handshake does not encrypt the data that
+//! follows it.
+//!
+//! This module requires the `auth` feature.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use udt::*;
+//! use udt::auth::Handshake;
+//!
+//! init();
+//! let handshake = Handshake::new(b"correct horse battery staple").version("replication/2");
+//!
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let (stream, version) = handshake.connect(stream).unwrap();
+//! assert_eq!(version, "replication/2");
+//! ```
+//!
+//! # Wire format
+//!
+//! 1. client: `"UDTAUTH1"`, 32 byte nonce, then the list of supported versions (a count byte
+//!    followed by length-prefixed strings, most preferred first)
+//! 2. server: a status byte; if it is `OK`, a 32 byte nonce, the chosen version (length-prefixed)
+//!    and `HMAC(key, "server" | client nonce | server nonce | offered versions | version)`
+//! 3. client: a status byte; if it is `OK` (the server's proof was valid), followed by
+//!    `HMAC(key, "client" | server nonce | client nonce | offered versions | version)`
+//! 4. server: a status byte, `OK` if the client's proof was valid
+//!
+//! The offered versions are covered as sent in step 1, so stripping versions from the list to
+//! force a downgrade makes both proofs fail.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{UdtError, UdtOpts, UdtStream};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: &[u8; 8] = b"UDTAUTH1";
+const NONCE_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+
+const STATUS_OK: u8 = 0;
+const STATUS_NO_VERSION: u8 = 1;
+const STATUS_AUTH_FAILED: u8 = 2;
+
+/// Errors from an authentication handshake
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The peer could not prove that it knows the pre-shared key, or rejected our proof
+    AuthFailed,
+    /// The two sides have no protocol version in common
+    NoCommonVersion,
+    /// The peer sent something that isn't a valid handshake message
+    Protocol(&'static str),
+    /// The underlying stream failed, or the handshake timed out
+    Io(io::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::AuthFailed => write!(f, "authentication failed"),
+            HandshakeError::NoCommonVersion => write!(f, "no common protocol version"),
+            HandshakeError::Protocol(msg) => write!(f, "handshake protocol error: {}", msg),
+            HandshakeError::Io(ref e) => write!(f, "handshake I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            HandshakeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> HandshakeError {
+        HandshakeError::Io(e)
+    }
+}
+
+impl From<UdtError> for HandshakeError {
+    fn from(e: UdtError) -> HandshakeError {
+        HandshakeError::Io(e.into())
+    }
+}
+
+/// Configuration for a pre-shared key handshake
+///
+/// The same `Handshake` can be used for any number of connections, on either side.
+#[derive(Clone)]
+pub struct Handshake {
+    key: Vec<u8>,
+    versions: Vec<String>,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the key
+        f.debug_struct("Handshake")
+            .field("versions", &self.versions)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Handshake {
+    /// Creates a handshake using the given pre-shared key.
+    ///
+    /// At least one protocol version must be added with [`version`](#method.version) before
+    /// the handshake can be used.
+    pub fn new(key: &[u8]) -> Handshake {
+        Handshake {
+            key: key.to_vec(),
+            versions: Vec::new(),
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Adds a supported protocol version.
+    ///
+    /// Versions are listed in order of preference.  The server picks the first of the client's
+    /// versions that it also supports.  Versions can be at most 255 bytes long, and at most 255
+    /// versions can be added.
+    pub fn version(mut self, version: &str) -> Handshake {
+        assert!(version.len() <= 255, "protocol version is too long");
+        assert!(self.versions.len() < 255, "too many protocol versions");
+        self.versions.push(version.to_owned());
+        self
+    }
+
+    /// Sets how long to wait for each handshake message from the peer.
+    ///
+    /// `None` waits forever.  Defaults to 10 seconds.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Handshake {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs the client side of the handshake on a newly connected stream.
+    ///
+    /// Returns the stream and the negotiated protocol version.  On failure the stream is
+    /// closed.
+    pub fn connect(&self, stream: UdtStream) -> Result<(UdtStream, String), HandshakeError> {
+        self.with_timeout(stream, |s| self.client(s))
+    }
+
+    /// Runs the server side of the handshake on a newly accepted stream.
+    ///
+    /// Returns the stream and the negotiated protocol version.  On failure the stream is
+    /// closed.
+    pub fn accept(&self, stream: UdtStream) -> Result<(UdtStream, String), HandshakeError> {
+        self.with_timeout(stream, |s| self.server(s))
+    }
+
+    fn with_timeout<F>(
+        &self,
+        mut stream: UdtStream,
+        f: F,
+    ) -> Result<(UdtStream, String), HandshakeError>
+    where
+        F: FnOnce(&mut UdtStream) -> Result<String, HandshakeError>,
+    {
+        let sock = *stream.socket();
+        let old = sock.getsockopt(UdtOpts::UDT_RCVTIMEO)?;
+        if let Some(t) = self.timeout {
+            sock.setsockopt(UdtOpts::UDT_RCVTIMEO, Some(t))?;
+        }
+        let version = f(&mut stream)?;
+        sock.setsockopt(UdtOpts::UDT_RCVTIMEO, old)?;
+        Ok((stream, version))
+    }
+
+    fn mac(
+        &self,
+        label: &[u8],
+        first: &[u8],
+        second: &[u8],
+        offered: &[u8],
+        version: &str,
+    ) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(label);
+        mac.update(first);
+        mac.update(second);
+        mac.update(offered);
+        mac.update(version.as_bytes());
+        mac
+    }
+
+    pub(crate) fn client<S: Read + Write>(&self, s: &mut S) -> Result<String, HandshakeError> {
+        assert!(!self.versions.is_empty(), "no protocol version configured");
+        let client_nonce = nonce()?;
+
+        let offered = encode_versions(&self.versions);
+        let mut hello = Vec::with_capacity(MAGIC.len() + NONCE_LEN + offered.len());
+        hello.extend_from_slice(MAGIC);
+        hello.extend_from_slice(&client_nonce);
+        hello.extend_from_slice(&offered);
+        s.write_all(&hello)?;
+
+        match read_u8(s)? {
+            STATUS_OK => {}
+            STATUS_NO_VERSION => return Err(HandshakeError::NoCommonVersion),
+            _ => return Err(HandshakeError::Protocol("unexpected server status")),
+        }
+        let mut server_nonce = [0u8; NONCE_LEN];
+        s.read_exact(&mut server_nonce)?;
+        let version = read_string(s)?;
+        if !self.versions.contains(&version) {
+            return Err(HandshakeError::Protocol(
+                "server chose a version we did not offer",
+            ));
+        }
+        let mut server_proof = [0u8; MAC_LEN];
+        s.read_exact(&mut server_proof)?;
+        if self
+            .mac(b"server", &client_nonce, &server_nonce, &offered, &version)
+            .verify_slice(&server_proof)
+            .is_err()
+        {
+            debug!("server failed to prove knowledge of the pre-shared key");
+            s.write_all(&[STATUS_AUTH_FAILED])?;
+            return Err(HandshakeError::AuthFailed);
+        }
+
+        let proof = self.mac(b"client", &server_nonce, &client_nonce, &offered, &version);
+        let mut reply = Vec::with_capacity(1 + MAC_LEN);
+        reply.push(STATUS_OK);
+        reply.extend_from_slice(&proof.finalize().into_bytes());
+        s.write_all(&reply)?;
+
+        match read_u8(s)? {
+            STATUS_OK => Ok(version),
+            STATUS_AUTH_FAILED => Err(HandshakeError::AuthFailed),
+            _ => Err(HandshakeError::Protocol("unexpected server status")),
+        }
+    }
+
+    pub(crate) fn server<S: Read + Write>(&self, s: &mut S) -> Result<String, HandshakeError> {
+        assert!(!self.versions.is_empty(), "no protocol version configured");
+        let mut magic = [0u8; 8];
+        s.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(HandshakeError::Protocol("bad magic"));
+        }
+        let mut client_nonce = [0u8; NONCE_LEN];
+        s.read_exact(&mut client_nonce)?;
+        let count = read_u8(s)?;
+        let mut versions = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            versions.push(read_string(s)?);
+        }
+        let offered = encode_versions(&versions);
+
+        let version = match versions.into_iter().find(|v| self.versions.contains(v)) {
+            Some(v) => v,
+            None => {
+                s.write_all(&[STATUS_NO_VERSION])?;
+                return Err(HandshakeError::NoCommonVersion);
+            }
+        };
+
+        let server_nonce = nonce()?;
+        let proof = self.mac(b"server", &client_nonce, &server_nonce, &offered, &version);
+        let mut reply = Vec::with_capacity(1 + NONCE_LEN + 1 + version.len() + MAC_LEN);
+        reply.push(STATUS_OK);
+        reply.extend_from_slice(&server_nonce);
+        reply.push(version.len() as u8);
+        reply.extend_from_slice(version.as_bytes());
+        reply.extend_from_slice(&proof.finalize().into_bytes());
+        s.write_all(&reply)?;
+
+        match read_u8(s)? {
+            STATUS_OK => {}
+            STATUS_AUTH_FAILED => return Err(HandshakeError::AuthFailed),
+            _ => return Err(HandshakeError::Protocol("unexpected client status")),
+        }
+        let mut client_proof = [0u8; MAC_LEN];
+        s.read_exact(&mut client_proof)?;
+        if self
+            .mac(b"client", &server_nonce, &client_nonce, &offered, &version)
+            .verify_slice(&client_proof)
+            .is_err()
+        {
+            debug!("client failed to prove knowledge of the pre-shared key");
+            s.write_all(&[STATUS_AUTH_FAILED])?;
+            return Err(HandshakeError::AuthFailed);
+        }
+        s.write_all(&[STATUS_OK])?;
+        Ok(version)
+    }
+}
+
+fn nonce() -> Result<[u8; NONCE_LEN], HandshakeError> {
+    let mut n = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut n).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(n)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn encode_versions(versions: &[String]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    buf.push(versions.len() as u8);
+    for v in versions {
+        buf.push(v.len() as u8);
+        buf.extend_from_slice(v.as_bytes());
+    }
+    buf
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String, HandshakeError> {
+    let len = read_u8(r)?;
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| HandshakeError::Protocol("version is not UTF-8"))
+}
+
+#[cfg(all(test, unix))]
+fn run_pair(
+    client: Handshake,
+    server: Handshake,
+) -> (
+    Result<String, HandshakeError>,
+    Result<String, HandshakeError>,
+) {
+    use std::os::unix::net::UnixStream;
+
+    let (mut a, mut b) = UnixStream::pair().unwrap();
+    let t = std::thread::spawn(move || server.server(&mut b));
+    let c = client.client(&mut a);
+    drop(a);
+    (c, t.join().unwrap())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_handshake_negotiates_version() {
+    let client = Handshake::new(b"secret")
+        .version("proto/3")
+        .version("proto/2");
+    let server = Handshake::new(b"secret")
+        .version("proto/2")
+        .version("proto/1");
+    let (c, s) = run_pair(client, server);
+    assert_eq!(c.unwrap(), "proto/2");
+    assert_eq!(s.unwrap(), "proto/2");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_handshake_wrong_key() {
+    let client = Handshake::new(b"secret").version("proto/1");
+    let server = Handshake::new(b"guess").version("proto/1");
+    match run_pair(client, server) {
+        (Err(HandshakeError::AuthFailed), Err(HandshakeError::AuthFailed)) => {}
+        (c, s) => panic!("unexpected result {:?} {:?}", c, s),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_handshake_no_common_version() {
+    let client = Handshake::new(b"secret").version("proto/2");
+    let server = Handshake::new(b"secret").version("proto/1");
+    match run_pair(client, server) {
+        (Err(HandshakeError::NoCommonVersion), Err(HandshakeError::NoCommonVersion)) => {}
+        (c, s) => panic!("unexpected result {:?} {:?}", c, s),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_handshake_detects_downgrade() {
+    use std::os::unix::net::UnixStream;
+
+    let client = Handshake::new(b"secret")
+        .version("proto/3")
+        .version("proto/2");
+    let server = Handshake::new(b"secret")
+        .version("proto/3")
+        .version("proto/2");
+
+    // a relay that drops "proto/3" from the client's hello
+    let (mut a, mut client_side) = UnixStream::pair().unwrap();
+    let (mut server_side, mut b) = UnixStream::pair().unwrap();
+    let relay = std::thread::spawn(move || {
+        let mut hello = [0u8; 8 + NONCE_LEN + 1 + 2 * 8];
+        client_side.read_exact(&mut hello).unwrap();
+        let mut forged = hello[..8 + NONCE_LEN].to_vec();
+        forged.push(1);
+        forged.extend_from_slice(&hello[8 + NONCE_LEN + 1 + 8..]);
+        server_side.write_all(&forged).unwrap();
+
+        let mut up = client_side.try_clone().unwrap();
+        let mut down = server_side.try_clone().unwrap();
+        std::thread::spawn(move || io::copy(&mut up, &mut server_side));
+        let _ = io::copy(&mut down, &mut client_side);
+    });
+    let t = std::thread::spawn(move || server.server(&mut b));
+    let c = client.client(&mut a);
+    drop(a);
+    match (c, t.join().unwrap()) {
+        (Err(HandshakeError::AuthFailed), Err(HandshakeError::AuthFailed)) => {}
+        (c, s) => panic!("unexpected result {:?} {:?}", c, s),
+    }
+    relay.join().unwrap();
+}
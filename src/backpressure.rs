@@ -0,0 +1,190 @@
+//! Backpressure for bulk producers
+//!
+//! `send` on a UDT socket only blocks once the sending buffer (`UDT_SNDBUF`, 10MB by default)
+//! is full, so a producer writing faster than the link can carry piles up megabytes per
+//! connection before it notices.  A `BoundedSender` checks how much is queued before each
+//! write: once it reaches the high-water mark, producers are paused until UDT has sent enough
+//! for the queue to fall below the low-water mark.
+//!
+//! Occupancy comes from `UDT_SNDDATA` (see
+//! [`UdtSocket::pending_send_bytes`](../struct.UdtSocket.html#method.pending_send_bytes)), so
+//! it includes data that was sent but not acknowledged yet.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use udt::*;
+//! use udt::backpressure::BackpressureConfig;
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let sender = BackpressureConfig::new()
+//!     .high_water(4 * 1024 * 1024)
+//!     .wrap(stream);
+//! for _ in 0..1000 {
+//!     sender.send(&[0u8; 65536]).unwrap();
+//! }
+//! println!("paused {} times", sender.pauses());
+//! ```
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{UdtSocket, UdtStream};
+
+// the smallest amount written between two occupancy checks
+const MIN_CHUNK: usize = 16 * 1024;
+
+/// Settings for a `BoundedSender`
+#[derive(Debug, Clone)]
+pub struct BackpressureConfig {
+    high_water: usize,
+    low_water: Option<usize>,
+    max_wait: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> BackpressureConfig {
+        BackpressureConfig::new()
+    }
+}
+
+impl BackpressureConfig {
+    /// Creates the default configuration: producers pause at 1 MiB queued, resume below half
+    /// of that, and give up after waiting 60 seconds.
+    pub fn new() -> BackpressureConfig {
+        BackpressureConfig {
+            high_water: 1024 * 1024,
+            low_water: None,
+            max_wait: Duration::from_secs(60),
+        }
+    }
+
+    /// Sets how many queued bytes pause producers.
+    pub fn high_water(mut self, bytes: usize) -> BackpressureConfig {
+        self.high_water = std::cmp::max(bytes, 1);
+        self
+    }
+
+    /// Sets how far the queue must drain before paused producers resume.
+    ///
+    /// Defaults to half the high-water mark, and is capped at the high-water mark.
+    pub fn low_water(mut self, bytes: usize) -> BackpressureConfig {
+        self.low_water = Some(bytes);
+        self
+    }
+
+    /// Sets how long a producer may be paused before `send` fails with `TimedOut`.
+    pub fn max_wait(mut self, timeout: Duration) -> BackpressureConfig {
+        self.max_wait = timeout;
+        self
+    }
+
+    /// Wraps a `UdtStream` or `WriteHalf`.
+    pub fn wrap<W: Write + AsRef<UdtSocket>>(self, writer: W) -> BoundedSender<W> {
+        let low_water = self
+            .low_water
+            .unwrap_or(self.high_water / 2)
+            .clamp(1, self.high_water);
+        BoundedSender {
+            sock: *writer.as_ref(),
+            writer: Mutex::new(writer),
+            high_water: self.high_water,
+            low_water,
+            chunk: std::cmp::max(self.high_water - low_water, MIN_CHUNK),
+            max_wait: self.max_wait,
+            pauses: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A writer that pauses producers while too much data is queued in UDT
+///
+/// `send` takes `&self`, so one `BoundedSender` can be shared by several producer threads.
+/// Large writes are split into chunks of `high_water - low_water` bytes (at least 16 KiB) and
+/// occupancy is checked before each one, so the queue exceeds the high-water mark by at most
+/// one chunk per producer.
+#[derive(Debug)]
+pub struct BoundedSender<W = UdtStream> {
+    sock: UdtSocket,
+    writer: Mutex<W>,
+    high_water: usize,
+    low_water: usize,
+    chunk: usize,
+    max_wait: Duration,
+    pauses: AtomicU64,
+}
+
+impl<W: Write + AsRef<UdtSocket>> BoundedSender<W> {
+    /// Wraps `writer` with the default settings.
+    pub fn new(writer: W) -> BoundedSender<W> {
+        BackpressureConfig::new().wrap(writer)
+    }
+
+    /// Writes all of `data`, first waiting for the queue to drain if it is above the high-water
+    /// mark.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(self.chunk) {
+            self.wait_for_room()?;
+            self.writer.lock().unwrap().write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until the queue is below the high-water mark, as `send` does.
+    pub fn wait_for_room(&self) -> io::Result<()> {
+        if self.sock.pending_send_bytes()? < self.high_water {
+            return Ok(());
+        }
+        self.pauses.fetch_add(1, Ordering::Relaxed);
+        trace!("pausing producer on {:?}", self.sock);
+        Ok(self
+            .sock
+            .wait_send_buffer_below(self.low_water, self.max_wait)?)
+    }
+
+    /// Returns the number of bytes currently queued.
+    pub fn pending_send_bytes(&self) -> io::Result<usize> {
+        Ok(self.sock.pending_send_bytes()?)
+    }
+
+    /// Returns how many times a producer had to wait for the queue to drain.
+    pub fn pauses(&self) -> u64 {
+        self.pauses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the underlying socket.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + AsRef<UdtSocket>> Write for &BoundedSender<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len(), self.chunk);
+        self.send(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl<W: Write + AsRef<UdtSocket>> Write for BoundedSender<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
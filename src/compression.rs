@@ -0,0 +1,638 @@
+//! Transparent compression for `UdtStream`s
+//!
+//! A `CompressedStream` splits the data written to it into frames and compresses each frame with
+//! zstd or lz4.  The algorithm is negotiated with the peer in a short preamble right after the
+//! connection is set up, so both sides only need to list what they support.
+//!
+//! Data is buffered until a frame is full or `flush` is called, which sends the pending data as a
+//! (possibly short) frame right away.  Interactive protocols should flush after each request;
+//! bulk transfers can just write and let the frames fill up.  Frames that don't compress are sent
+//! as they are, so incompressible data costs only a few header bytes per frame.
+//!
+//! `CompressedStream` works over any `Read + Write` stream, so it can also be layered on top of a
+//! `TlsStream` (compress first, then encrypt).
+//!
+//! This module requires the `compression` feature.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use udt::*;
+//! use udt::compression::{Algorithm, CompressionConfig};
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let config = CompressionConfig::new().zstd_level(6);
+//! let mut stream = config.connect(stream).unwrap();
+//! assert_eq!(stream.algorithm(), Algorithm::Zstd);
+//!
+//! stream.write_all(b"id,name,value\n").unwrap();
+//! stream.flush().unwrap();
+//! let (perf, stats) = stream.perfmon().unwrap();
+//! println!("{} Mbps on the wire, compressed {:.1}x", perf.mbps_send_rate, stats.send_ratio());
+//! ```
+//!
+//! # Wire format
+//!
+//! 1. client: `"UDTCOMP1"`, a count byte and the supported algorithm ids, most preferred first
+//! 2. server: the id of the first algorithm in the client's list that it supports, or `0xff`
+//!
+//! Each frame is a flags byte (1 if the payload is compressed, 0 if it is stored as is), the
+//! uncompressed length and the payload length as 32-bit big-endian integers, and the payload.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::raw::PerfMon;
+use crate::{UdtError, UdtStream};
+
+const MAGIC: &[u8; 8] = b"UDTCOMP1";
+const NO_ALGORITHM: u8 = 0xff;
+
+const FLAG_COMPRESSED: u8 = 1;
+const HEADER_LEN: usize = 9;
+
+/// The largest frame a `CompressedStream` will send or accept
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// A compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// No compression; frames are sent as they are
+    None,
+    /// lz4: fast, with a moderate ratio
+    Lz4,
+    /// zstd: slower, with a better ratio
+    Zstd,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::None => 0,
+            Algorithm::Lz4 => 1,
+            Algorithm::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Algorithm> {
+        match id {
+            0 => Some(Algorithm::None),
+            1 => Some(Algorithm::Lz4),
+            2 => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Algorithm::None => write!(f, "none"),
+            Algorithm::Lz4 => write!(f, "lz4"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Settings for setting up a `CompressedStream`
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    algorithms: Vec<Algorithm>,
+    zstd_level: i32,
+    frame_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig::new()
+    }
+}
+
+impl CompressionConfig {
+    /// Creates the default configuration: zstd, lz4 and no compression are supported (in that
+    /// order of preference), with zstd level 3 and 64 KiB frames.
+    pub fn new() -> CompressionConfig {
+        CompressionConfig {
+            algorithms: vec![Algorithm::Zstd, Algorithm::Lz4, Algorithm::None],
+            zstd_level: 3,
+            frame_size: 64 * 1024,
+        }
+    }
+
+    /// Replaces the supported algorithms, most preferred first.
+    ///
+    /// The connecting side's preference wins.  Leave out `Algorithm::None` to fail the setup
+    /// rather than fall back to sending uncompressed data.
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> CompressionConfig {
+        assert!(
+            !algorithms.is_empty(),
+            "no compression algorithm configured"
+        );
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    /// Sets the zstd compression level (1-22, higher is smaller but slower).
+    pub fn zstd_level(mut self, level: i32) -> CompressionConfig {
+        self.zstd_level = level;
+        self
+    }
+
+    /// Sets how much data is collected into one frame before it is compressed and sent.
+    ///
+    /// Larger frames compress better but add latency unless the stream is flushed.
+    pub fn frame_size(mut self, size: usize) -> CompressionConfig {
+        assert!(
+            size > 0 && size <= MAX_FRAME_SIZE,
+            "frame size must be between 1 and MAX_FRAME_SIZE"
+        );
+        self.frame_size = size;
+        self
+    }
+
+    /// Negotiates an algorithm as the connecting side and wraps the stream.
+    pub fn connect<S: Read + Write>(&self, mut stream: S) -> io::Result<CompressedStream<S>> {
+        let mut hello = Vec::with_capacity(MAGIC.len() + 1 + self.algorithms.len());
+        hello.extend_from_slice(MAGIC);
+        hello.push(self.algorithms.len() as u8);
+        hello.extend(self.algorithms.iter().map(|a| a.id()));
+        stream.write_all(&hello)?;
+        stream.flush()?;
+
+        let id = read_u8(&mut stream)?;
+        if id == NO_ALGORITHM {
+            return Err(invalid("no common compression algorithm"));
+        }
+        match Algorithm::from_id(id) {
+            Some(a) if self.algorithms.contains(&a) => Ok(self.wrap(stream, a)),
+            _ => Err(invalid(
+                "peer chose a compression algorithm we did not offer",
+            )),
+        }
+    }
+
+    /// Negotiates an algorithm as the accepting side and wraps the stream.
+    pub fn accept<S: Read + Write>(&self, mut stream: S) -> io::Result<CompressedStream<S>> {
+        let mut magic = [0u8; 8];
+        stream.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("bad compression preamble"));
+        }
+        let count = read_u8(&mut stream)?;
+        let mut offered = vec![0u8; count as usize];
+        stream.read_exact(&mut offered)?;
+
+        let chosen = offered
+            .into_iter()
+            .filter_map(Algorithm::from_id)
+            .find(|a| self.algorithms.contains(a));
+        match chosen {
+            Some(a) => {
+                stream.write_all(&[a.id()])?;
+                stream.flush()?;
+                Ok(self.wrap(stream, a))
+            }
+            None => {
+                stream.write_all(&[NO_ALGORITHM])?;
+                stream.flush()?;
+                Err(invalid("no common compression algorithm"))
+            }
+        }
+    }
+
+    fn wrap<S: Read + Write>(&self, inner: S, algorithm: Algorithm) -> CompressedStream<S> {
+        debug!("using {} compression", algorithm);
+        CompressedStream {
+            inner,
+            algorithm,
+            zstd_level: self.zstd_level,
+            frame_size: self.frame_size,
+            pending: Vec::with_capacity(self.frame_size),
+            decoded: Vec::new(),
+            pos: 0,
+            stats: CompressionStats::default(),
+            broken: None,
+        }
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+/// Byte counts kept by a `CompressedStream`
+///
+/// The wire counts include the frame headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Uncompressed bytes written by the application and sent
+    pub raw_sent: u64,
+    /// Bytes sent on the underlying stream
+    pub wire_sent: u64,
+    /// Uncompressed bytes received and decoded
+    pub raw_received: u64,
+    /// Bytes received from the underlying stream
+    pub wire_received: u64,
+    /// Frames sent
+    pub frames_sent: u64,
+    /// Frames received
+    pub frames_received: u64,
+}
+
+fn ratio(raw: u64, wire: u64) -> f64 {
+    if wire == 0 {
+        1.0
+    } else {
+        raw as f64 / wire as f64
+    }
+}
+
+impl CompressionStats {
+    /// Returns how many times smaller the sent data was on the wire (e.g. 5.0 for 5x).
+    pub fn send_ratio(&self) -> f64 {
+        ratio(self.raw_sent, self.wire_sent)
+    }
+
+    /// Returns how many times smaller the received data was on the wire.
+    pub fn recv_ratio(&self) -> f64 {
+        ratio(self.raw_received, self.wire_received)
+    }
+}
+
+/// A stream that compresses everything written to it and decompresses everything read from it
+///
+/// Created by [`CompressionConfig::connect`](struct.CompressionConfig.html#method.connect) or
+/// [`CompressionConfig::accept`](struct.CompressionConfig.html#method.accept).  Pending data is
+/// flushed when the `CompressedStream` is dropped, but errors are ignored then; call `flush` to
+/// see them.
+///
+/// If a write fails after part of a frame went out, the peer can't make sense of anything sent
+/// after it, so all later writes and flushes fail as well.
+#[derive(Debug)]
+pub struct CompressedStream<S: Read + Write = UdtStream> {
+    inner: S,
+    algorithm: Algorithm,
+    zstd_level: i32,
+    frame_size: usize,
+    pending: Vec<u8>,
+    decoded: Vec<u8>,
+    pos: usize,
+    stats: CompressionStats,
+    // set when a frame was only partly written; the peer can't find the next frame after that
+    broken: Option<io::ErrorKind>,
+}
+
+impl<S: Read + Write> CompressedStream<S> {
+    /// Returns the negotiated algorithm.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Returns the underlying stream.
+    ///
+    /// Reading or writing it directly will corrupt the framing.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the byte counts so far.
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let out = match self.algorithm {
+            Algorithm::None => return Ok(None),
+            Algorithm::Lz4 => lz4_flex::block::compress(data),
+            Algorithm::Zstd => zstd::bulk::compress(data, self.zstd_level)?,
+        };
+        Ok(if out.len() < data.len() {
+            Some(out)
+        } else {
+            None
+        })
+    }
+
+    fn decompress(&self, payload: Vec<u8>, raw_len: usize) -> io::Result<Vec<u8>> {
+        let out = match self.algorithm {
+            Algorithm::None => return Err(invalid("compressed frame on an uncompressed stream")),
+            Algorithm::Lz4 => lz4_flex::block::decompress(&payload, raw_len)
+                .map_err(|_| invalid("corrupt lz4 frame"))?,
+            Algorithm::Zstd => zstd::bulk::decompress(&payload, raw_len)
+                .map_err(|_| invalid("corrupt zstd frame"))?,
+        };
+        if out.len() != raw_len {
+            return Err(invalid("frame length mismatch"));
+        }
+        Ok(out)
+    }
+}
+
+impl<S: Read + Write> CompressedStream<S> {
+    fn check_broken(&self) -> io::Result<()> {
+        match self.broken {
+            Some(kind) => Err(io::Error::new(
+                kind,
+                "an earlier write failed partway through a frame",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        self.check_broken()?;
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let compressed = self.compress(&self.pending)?;
+        let (flags, payload) = match compressed {
+            Some(ref c) => (FLAG_COMPRESSED, &c[..]),
+            None => (0, &self.pending[..]),
+        };
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(flags);
+        frame.extend_from_slice(&(self.pending.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        let mut sent = 0;
+        while sent < frame.len() {
+            let err = match self.inner.write(&frame[sent..]) {
+                Ok(0) => io::ErrorKind::WriteZero.into(),
+                Ok(n) => {
+                    sent += n;
+                    continue;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            if sent > 0 {
+                self.broken = Some(err.kind());
+            }
+            return Err(err);
+        }
+
+        self.stats.raw_sent += self.pending.len() as u64;
+        self.stats.wire_sent += frame.len() as u64;
+        self.stats.frames_sent += 1;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl CompressedStream<UdtStream> {
+    /// Returns the UDT performance data of the underlying socket (see `UdtSocket::perfmon`)
+    /// together with the compression byte counts.
+    pub fn perfmon(&self) -> Result<(PerfMon, CompressionStats), UdtError> {
+        Ok((self.inner.socket().perfmon()?, self.stats))
+    }
+}
+
+impl<S: Read + Write> CompressedStream<S> {
+    // returns false on a clean end of stream
+    fn read_frame(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; HEADER_LEN];
+        if self.inner.read(&mut header[..1])? == 0 {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut header[1..])?;
+        let flags = header[0];
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[1..5]);
+        let raw_len = u32::from_be_bytes(len) as usize;
+        len.copy_from_slice(&header[5..9]);
+        let wire_len = u32::from_be_bytes(len) as usize;
+        if raw_len > MAX_FRAME_SIZE || wire_len > raw_len {
+            return Err(invalid("frame too large"));
+        }
+
+        let mut payload = vec![0u8; wire_len];
+        self.inner.read_exact(&mut payload)?;
+        self.decoded = match flags {
+            FLAG_COMPRESSED => self.decompress(payload, raw_len)?,
+            0 if wire_len == raw_len => payload,
+            _ => return Err(invalid("bad frame header")),
+        };
+        self.pos = 0;
+
+        self.stats.raw_received += raw_len as u64;
+        self.stats.wire_received += (HEADER_LEN + wire_len) as u64;
+        self.stats.frames_received += 1;
+        Ok(true)
+    }
+}
+
+impl<S: Read + Write> Read for CompressedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // empty frames are never sent, but skip them anyway
+        while self.pos == self.decoded.len() {
+            if !self.read_frame()? {
+                return Ok(0);
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for CompressedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_broken()?;
+        let before = self.pending.len();
+        let n = std::cmp::min(buf.len(), self.frame_size - before);
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == self.frame_size {
+            if let Err(e) = self.write_frame() {
+                // either nothing was sent or the stream is now broken; a retry must not
+                // buffer `buf` twice
+                self.pending.truncate(before);
+                return Err(e);
+            }
+        }
+        Ok(n)
+    }
+
+    /// Sends any pending data as a frame immediately.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frame()?;
+        self.inner.flush()
+    }
+}
+
+impl<S: Read + Write> Drop for CompressedStream<S> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let _ = self.write_frame();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+fn roundtrip(
+    client: CompressionConfig,
+    server: CompressionConfig,
+    data: &[u8],
+) -> (Algorithm, f64) {
+    use std::os::unix::net::UnixStream;
+
+    let (a, b) = UnixStream::pair().unwrap();
+    let expected = data.to_vec();
+    let t = std::thread::spawn(move || {
+        let mut s = server.accept(b).unwrap();
+        let mut got = Vec::new();
+        s.read_to_end(&mut got).unwrap();
+        assert_eq!(got, expected);
+        s.stats().recv_ratio()
+    });
+    let mut s = client.connect(a).unwrap();
+    s.write_all(data).unwrap();
+    s.flush().unwrap();
+    let algorithm = s.algorithm();
+    drop(s);
+    (algorithm, t.join().unwrap())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_compression_roundtrip() {
+    let csv: Vec<u8> = (0..20_000)
+        .flat_map(|i| format!("{},sensor-{},{}\n", i, i % 7, i * 3).into_bytes())
+        .collect();
+
+    let (algorithm, ratio) = roundtrip(CompressionConfig::new(), CompressionConfig::new(), &csv);
+    assert_eq!(algorithm, Algorithm::Zstd);
+    assert!(ratio > 3.0, "ratio {}", ratio);
+
+    let lz4 = CompressionConfig::new().algorithms(&[Algorithm::Lz4]);
+    let (algorithm, ratio) = roundtrip(lz4, CompressionConfig::new().frame_size(1000), &csv);
+    assert_eq!(algorithm, Algorithm::Lz4);
+    assert!(ratio > 1.5, "ratio {}", ratio);
+
+    // incompressible data is stored, so it only costs the frame headers
+    let mut noise = vec![0u8; 100_000];
+    let mut x = 1u32;
+    for b in noise.iter_mut() {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *b = x as u8;
+    }
+    let (_, ratio) = roundtrip(CompressionConfig::new(), CompressionConfig::new(), &noise);
+    assert!(ratio > 0.99 && ratio <= 1.0, "ratio {}", ratio);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_compression_flush_and_negotiation() {
+    use std::os::unix::net::UnixStream;
+
+    // a flushed write is readable right away, without filling a frame
+    let (a, b) = UnixStream::pair().unwrap();
+    let t = std::thread::spawn(move || {
+        let mut s = CompressionConfig::new().accept(b).unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        s.write_all(b"pong").unwrap();
+        s.flush().unwrap();
+        s.stats()
+    });
+    let mut s = CompressionConfig::new().connect(a).unwrap();
+    s.write_all(b"ping").unwrap();
+    s.flush().unwrap();
+    let mut buf = [0u8; 4];
+    s.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+    let stats = t.join().unwrap();
+    assert_eq!((stats.frames_received, stats.frames_sent), (1, 1));
+
+    // no algorithm in common
+    let (a, b) = UnixStream::pair().unwrap();
+    let t = std::thread::spawn(move || {
+        let config = CompressionConfig::new().algorithms(&[Algorithm::Lz4]);
+        config.accept(b).unwrap_err()
+    });
+    let config = CompressionConfig::new().algorithms(&[Algorithm::Zstd]);
+    let err = config.connect(a).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(t.join().unwrap().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_compression_failed_write_keeps_nothing() {
+    // a stream that fails the first write, and then accepts `limit` bytes before failing for good
+    struct Flaky {
+        failed: bool,
+        limit: usize,
+        written: Vec<u8>,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let n = std::cmp::min(buf.len(), self.limit - self.written.len());
+            if n == 0 {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let flaky = Flaky {
+        failed: false,
+        limit: 2 * (HEADER_LEN + 4) + 2,
+        written: Vec::new(),
+    };
+    let mut s = CompressionConfig::new()
+        .frame_size(4)
+        .wrap(flaky, Algorithm::None);
+    // nothing was sent, so retrying the failed write must not send the bytes twice
+    assert_eq!(
+        s.write(b"abcd").unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+    s.write_all(b"abcd").unwrap();
+    assert_eq!(s.stats().frames_sent, 1);
+    assert_eq!(s.stats().raw_sent, 4);
+    assert_eq!(&s.get_ref().written[HEADER_LEN..], b"abcd");
+
+    // the third frame is cut off, after which the stream refuses to send anything
+    s.write_all(b"efgh").unwrap();
+    assert_eq!(
+        s.write(b"ijkl").unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+    let sent = s.get_ref().written.len();
+    assert_eq!(sent, 2 * (HEADER_LEN + 4) + 2);
+    assert!(s.write(b"ijkl").is_err());
+    assert!(s.flush().is_err());
+    assert_eq!(s.get_ref().written.len(), sent);
+    assert_eq!(s.stats().frames_sent, 2);
+}
@@ -0,0 +1,381 @@
+//! Checks for buffer sizes that were silently reduced
+//!
+//! UDT accepts any `UDP_SNDBUF` and `UDP_RCVBUF` and reports back the value it was given, but
+//! on Linux the kernel caps the UDP socket's buffers at the `net.core.wmem_max` and
+//! `net.core.rmem_max` sysctls without any error.  UDT itself rounds `UDT_SNDBUF` and
+//! `UDT_RCVBUF` down to whole packets and caps `UDT_RCVBUF` at `UDT_FC` packets.  Either way a
+//! connection can end up with far smaller buffers than configured, and only shows it as poor
+//! throughput on long, fast paths.
+//!
+//! [`check_buffers`](fn.check_buffers.html) compares the sizes that were asked for with the
+//! socket's effective values and the kernel limits read from `/proc/sys/net/core`, and returns
+//! a `BufferReport` with a warning for every buffer that came out smaller, along with the
+//! sysctl settings that would lift the kernel limits.  On other systems, or if `/proc` can't
+//! be read, the kernel limits are unknown and only UDT's own adjustments are checked.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use udt::*;
+//! use udt::diagnostics;
+//!
+//! init();
+//! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+//! let mut wanted = sock.options().unwrap();
+//! wanted.udp_rcvbuf = 16 * 1024 * 1024;
+//! wanted.rcvbuf = 64 * 1024 * 1024;
+//! let _ = sock.apply_options(&wanted);
+//!
+//! let report = diagnostics::check_buffers(&sock, &wanted).unwrap();
+//! for warning in &report.warnings {
+//!     println!("warning: {}", warning);
+//! }
+//! for setting in &report.sysctl {
+//!     println!("suggest: sysctl -w {}", setting);
+//! }
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{OptionSnapshot, UdtError, UdtSocket};
+
+const SYSCTL_DIR: &str = "/proc/sys/net/core";
+
+/// The kernel's limits on UDP socket buffer sizes, in bytes
+///
+/// A limit is `None` if it could not be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KernelLimits {
+    /// `net.core.wmem_max`, the largest send buffer a socket may set
+    pub wmem_max: Option<usize>,
+    /// `net.core.rmem_max`, the largest receive buffer a socket may set
+    pub rmem_max: Option<usize>,
+}
+
+impl KernelLimits {
+    /// Reads the limits from `/proc/sys/net/core`.
+    pub fn read() -> KernelLimits {
+        KernelLimits::read_from(Path::new(SYSCTL_DIR))
+    }
+
+    fn read_from(dir: &Path) -> KernelLimits {
+        let read = |name: &str| {
+            fs::read_to_string(dir.join(name))
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+        };
+        KernelLimits {
+            wmem_max: read("wmem_max"),
+            rmem_max: read("rmem_max"),
+        }
+    }
+}
+
+/// The requested and effective size of one buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStatus {
+    /// The name of the option, e.g. `"UDP_RCVBUF"`
+    pub option: &'static str,
+    /// The size that was asked for, in bytes
+    pub requested: usize,
+    /// The size the buffer really has, in bytes, as far as it can be told
+    pub effective: usize,
+}
+
+/// A buffer that is smaller than requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferWarning {
+    /// The kernel caps a UDP buffer below the size UDT asks for
+    KernelLimit {
+        /// `"UDP_SNDBUF"` or `"UDP_RCVBUF"`
+        option: &'static str,
+        /// The size asked for, in bytes
+        requested: usize,
+        /// The kernel's limit, in bytes
+        limit: usize,
+        /// The sysctl that sets the limit, e.g. `"net.core.rmem_max"`
+        sysctl: &'static str,
+    },
+    /// The socket holds less than was requested, by at least one packet
+    Reduced {
+        /// The name of the option
+        option: &'static str,
+        /// The size asked for, in bytes
+        requested: usize,
+        /// The size the socket reports, in bytes
+        effective: usize,
+    },
+}
+
+impl fmt::Display for BufferWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BufferWarning::KernelLimit {
+                option,
+                requested,
+                limit,
+                sysctl,
+            } => write!(
+                f,
+                "{}: requested {} bytes, but the kernel caps it at {} ({})",
+                option, requested, limit, sysctl
+            ),
+            BufferWarning::Reduced {
+                option,
+                requested,
+                effective,
+            } => {
+                write!(
+                    f,
+                    "{}: requested {} bytes, got {}",
+                    option, requested, effective
+                )?;
+                match option {
+                    "UDT_RCVBUF" => write!(f, " (UDT caps it at UDT_FC packets)"),
+                    "UDP_SNDBUF" | "UDP_RCVBUF" => {
+                        write!(f, " (it must be set before the socket is bound)")
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// A sysctl setting that would let the requested buffers be used
+///
+/// Displays as `name=value`, the form `sysctl -w` and `/etc/sysctl.conf` take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysctlSetting {
+    /// The name of the sysctl, e.g. `"net.core.rmem_max"`
+    pub name: &'static str,
+    /// Its current value
+    pub current: usize,
+    /// The suggested value
+    pub suggested: usize,
+}
+
+impl fmt::Display for SysctlSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.suggested)
+    }
+}
+
+/// The result of [`check_buffers`](fn.check_buffers.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferReport {
+    /// The kernel limits the UDP buffers were checked against
+    pub limits: KernelLimits,
+    /// Every buffer checked: `UDT_SNDBUF`, `UDT_RCVBUF`, `UDP_SNDBUF` and `UDP_RCVBUF`
+    pub buffers: Vec<BufferStatus>,
+    /// The buffers that are smaller than requested
+    pub warnings: Vec<BufferWarning>,
+    /// Sysctl settings that would lift the kernel limits in the warnings
+    pub sysctl: Vec<SysctlSetting>,
+}
+
+impl BufferReport {
+    /// Returns true if every buffer has the requested size.
+    pub fn is_ok(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+/// Compares the buffer sizes in `requested` with the ones `sock` really has.
+///
+/// `requested` is usually the snapshot that was applied to the socket with
+/// [`apply_options`](../struct.UdtSocket.html#method.apply_options).  Only the buffer sizes
+/// are compared; see [`OptionSnapshot::diff`](../struct.OptionSnapshot.html#method.diff) for
+/// the other options.
+pub fn check_buffers(
+    sock: &UdtSocket,
+    requested: &OptionSnapshot,
+) -> Result<BufferReport, UdtError> {
+    let report = diagnose(requested, &sock.options()?, &KernelLimits::read());
+    for warning in &report.warnings {
+        debug!("{:?}: {}", sock, warning);
+    }
+    Ok(report)
+}
+
+/// Checks the socket's current buffer sizes against the kernel limits.
+pub fn check_socket(sock: &UdtSocket) -> Result<BufferReport, UdtError> {
+    check_buffers(sock, &sock.options()?)
+}
+
+fn diagnose(
+    requested: &OptionSnapshot,
+    actual: &OptionSnapshot,
+    limits: &KernelLimits,
+) -> BufferReport {
+    let bytes = |v: i32| std::cmp::max(v, 0) as usize;
+    // UDT keeps its buffers in whole packets of MSS - 28 bytes
+    let packet = bytes(actual.mss - 28);
+    let mut report = BufferReport {
+        limits: *limits,
+        buffers: Vec::new(),
+        warnings: Vec::new(),
+        sysctl: Vec::new(),
+    };
+
+    let udt = [
+        ("UDT_SNDBUF", requested.sndbuf, actual.sndbuf),
+        ("UDT_RCVBUF", requested.rcvbuf, actual.rcvbuf),
+    ];
+    for &(option, requested, effective) in udt.iter() {
+        let (requested, effective) = (bytes(requested), bytes(effective));
+        if effective + packet <= requested {
+            report.warnings.push(BufferWarning::Reduced {
+                option,
+                requested,
+                effective,
+            });
+        }
+        report.buffers.push(BufferStatus {
+            option,
+            requested,
+            effective,
+        });
+    }
+
+    let udp = [
+        (
+            "UDP_SNDBUF",
+            requested.udp_sndbuf,
+            actual.udp_sndbuf,
+            limits.wmem_max,
+            "net.core.wmem_max",
+        ),
+        (
+            "UDP_RCVBUF",
+            requested.udp_rcvbuf,
+            actual.udp_rcvbuf,
+            limits.rmem_max,
+            "net.core.rmem_max",
+        ),
+    ];
+    for &(option, requested, set, limit, sysctl) in udp.iter() {
+        let (requested, set) = (bytes(requested), bytes(set));
+        if set < requested {
+            report.warnings.push(BufferWarning::Reduced {
+                option,
+                requested,
+                effective: set,
+            });
+        }
+        let wanted = std::cmp::max(requested, set);
+        let mut effective = set;
+        if let Some(limit) = limit {
+            effective = std::cmp::min(set, limit);
+            if wanted > limit {
+                report.warnings.push(BufferWarning::KernelLimit {
+                    option,
+                    requested: wanted,
+                    limit,
+                    sysctl,
+                });
+                report.sysctl.push(SysctlSetting {
+                    name: sysctl,
+                    current: limit,
+                    suggested: wanted,
+                });
+            }
+        }
+        report.buffers.push(BufferStatus {
+            option,
+            requested,
+            effective,
+        });
+    }
+    report
+}
+
+#[test]
+fn test_diagnostics_kernel_limits() {
+    let mut requested = crate::snapshot::defaults();
+    requested.udp_rcvbuf = 16 * 1024 * 1024;
+    requested.udp_sndbuf = 4 * 1024 * 1024;
+    let actual = requested.clone();
+    // the Linux defaults
+    let limits = KernelLimits {
+        wmem_max: Some(212_992),
+        rmem_max: Some(212_992),
+    };
+
+    let report = diagnose(&requested, &actual, &limits);
+    assert!(!report.is_ok());
+    assert_eq!(report.warnings.len(), 2);
+    assert_eq!(
+        report.warnings[1].to_string(),
+        "UDP_RCVBUF: requested 16777216 bytes, but the kernel caps it at 212992 \
+         (net.core.rmem_max)"
+    );
+    let settings: Vec<String> = report.sysctl.iter().map(|s| s.to_string()).collect();
+    assert_eq!(
+        settings,
+        ["net.core.wmem_max=4194304", "net.core.rmem_max=16777216"]
+    );
+    assert_eq!(report.buffers[3].effective, 212_992);
+
+    // nothing to warn about with large enough limits, or unknown ones
+    let limits = KernelLimits {
+        wmem_max: Some(1 << 30),
+        rmem_max: Some(1 << 30),
+    };
+    assert!(diagnose(&requested, &actual, &limits).is_ok());
+    assert!(diagnose(&requested, &actual, &KernelLimits::default()).is_ok());
+}
+
+#[test]
+fn test_diagnostics_udt_reductions() {
+    let mut requested = crate::snapshot::defaults();
+    requested.rcvbuf = 100_000_000;
+    let mut actual = requested.clone();
+    // UDT rounds to whole packets, which is not worth a warning
+    actual.sndbuf = 6956 * 1472;
+    // and caps the receiver buffer at the flow window
+    actual.rcvbuf = 25600 * 1472;
+    // a UDP buffer set too late keeps its old size
+    actual.udp_sndbuf = 65536;
+    requested.udp_sndbuf = 1_000_000;
+
+    let report = diagnose(&requested, &actual, &KernelLimits::default());
+    assert_eq!(
+        report.warnings,
+        [
+            BufferWarning::Reduced {
+                option: "UDT_RCVBUF",
+                requested: 100_000_000,
+                effective: 37_683_200,
+            },
+            BufferWarning::Reduced {
+                option: "UDP_SNDBUF",
+                requested: 1_000_000,
+                effective: 65536,
+            },
+        ]
+    );
+    assert!(report.warnings[0]
+        .to_string()
+        .ends_with("(UDT caps it at UDT_FC packets)"));
+    assert!(report.sysctl.is_empty());
+}
+
+#[test]
+fn test_diagnostics_read_limits() {
+    let dir = std::env::temp_dir().join(format!("udt-sysctl-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("rmem_max"), "8388608\n").unwrap();
+    fs::write(dir.join("wmem_max"), "garbage\n").unwrap();
+    let limits = KernelLimits::read_from(&dir);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        limits,
+        KernelLimits {
+            wmem_max: None,
+            rmem_max: Some(8_388_608),
+        }
+    );
+}
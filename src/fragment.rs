@@ -0,0 +1,518 @@
+//! Fragmentation of large messages on `Datagram` sockets
+//!
+//! `sendmsg` fails with `ELARGEMSG` if a message doesn't fit into the send buffer.  A
+//! `FragmentSocket` splits messages into fragments that each fit into one UDT message, and
+//! reassembles them on the receiving side, so messages of any size (up to a configurable
+//! limit) can be sent without chunking them by hand.
+//!
+//! Every fragment of a message is sent with the same TTL.  If UDT drops some of them because the
+//! TTL expired, the message can't be completed; the receiver then discards the fragments it did
+//! get instead of holding on to them:
+//!
+//! * for in-order messages, as soon as a fragment of a later in-order message arrives (UDT
+//!   delivers them in order, so the missing fragments can't turn up any more);
+//! * for out-of-order messages, once the reassembly timeout has passed since the first fragment
+//!   arrived.
+//!
+//! The number of incomplete messages is also limited; when there are too many, the oldest is
+//! discarded.
+//!
+//! Both sides must use a `FragmentSocket`, with the same fragment size.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::fragment::FragmentConfig;
+//!
+//! init();
+//! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+//! sock.connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//!
+//! let sock = FragmentConfig::new().wrap(sock);
+//! let snapshot = vec![0u8; 50 * 1024 * 1024];
+//! sock.send_ttl(&snapshot, Some(Duration::from_secs(2)), true).unwrap();
+//! ```
+//!
+//! # Wire format
+//!
+//! Every fragment starts with a 13 byte header: a flags byte (bit 0 is set if the message was
+//! sent in order), then the message id, the fragment index and the number of fragments as
+//! 32-bit big-endian integers.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::stream::OwnedSocket;
+use crate::{UdtError, UdtSocket};
+
+const HEADER_LEN: usize = 13;
+const FLAG_INORDER: u8 = 1;
+
+/// Errors from a `FragmentSocket`
+///
+/// `BadFragment` only concerns a single fragment, which has been discarded; the socket can still
+/// be used afterwards.
+#[derive(Debug)]
+pub enum FragmentError {
+    /// The underlying socket failed
+    Udt(UdtError),
+    /// A message was larger than the configured maximum
+    TooLarge {
+        /// The length of the message
+        len: usize,
+        /// The configured maximum
+        max: usize,
+    },
+    /// A fragment was malformed, or larger than the configured fragment size
+    BadFragment(&'static str),
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FragmentError::Udt(ref e) => write!(f, "{}", e),
+            FragmentError::TooLarge { len, max } => {
+                write!(f, "message of {} bytes exceeds the maximum of {}", len, max)
+            }
+            FragmentError::BadFragment(msg) => write!(f, "bad fragment: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for FragmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            FragmentError::Udt(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UdtError> for FragmentError {
+    fn from(e: UdtError) -> FragmentError {
+        FragmentError::Udt(e)
+    }
+}
+
+/// Settings for a `FragmentSocket`
+#[derive(Debug, Clone)]
+pub struct FragmentConfig {
+    fragment_size: usize,
+    max_message_size: usize,
+    max_pending: usize,
+    reassembly_timeout: Duration,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> FragmentConfig {
+        FragmentConfig::new()
+    }
+}
+
+impl FragmentConfig {
+    /// Creates the default configuration: 64 KiB fragments, messages of up to 256 MiB, at most
+    /// 16 incomplete messages and a 10 second reassembly timeout.
+    pub fn new() -> FragmentConfig {
+        FragmentConfig {
+            fragment_size: 64 * 1024,
+            max_message_size: 256 * 1024 * 1024,
+            max_pending: 16,
+            reassembly_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the payload size of each fragment.
+    ///
+    /// It must be smaller than the send buffer (`UDT_SNDBUF`), and must be the same on both
+    /// sides.
+    pub fn fragment_size(mut self, bytes: usize) -> FragmentConfig {
+        assert!(bytes > 0, "fragments must not be empty");
+        self.fragment_size = bytes;
+        self
+    }
+
+    /// Sets the largest message that may be sent or received.
+    pub fn max_message_size(mut self, bytes: usize) -> FragmentConfig {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Sets how many incomplete messages are kept while waiting for their remaining fragments.
+    pub fn max_pending(mut self, messages: usize) -> FragmentConfig {
+        assert!(
+            messages > 0,
+            "at least one message must be reassembled at a time"
+        );
+        self.max_pending = messages;
+        self
+    }
+
+    /// Sets how long the fragments of an out-of-order message are kept before the message is
+    /// considered lost.  This should be longer than the TTL the sender uses.
+    pub fn reassembly_timeout(mut self, timeout: Duration) -> FragmentConfig {
+        self.reassembly_timeout = timeout;
+        self
+    }
+
+    /// Takes ownership of a connected `Datagram` socket.
+    pub fn wrap(&self, sock: UdtSocket) -> FragmentSocket {
+        FragmentSocket {
+            sock: OwnedSocket::new(sock),
+            config: self.clone(),
+            next_id: AtomicU32::new(0),
+            send_lock: Mutex::new(()),
+            recv: Mutex::new(Reassembler::new(self.clone())),
+            stats: Mutex::new(FragmentStats::default()),
+        }
+    }
+}
+
+/// Counters kept by a `FragmentSocket`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragmentStats {
+    /// Messages sent
+    pub messages_sent: u64,
+    /// Fragments sent
+    pub fragments_sent: u64,
+    /// Messages received and reassembled
+    pub messages_received: u64,
+    /// Fragments received
+    pub fragments_received: u64,
+    /// Incomplete messages that were discarded because some of their fragments were lost
+    pub discarded: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    inorder: bool,
+    id: u32,
+    index: u32,
+    count: u32,
+}
+
+impl Header {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(if self.inorder { FLAG_INORDER } else { 0 });
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.count.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Result<Header, FragmentError> {
+        if buf.len() < HEADER_LEN || buf[0] & !FLAG_INORDER != 0 {
+            return Err(FragmentError::BadFragment("bad header"));
+        }
+        let word = |i: usize| {
+            let mut w = [0u8; 4];
+            w.copy_from_slice(&buf[i..i + 4]);
+            u32::from_be_bytes(w)
+        };
+        let h = Header {
+            inorder: buf[0] & FLAG_INORDER != 0,
+            id: word(1),
+            index: word(5),
+            count: word(9),
+        };
+        if h.count == 0 || h.index >= h.count {
+            return Err(FragmentError::BadFragment("bad fragment index"));
+        }
+        Ok(h)
+    }
+}
+
+// true if message id `a` was sent after `b`, allowing for wrap around
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[derive(Debug)]
+struct Partial {
+    inorder: bool,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: u32,
+    started: Instant,
+}
+
+// Reassembly state, separate from the socket so it can be tested on its own
+#[derive(Debug)]
+struct Reassembler {
+    config: FragmentConfig,
+    pending: HashMap<u32, Partial>,
+    discarded: u64,
+}
+
+impl Reassembler {
+    fn new(config: FragmentConfig) -> Reassembler {
+        Reassembler {
+            config,
+            pending: HashMap::new(),
+            discarded: 0,
+        }
+    }
+
+    fn discard(&mut self, id: u32, why: &str) {
+        if let Some(p) = self.pending.remove(&id) {
+            debug!(
+                "discarding message {} ({} of {} fragments missing): {}",
+                id,
+                p.missing,
+                p.fragments.len(),
+                why
+            );
+            self.discarded += 1;
+        }
+    }
+
+    // Adds a fragment, returning the message if it is now complete.
+    fn push(
+        &mut self,
+        h: Header,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        let frag = self.config.fragment_size;
+        if payload.len() > frag || (h.index + 1 < h.count && payload.len() != frag) {
+            return Err(FragmentError::BadFragment("unexpected fragment size"));
+        }
+        if (h.count as u64 - 1) * frag as u64 > self.config.max_message_size as u64 {
+            return Err(FragmentError::BadFragment("message too large"));
+        }
+
+        // expire what can't be completed any more
+        let timeout = self.config.reassembly_timeout;
+        let stale: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|&(&id, p)| {
+                (p.inorder && h.inorder && newer(h.id, id))
+                    || now.duration_since(p.started) >= timeout
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in stale {
+            self.discard(id, "fragments were dropped");
+        }
+
+        if h.count == 1 {
+            return Ok(Some(payload.to_vec()));
+        }
+        if !self.pending.contains_key(&h.id) && self.pending.len() >= self.config.max_pending {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|&(_, p)| p.started)
+                .map(|(&id, _)| id)
+                .unwrap();
+            self.discard(oldest, "too many incomplete messages");
+        }
+
+        let p = self.pending.entry(h.id).or_insert_with(|| Partial {
+            inorder: h.inorder,
+            fragments: vec![None; h.count as usize],
+            missing: h.count,
+            started: now,
+        });
+        if p.fragments.len() != h.count as usize {
+            return Err(FragmentError::BadFragment("inconsistent fragment count"));
+        }
+        let slot = &mut p.fragments[h.index as usize];
+        if slot.is_none() {
+            *slot = Some(payload.to_vec());
+            p.missing -= 1;
+        }
+        if p.missing > 0 {
+            return Ok(None);
+        }
+
+        let p = self.pending.remove(&h.id).unwrap();
+        let len = p.fragments.iter().map(|f| f.as_ref().unwrap().len()).sum();
+        let mut msg = Vec::with_capacity(len);
+        for f in p.fragments {
+            msg.extend_from_slice(&f.unwrap());
+        }
+        Ok(Some(msg))
+    }
+}
+
+/// A `Datagram` socket that fragments large messages
+///
+/// Created by [`FragmentConfig::wrap`](struct.FragmentConfig.html#method.wrap).  It can be
+/// shared between threads; sends are serialized so that the fragments of different messages
+/// aren't interleaved.  The socket is closed when the `FragmentSocket` is dropped.
+#[derive(Debug)]
+pub struct FragmentSocket {
+    sock: OwnedSocket,
+    config: FragmentConfig,
+    next_id: AtomicU32,
+    send_lock: Mutex<()>,
+    recv: Mutex<Reassembler>,
+    stats: Mutex<FragmentStats>,
+}
+
+impl FragmentSocket {
+    /// Returns the underlying socket.
+    ///
+    /// Sending or receiving on it directly will confuse the peer.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Sends a message, with no TTL and in-order delivery.
+    pub fn send(&self, msg: &[u8]) -> Result<(), FragmentError> {
+        self.send_ttl(msg, None, true)
+    }
+
+    /// Sends a message with the given TTL and ordering (see `UdtSocket::sendmsg_ttl`).
+    ///
+    /// The TTL applies to each fragment separately.  If any of them expires, the receiver
+    /// discards the whole message.
+    pub fn send_ttl(
+        &self,
+        msg: &[u8],
+        ttl: Option<Duration>,
+        inorder: bool,
+    ) -> Result<(), FragmentError> {
+        if msg.len() > self.config.max_message_size {
+            return Err(FragmentError::TooLarge {
+                len: msg.len(),
+                max: self.config.max_message_size,
+            });
+        }
+        let chunks: Vec<&[u8]> = if msg.is_empty() {
+            vec![msg]
+        } else {
+            msg.chunks(self.config.fragment_size).collect()
+        };
+
+        let _guard = self.send_lock.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.config.fragment_size);
+        for (index, chunk) in chunks.iter().enumerate() {
+            buf.clear();
+            Header {
+                inorder,
+                id,
+                index: index as u32,
+                count: chunks.len() as u32,
+            }
+            .encode(&mut buf);
+            buf.extend_from_slice(chunk);
+            self.sock.sendmsg_ttl(&buf, ttl, inorder)?;
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.fragments_sent += chunks.len() as u64;
+        Ok(())
+    }
+
+    /// Receives the next complete message.
+    ///
+    /// Incomplete messages are discarded as described in the [module
+    /// documentation](index.html).  If another thread is receiving at the same time, each gets
+    /// some of the fragments and neither may complete a message, so only receive from one
+    /// thread.
+    pub fn recv(&self) -> Result<Vec<u8>, FragmentError> {
+        // one spare byte tells us whether UDT had to truncate the fragment
+        let mut buf = vec![0u8; HEADER_LEN + self.config.fragment_size + 1];
+        loop {
+            let len = self.sock.recvmsg(&mut buf)?;
+            if len == buf.len() {
+                return Err(FragmentError::BadFragment(
+                    "fragment larger than the fragment size",
+                ));
+            }
+            let header = Header::decode(&buf[..len])?;
+            let mut recv = self.recv.lock().unwrap();
+            let done = recv.push(header, &buf[HEADER_LEN..len], Instant::now())?;
+            let discarded = recv.discarded;
+            drop(recv);
+
+            let mut stats = self.stats.lock().unwrap();
+            stats.fragments_received += 1;
+            stats.discarded = discarded;
+            if let Some(msg) = done {
+                stats.messages_received += 1;
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Returns a snapshot of the message counters.
+    pub fn stats(&self) -> FragmentStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Closes the socket, reporting any error from UDT.
+    pub fn close(self) -> Result<(), UdtError> {
+        self.sock.close()
+    }
+
+    // Closes the socket while others may still be using it, e.g. to wake up a thread blocked in
+    // `recv`.  Dropping the `FragmentSocket` later doesn't close it again.
+    pub(crate) fn close_shared(&self) -> Result<(), UdtError> {
+        self.sock.close()
+    }
+}
+
+#[test]
+fn test_reassembly() {
+    let config = FragmentConfig::new()
+        .fragment_size(4)
+        .max_pending(2)
+        .reassembly_timeout(Duration::from_secs(5));
+    let mut r = Reassembler::new(config);
+    let now = Instant::now();
+    let h = |inorder, id, index, count| Header {
+        inorder,
+        id,
+        index,
+        count,
+    };
+
+    let mut buf = Vec::new();
+    h(true, 7, 2, 3).encode(&mut buf);
+    assert_eq!(Header::decode(&buf).unwrap(), h(true, 7, 2, 3));
+    assert!(Header::decode(&buf[..12]).is_err());
+
+    // fragments may arrive in any order
+    assert_eq!(r.push(h(false, 1, 1, 3), b"efgh", now).unwrap(), None);
+    assert_eq!(r.push(h(false, 1, 2, 3), b"ij", now).unwrap(), None);
+    assert_eq!(
+        r.push(h(false, 1, 0, 3), b"abcd", now).unwrap().unwrap(),
+        b"abcdefghij"
+    );
+    assert!(r.push(h(false, 2, 0, 2), b"abc", now).is_err());
+
+    // an in-order message is given up as soon as the next one starts
+    r.push(h(true, 2, 0, 2), b"abcd", now).unwrap();
+    assert_eq!(r.push(h(true, 3, 0, 1), b"x", now).unwrap().unwrap(), b"x");
+    assert_eq!(r.discarded, 1);
+    assert!(r.pending.is_empty());
+
+    // an out-of-order one only after the timeout
+    r.push(h(false, 4, 0, 2), b"abcd", now).unwrap();
+    r.push(h(false, 5, 0, 1), b"y", now).unwrap();
+    assert_eq!(r.pending.len(), 1);
+    r.push(h(false, 6, 0, 1), b"z", now + Duration::from_secs(6))
+        .unwrap();
+    assert_eq!(r.discarded, 2);
+
+    // and the oldest goes when there are too many
+    r.push(h(false, 7, 0, 2), b"abcd", now).unwrap();
+    r.push(h(false, 8, 0, 2), b"abcd", now + Duration::from_secs(1))
+        .unwrap();
+    r.push(h(false, 9, 0, 2), b"abcd", now + Duration::from_secs(2))
+        .unwrap();
+    assert_eq!(r.discarded, 3);
+    assert!(!r.pending.contains_key(&7));
+    assert_eq!(
+        r.push(h(false, 8, 1, 2), b"!", now).unwrap().unwrap(),
+        b"abcd!"
+    );
+}
@@ -0,0 +1,565 @@
+//! Length-prefixed message framing for `UdtStream`s
+//!
+//! `Stream` sockets are fast but have no message boundaries, and `Datagram` sockets can't send
+//! messages larger than the send buffer (`sendmsg` fails with `ELARGEMSG`).  A `FramedStream`
+//! sends whole messages over a stream: each frame is prefixed with its length as a varint, and
+//! can optionally carry a CRC32C checksum of its payload.
+//!
+//! Both sides must use the same settings; they are not negotiated.
+//!
+//! With the `async` feature, [`FrameCodec`](struct.FrameCodec.html) implements the same format
+//! as a tokio-util `Decoder` and `Encoder`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use udt::*;
+//! use udt::framed::FrameConfig;
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let mut framed = FrameConfig::new().checksum(true).wrap(stream);
+//! framed.send_frame(b"first message").unwrap();
+//! while let Some(frame) = framed.recv_frame().unwrap() {
+//!     println!("got {} bytes", frame.len());
+//! }
+//! ```
+//!
+//! # Wire format
+//!
+//! The payload length as an unsigned LEB128 varint, the payload, and if checksums are enabled,
+//! the CRC32C of the payload as a 32-bit big-endian integer.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::UdtStream;
+
+// the longest LEB128 encoding of a u64
+const MAX_VARINT_LEN: usize = 10;
+// how much a `FramedStream` asks the underlying stream for at a time
+const READ_BUF_SIZE: usize = 8 * 1024;
+
+/// Errors from a `FramedStream` or `FrameCodec`
+#[derive(Debug)]
+pub enum FrameError {
+    /// A frame was larger than the configured maximum.  When receiving, the stream can't be used
+    /// any more, since the rest of the frame hasn't been read.
+    TooLarge {
+        /// The length of the frame
+        len: u64,
+        /// The configured maximum
+        max: usize,
+    },
+    /// The frame length prefix was not a valid varint
+    BadLength,
+    /// The checksum of a received frame didn't match its payload
+    Checksum,
+    /// The stream ended in the middle of a frame
+    Truncated,
+    /// The underlying stream failed
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the maximum of {}", len, max)
+            }
+            FrameError::BadLength => write!(f, "invalid frame length prefix"),
+            FrameError::Checksum => write!(f, "frame checksum mismatch"),
+            FrameError::Truncated => write!(f, "stream ended in the middle of a frame"),
+            FrameError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            FrameError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> io::Error {
+        match e {
+            FrameError::Io(e) => e,
+            FrameError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// Computes the CRC32C (Castagnoli) checksum of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn encode_varint(mut n: u64, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// Decodes a varint from the start of `buf`, returning the value and its encoded length, or
+// `None` if `buf` ends before the varint does.
+fn decode_varint(buf: &[u8]) -> Result<Option<(u64, usize)>, FrameError> {
+    let mut n = 0u64;
+    for (i, &b) in buf.iter().enumerate().take(MAX_VARINT_LEN) {
+        if i == MAX_VARINT_LEN - 1 && b > 1 {
+            return Err(FrameError::BadLength);
+        }
+        n |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((n, i + 1)));
+        }
+    }
+    if buf.len() >= MAX_VARINT_LEN {
+        Err(FrameError::BadLength)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Settings shared by both ends of a framed connection
+#[derive(Debug, Clone, Copy)]
+pub struct FrameConfig {
+    max_frame_size: usize,
+    checksum: bool,
+}
+
+impl Default for FrameConfig {
+    fn default() -> FrameConfig {
+        FrameConfig::new()
+    }
+}
+
+impl FrameConfig {
+    /// Creates the default configuration: frames of up to 16 MiB, without checksums.
+    pub fn new() -> FrameConfig {
+        FrameConfig {
+            max_frame_size: 16 * 1024 * 1024,
+            checksum: false,
+        }
+    }
+
+    /// Sets the largest payload that may be sent or received.
+    ///
+    /// A received frame is rejected as soon as its length prefix has been read, so this also
+    /// bounds the memory a misbehaving peer can make the receiver allocate.
+    pub fn max_frame_size(mut self, bytes: usize) -> FrameConfig {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Enables or disables a CRC32C checksum after every payload.
+    ///
+    /// UDT already detects corrupted packets, so this is mostly useful when the data passes
+    /// through other hops (e.g. a relay) that might damage it.
+    pub fn checksum(mut self, enabled: bool) -> FrameConfig {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Wraps a stream to send and receive frames with these settings.
+    pub fn wrap<S: Read + Write>(self, stream: S) -> FramedStream<S> {
+        FramedStream {
+            inner: stream,
+            config: self,
+            rbuf: Vec::new(),
+            rpos: 0,
+        }
+    }
+
+    /// Returns a tokio-util codec for these settings.
+    #[cfg(feature = "async")]
+    pub fn codec(self) -> FrameCodec {
+        FrameCodec { config: self }
+    }
+
+    fn check_len(&self, len: u64) -> Result<usize, FrameError> {
+        if len > self.max_frame_size as u64 {
+            Err(FrameError::TooLarge {
+                len,
+                max: self.max_frame_size,
+            })
+        } else {
+            Ok(len as usize)
+        }
+    }
+
+    fn trailer_len(&self) -> usize {
+        if self.checksum {
+            4
+        } else {
+            0
+        }
+    }
+
+    fn encode(&self, payload: &[u8], out: &mut Vec<u8>) -> Result<(), FrameError> {
+        self.check_len(payload.len() as u64)?;
+        out.reserve(MAX_VARINT_LEN + payload.len() + self.trailer_len());
+        encode_varint(payload.len() as u64, out);
+        out.extend_from_slice(payload);
+        if self.checksum {
+            out.extend_from_slice(&crc32c(payload).to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn verify(&self, payload: &[u8], trailer: &[u8]) -> Result<(), FrameError> {
+        if self.checksum {
+            let mut crc = [0u8; 4];
+            crc.copy_from_slice(trailer);
+            if u32::from_be_bytes(crc) != crc32c(payload) {
+                return Err(FrameError::Checksum);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream that sends and receives whole frames
+///
+/// Created by [`FrameConfig::wrap`](struct.FrameConfig.html#method.wrap).
+#[derive(Debug)]
+pub struct FramedStream<S = UdtStream> {
+    inner: S,
+    config: FrameConfig,
+    // bytes read from `inner` but not returned yet, starting at `rpos`
+    rbuf: Vec<u8>,
+    rpos: usize,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    /// Wraps a stream with the default settings.
+    pub fn new(stream: S) -> FramedStream<S> {
+        FrameConfig::new().wrap(stream)
+    }
+
+    /// Returns the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the underlying stream.
+    ///
+    /// Frames are received in chunks, so data after the last received frame may already have
+    /// been read from the stream.  Use [`into_parts`](#method.into_parts) to drop back to
+    /// unframed I/O after a message that announced it.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the underlying stream and the data already read from it after the last received
+    /// frame.
+    pub fn into_parts(mut self) -> (S, Vec<u8>) {
+        let rest = self.rbuf.split_off(self.rpos);
+        (self.inner, rest)
+    }
+
+    /// Sends one frame.
+    ///
+    /// A large frame may take several sends, so frames written from different threads through
+    /// separate `FramedStream`s over one socket can be interleaved.  Share a single
+    /// `FramedStream` behind a lock instead.
+    pub fn send_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        let mut buf = Vec::new();
+        self.config.encode(payload, &mut buf)?;
+        self.inner.write_all(&buf)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Receives the next frame.
+    ///
+    /// Returns `None` if the peer closed the stream between two frames.
+    pub fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let (len, prefix_len) = loop {
+            if let Some(v) = decode_varint(&self.rbuf[self.rpos..])? {
+                break v;
+            }
+            if !self.fill()? {
+                if self.rpos == self.rbuf.len() {
+                    return Ok(None);
+                }
+                return Err(FrameError::Truncated);
+            }
+        };
+        let len = self.config.check_len(len)?;
+        self.rpos += prefix_len;
+
+        // take what was already read, then read the rest of a large frame straight into place
+        let total = len + self.config.trailer_len();
+        let buffered = std::cmp::min(total, self.rbuf.len() - self.rpos);
+        let mut buf = Vec::with_capacity(total);
+        buf.extend_from_slice(&self.rbuf[self.rpos..self.rpos + buffered]);
+        self.rpos += buffered;
+        buf.resize(total, 0);
+        self.inner.read_exact(&mut buf[buffered..]).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                FrameError::Truncated
+            } else {
+                FrameError::Io(e)
+            }
+        })?;
+        self.config.verify(&buf[..len], &buf[len..])?;
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+
+    // Reads more of the stream into the read buffer.  Returns false at the end of the stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        self.rbuf.drain(..self.rpos);
+        self.rpos = 0;
+        let start = self.rbuf.len();
+        self.rbuf.resize(start + READ_BUF_SIZE, 0);
+        let n = match self.inner.read(&mut self.rbuf[start..]) {
+            Ok(n) => n,
+            Err(e) => {
+                self.rbuf.truncate(start);
+                return Err(e);
+            }
+        };
+        self.rbuf.truncate(start + n);
+        Ok(n > 0)
+    }
+}
+
+/// A tokio-util codec for the frame format of this module
+///
+/// Created by [`FrameConfig::codec`](struct.FrameConfig.html#method.codec).  Decoded frames are
+/// returned without their length prefix and checksum.
+///
+/// This requires the `async` feature.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCodec {
+    config: FrameConfig,
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Decoder for FrameCodec {
+    type Item = bytes::BytesMut;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, FrameError> {
+        use bytes::Buf;
+
+        let (len, prefix_len) = match decode_varint(src)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let len = self.config.check_len(len)?;
+        let total = prefix_len + len + self.config.trailer_len();
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+        src.advance(prefix_len);
+        let payload = src.split_to(len);
+        let trailer = src.split_to(self.config.trailer_len());
+        self.config.verify(&payload, &trailer)?;
+        Ok(Some(payload))
+    }
+
+    fn decode_eof(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, FrameError> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(FrameError::Truncated),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> tokio_util::codec::Encoder<&'a [u8]> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, item: &'a [u8], dst: &mut bytes::BytesMut) -> Result<(), FrameError> {
+        let mut buf = Vec::new();
+        self.config.encode(item, &mut buf)?;
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Encoder<bytes::Bytes> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, item: bytes::Bytes, dst: &mut bytes::BytesMut) -> Result<(), FrameError> {
+        tokio_util::codec::Encoder::<&[u8]>::encode(self, &item, dst)
+    }
+}
+
+#[test]
+fn test_varint_and_crc() {
+    for &n in &[
+        0u64,
+        1,
+        127,
+        128,
+        300,
+        16_383,
+        16_384,
+        u32::MAX as u64,
+        u64::MAX,
+    ] {
+        let mut buf = Vec::new();
+        encode_varint(n, &mut buf);
+        assert_eq!(decode_varint(&buf).unwrap(), Some((n, buf.len())));
+        assert_eq!(decode_varint(&buf[..buf.len() - 1]).unwrap(), None);
+    }
+    assert!(decode_varint(&[0xff; 11]).is_err());
+
+    // the check value from the CRC catalogue
+    assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+}
+
+#[test]
+fn test_framed_roundtrip() {
+    // a `Read + Write` that reads back what was written
+    // and counts the reads
+    #[derive(Default)]
+    struct Loopback(io::Cursor<Vec<u8>>, Vec<u8>, usize);
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.2 += 1;
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let config = FrameConfig::new().checksum(true).max_frame_size(1000);
+    let mut framed = config.wrap(Loopback::default());
+    framed.send_frame(b"").unwrap();
+    framed.send_frame(b"hello").unwrap();
+    framed.send_frame(&[7u8; 1000]).unwrap();
+    match framed.send_frame(&[7u8; 1001]) {
+        Err(FrameError::TooLarge {
+            len: 1001,
+            max: 1000,
+        }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let wire = std::mem::take(&mut framed.inner.1);
+    framed.inner.0 = io::Cursor::new(wire.clone());
+    assert_eq!(framed.recv_frame().unwrap().unwrap(), b"");
+    assert_eq!(framed.recv_frame().unwrap().unwrap(), b"hello");
+    assert_eq!(framed.recv_frame().unwrap().unwrap(), vec![7u8; 1000]);
+    assert!(framed.recv_frame().unwrap().is_none());
+    // all three frames fit in one read, plus one more to see the end of the stream
+    assert_eq!(framed.inner.2, 2);
+
+    // corrupt the "hello" payload, which follows the 5 byte empty frame and its prefix
+    let mut corrupt = wire.clone();
+    corrupt[7] ^= 1;
+    framed.inner.0 = io::Cursor::new(corrupt);
+    framed.recv_frame().unwrap();
+    assert!(matches!(framed.recv_frame(), Err(FrameError::Checksum)));
+
+    // the receiver's limit applies too
+    let mut small = FrameConfig::new()
+        .checksum(true)
+        .max_frame_size(10)
+        .wrap(Loopback(io::Cursor::new(wire.clone()), Vec::new(), 0));
+    small.recv_frame().unwrap();
+    small.recv_frame().unwrap();
+    assert!(matches!(
+        small.recv_frame(),
+        Err(FrameError::TooLarge { len: 1000, .. })
+    ));
+
+    // data after a frame is handed back with the stream
+    let mut framed = FrameConfig::new().wrap(Loopback::default());
+    framed.send_frame(b"switch").unwrap();
+    let mut wire = std::mem::take(&mut framed.inner.1);
+    wire.extend_from_slice(b"raw bytes");
+    framed.inner.0 = io::Cursor::new(wire);
+    assert_eq!(framed.recv_frame().unwrap().unwrap(), b"switch");
+    let (_, rest) = framed.into_parts();
+    assert_eq!(rest, b"raw bytes");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_frame_codec() {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let mut codec = FrameConfig::new().checksum(true).codec();
+    let mut buf = BytesMut::new();
+    codec.encode(&b"hello"[..], &mut buf).unwrap();
+    codec
+        .encode(bytes::Bytes::from_static(b"world"), &mut buf)
+        .unwrap();
+
+    // feed the bytes in one at a time
+    let wire = buf.split();
+    let mut frames = Vec::new();
+    for b in wire.iter() {
+        buf.extend_from_slice(&[*b]);
+        if let Some(frame) = codec.decode(&mut buf).unwrap() {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(frames, vec![&b"hello"[..], &b"world"[..]]);
+    assert!(codec.decode_eof(&mut buf).unwrap().is_none());
+
+    buf.extend_from_slice(&wire[..4]);
+    assert!(matches!(
+        codec.decode_eof(&mut buf),
+        Err(FrameError::Truncated)
+    ));
+}
@@ -0,0 +1,538 @@
+//! Application-level heartbeats and dead-peer detection for a `UdtStream`
+//!
+//! UDT only reports `ECONNLOST` once its own timers give up on a peer, which can take many
+//! seconds.  A `KeepaliveStream` carries the application's data in frames and interleaves
+//! heartbeats with them:
+//!
+//! * when nothing has been sent for one heartbeat interval, a `PING` is sent, which the peer
+//!   answers with a `PONG`; the round trip time of these is measured;
+//! * when nothing at all has been received for the configured number of intervals, the peer is
+//!   declared dead, the socket is closed, and reads and writes fail with a
+//!   [`PeerTimeout`](struct.PeerTimeout.html) error.
+//!
+//! A background thread reads from the connection so that heartbeats are answered even while the
+//! application is busy.  It buffers up to a configurable amount of data; while that buffer is
+//! full the peer is assumed to be alive, since the application isn't consuming what it sent.
+//!
+//! Both sides must use a `KeepaliveStream`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::Read;
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::keepalive::{KeepaliveConfig, PeerTimeout};
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let mut stream = KeepaliveConfig::new()
+//!     .interval(Duration::from_millis(100))
+//!     .max_misses(3)
+//!     .wrap(stream);
+//!
+//! let mut buf = [0u8; 4096];
+//! match stream.read(&mut buf) {
+//!     Err(ref e) if PeerTimeout::is_peer_timeout(e) => println!("peer vanished"),
+//!     r => println!("{:?}, rtt {:?}", r, stream.rtt()),
+//! }
+//! ```
+//!
+//! # Wire format
+//!
+//! Every frame is a type byte, the payload length as a 32-bit big-endian integer, and the
+//! payload.  `DATA` (0) frames carry up to 64 KiB of application data.  `PING` (1) frames carry
+//! an 8 byte token, which is echoed back unchanged in a `PONG` (2) frame.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::stream::Transport;
+use crate::UdtStream;
+
+const DATA: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+const HEADER_LEN: usize = 5;
+const MAX_DATA: usize = 64 * 1024;
+
+/// The error reported once the peer has been declared dead
+///
+/// Reads and writes on a `KeepaliveStream` return it wrapped in an `io::Error` of kind
+/// `TimedOut`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerTimeout {
+    /// How long nothing had been received from the peer
+    pub silent_for: Duration,
+    /// The number of heartbeat intervals that passed without hearing from the peer
+    pub misses: u32,
+}
+
+impl PeerTimeout {
+    /// Returns true if `err` was caused by a `PeerTimeout`.
+    pub fn is_peer_timeout(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|e| e.is::<PeerTimeout>())
+    }
+}
+
+impl fmt::Display for PeerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "peer timed out: nothing received for {:?} ({} missed heartbeats)",
+            self.silent_for, self.misses
+        )
+    }
+}
+
+impl std::error::Error for PeerTimeout {}
+
+impl From<PeerTimeout> for io::Error {
+    fn from(e: PeerTimeout) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Settings for a `KeepaliveStream`
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    interval: Duration,
+    max_misses: u32,
+    recv_buffer: usize,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> KeepaliveConfig {
+        KeepaliveConfig::new()
+    }
+}
+
+impl KeepaliveConfig {
+    /// Creates the default configuration: a heartbeat interval of 200ms, the peer is declared
+    /// dead after 4 missed intervals, and up to 1 MiB of received data is buffered.
+    pub fn new() -> KeepaliveConfig {
+        KeepaliveConfig {
+            interval: Duration::from_millis(200),
+            max_misses: 4,
+            recv_buffer: 1024 * 1024,
+        }
+    }
+
+    /// Sets how long the connection may be idle before a heartbeat is sent.
+    pub fn interval(mut self, interval: Duration) -> KeepaliveConfig {
+        assert!(
+            interval > Duration::from_millis(0),
+            "interval must not be zero"
+        );
+        self.interval = interval;
+        self
+    }
+
+    /// Sets after how many intervals without hearing from the peer it is declared dead.
+    pub fn max_misses(mut self, misses: u32) -> KeepaliveConfig {
+        assert!(misses > 0, "max_misses must not be zero");
+        self.max_misses = misses;
+        self
+    }
+
+    /// Sets how much received data is buffered before the background reader stops reading.
+    pub fn recv_buffer(mut self, bytes: usize) -> KeepaliveConfig {
+        self.recv_buffer = std::cmp::max(bytes, 1);
+        self
+    }
+
+    /// Starts sending heartbeats on `stream`.
+    pub fn wrap(&self, stream: UdtStream) -> KeepaliveStream {
+        self.start(Arc::new(stream))
+    }
+
+    fn start(&self, link: Arc<dyn Transport>) -> KeepaliveStream {
+        let now = Instant::now();
+        let shared = Arc::new(Shared {
+            link,
+            config: self.clone(),
+            start: now,
+            writer: Mutex::new(()),
+            state: Mutex::new(State {
+                buf: VecDeque::new(),
+                eof: false,
+                broken: None,
+                dead: None,
+                closed: false,
+                last_recv: now,
+                last_send: now,
+                stats: KeepaliveStats::default(),
+            }),
+            cond: Condvar::new(),
+        });
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.read_loop())
+        };
+        let heartbeat = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.heartbeat_loop())
+        };
+        KeepaliveStream {
+            shared,
+            threads: vec![reader, heartbeat],
+        }
+    }
+}
+
+/// Heartbeat counters of a `KeepaliveStream`
+#[derive(Debug, Clone, Default)]
+pub struct KeepaliveStats {
+    /// `PING` frames sent
+    pub pings_sent: u64,
+    /// `PONG` frames received in reply
+    pub pongs_received: u64,
+    /// The most recent round trip time
+    pub last_rtt: Option<Duration>,
+    /// The smoothed round trip time, averaged like TCP's SRTT
+    pub rtt: Option<Duration>,
+}
+
+struct State {
+    buf: VecDeque<u8>,
+    eof: bool,
+    broken: Option<io::ErrorKind>,
+    dead: Option<PeerTimeout>,
+    closed: bool,
+    last_recv: Instant,
+    last_send: Instant,
+    stats: KeepaliveStats,
+}
+
+struct Shared {
+    link: Arc<dyn Transport>,
+    config: KeepaliveConfig,
+    // PING tokens are nanoseconds since this instant
+    start: Instant,
+    // keeps the frames of concurrent writers apart
+    writer: Mutex<()>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn check(&self, state: &State) -> io::Result<()> {
+        if let Some(dead) = state.dead {
+            return Err(dead.into());
+        }
+        match state.broken {
+            Some(kind) => Err(io::Error::new(kind, "keepalive connection failed")),
+            None => Ok(()),
+        }
+    }
+
+    // the writer lock must be held
+    fn write_frame(&self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        let res = self.link.write_all(&frame);
+        let mut state = self.lock();
+        state.last_send = Instant::now();
+        match res {
+            // a write fails because the heartbeat thread closed the connection
+            Err(_) if state.dead.is_some() => self.check(&state),
+            r => r,
+        }
+    }
+
+    // Heartbeats are only sent if no application data is being written.  If the write is stuck,
+    // the peer still hears from us once it gets through, and otherwise it isn't reading anyway.
+    fn try_write_frame(&self, kind: u8, payload: &[u8]) -> bool {
+        match self.writer.try_lock() {
+            Ok(_guard) => self.write_frame(kind, payload).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.link.read(&mut buf[done..]) {
+                Ok(0) if done == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_loop(&self) {
+        let res = self.read_frames();
+        let mut state = self.lock();
+        match res {
+            Ok(()) => state.eof = true,
+            Err(_) if state.closed || state.dead.is_some() => {}
+            Err(e) => {
+                debug!("keepalive connection failed: {}", e);
+                state.broken = Some(e.kind());
+            }
+        }
+        self.cond.notify_all();
+    }
+
+    // returns once the peer closed the connection
+    fn read_frames(&self) -> io::Result<()> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut payload = vec![0u8; MAX_DATA];
+        loop {
+            if !self.read_exact(&mut header)? {
+                return Ok(());
+            }
+            let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            let valid = match header[0] {
+                DATA => len <= MAX_DATA,
+                PING | PONG => len == 8,
+                _ => false,
+            };
+            if !valid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed keepalive frame",
+                ));
+            }
+            if !self.read_exact(&mut payload[..len])? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let now = Instant::now();
+            let mut state = self.lock();
+            state.last_recv = now;
+            match header[0] {
+                DATA => {
+                    state.buf.extend(&payload[..len]);
+                    self.cond.notify_all();
+                    while state.buf.len() >= self.config.recv_buffer && !state.closed {
+                        state = self.cond.wait(state).unwrap();
+                    }
+                    if state.closed {
+                        return Ok(());
+                    }
+                }
+                PING => {
+                    drop(state);
+                    self.try_write_frame(PONG, &payload[..8]);
+                }
+                _ => {
+                    let mut token = [0u8; 8];
+                    token.copy_from_slice(&payload[..8]);
+                    let sent = Duration::from_nanos(u64::from_be_bytes(token));
+                    let rtt = (now - self.start).checked_sub(sent).unwrap_or_default();
+                    let stats = &mut state.stats;
+                    stats.pongs_received += 1;
+                    stats.last_rtt = Some(rtt);
+                    stats.rtt = Some(match stats.rtt {
+                        Some(srtt) => (srtt * 7 + rtt) / 8,
+                        None => rtt,
+                    });
+                }
+            }
+        }
+    }
+
+    fn heartbeat_loop(&self) {
+        let interval = self.config.interval;
+        let tick = std::cmp::max(interval / 4, Duration::from_millis(1));
+        let mut state = self.lock();
+        loop {
+            state = self.cond.wait_timeout(state, tick).unwrap().0;
+            if state.closed || state.eof || state.broken.is_some() {
+                return;
+            }
+            let now = Instant::now();
+            if state.buf.len() >= self.config.recv_buffer {
+                // the reader is waiting for the application, so it can't hear from the peer
+                state.last_recv = now;
+            }
+            let silent_for = now - state.last_recv;
+            let misses = (silent_for.as_nanos() / interval.as_nanos()) as u32;
+            if misses >= self.config.max_misses {
+                let dead = PeerTimeout { silent_for, misses };
+                warn!("{}", dead);
+                state.dead = Some(dead);
+                self.cond.notify_all();
+                drop(state);
+                self.link.shutdown();
+                return;
+            }
+            if now - state.last_send >= interval {
+                drop(state);
+                let token = (Instant::now() - self.start).as_nanos() as u64;
+                if self.try_write_frame(PING, &token.to_be_bytes()) {
+                    self.lock().stats.pings_sent += 1;
+                }
+                state = self.lock();
+            }
+        }
+    }
+}
+
+/// A stream that sends heartbeats and detects a dead peer
+///
+/// Dropping the `KeepaliveStream` closes the connection.
+pub struct KeepaliveStream {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl fmt::Debug for KeepaliveStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeepaliveStream")
+            .field("config", &self.shared.config)
+            .finish()
+    }
+}
+
+impl KeepaliveStream {
+    /// Returns the smoothed heartbeat round trip time, once one has been measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.shared.lock().stats.rtt
+    }
+
+    /// Returns a snapshot of the heartbeat counters.
+    pub fn stats(&self) -> KeepaliveStats {
+        self.shared.lock().stats.clone()
+    }
+
+    /// Returns the error the peer was declared dead with, if it was.
+    pub fn peer_timeout(&self) -> Option<PeerTimeout> {
+        self.shared.lock().dead
+    }
+}
+
+impl Drop for KeepaliveStream {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.cond.notify_all();
+        self.shared.link.shutdown();
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Read for &KeepaliveStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        loop {
+            if !state.buf.is_empty() {
+                let n = std::cmp::min(buf.len(), state.buf.len());
+                for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+                    *dst = src;
+                }
+                shared.cond.notify_all();
+                return Ok(n);
+            }
+            shared.check(&state)?;
+            if state.eof || buf.is_empty() {
+                return Ok(0);
+            }
+            state = shared.cond.wait(state).unwrap();
+        }
+    }
+}
+
+impl Write for &KeepaliveStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let shared = &self.shared;
+        let _writer = shared.writer.lock().unwrap();
+        shared.check(&shared.lock())?;
+        let n = std::cmp::min(buf.len(), MAX_DATA);
+        shared.write_frame(DATA, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for KeepaliveStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for KeepaliveStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+fn pair(config: &KeepaliveConfig) -> (KeepaliveStream, KeepaliveStream) {
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    (config.start(Arc::new(a)), config.start(Arc::new(b)))
+}
+
+#[test]
+#[cfg(unix)]
+fn test_keepalive_rtt() {
+    let config = KeepaliveConfig::new().interval(Duration::from_millis(10));
+    let (mut a, mut b) = pair(&config);
+
+    let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let writer = thread::spawn(move || {
+        a.write_all(&data).unwrap();
+        a
+    });
+    let mut got = vec![0u8; expected.len()];
+    b.read_exact(&mut got).unwrap();
+    assert_eq!(got, expected);
+    let a = writer.join().unwrap();
+
+    // both sides are idle now, so heartbeats flow and measure the rtt
+    thread::sleep(Duration::from_millis(100));
+    for s in &[&a, &b] {
+        let stats = s.stats();
+        assert!(stats.pings_sent > 0 && stats.pongs_received > 0);
+        assert!(s.rtt().unwrap() < Duration::from_secs(1));
+        assert!(s.peer_timeout().is_none());
+    }
+
+    drop(a);
+    assert_eq!(b.read(&mut [0u8; 16]).unwrap(), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_keepalive_dead_peer() {
+    let (a, silent) = std::os::unix::net::UnixStream::pair().unwrap();
+    let config = KeepaliveConfig::new()
+        .interval(Duration::from_millis(10))
+        .max_misses(3);
+    let mut stream = config.start(Arc::new(a));
+
+    let started = Instant::now();
+    let err = stream.read(&mut [0u8; 16]).unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(PeerTimeout::is_peer_timeout(&err));
+    assert!(stream.peer_timeout().unwrap().misses >= 3);
+
+    let err = stream.write(b"hello").unwrap_err();
+    assert!(PeerTimeout::is_peer_timeout(&err));
+    drop(silent);
+}
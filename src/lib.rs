@@ -51,6 +51,8 @@ extern crate libc;
 
 use libc::c_int;
 use std::ffi::CStr;
+use std::fmt;
+use std::io;
 use std::mem::size_of;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
@@ -91,7 +93,38 @@ mod _plat_specifics {
 }
 use _plat_specifics::*;
 
+mod stream;
+
+pub mod admission;
+#[cfg(feature = "aead")]
+pub mod aead;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod backpressure;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod diagnostics;
+pub mod fragment;
+pub mod framed;
+pub mod keepalive;
+pub mod mtu;
+pub mod mux;
+pub mod pool;
+pub mod pubsub;
+pub mod resilient;
+pub mod rpc;
+pub mod server;
+mod snapshot;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tuning;
+#[cfg(any(feature = "bincode", feature = "cbor"))]
+pub mod typed;
+pub mod watch;
+
 pub use raw::UdtStatus;
+pub use snapshot::{OptionDiff, OptionSnapshot};
+pub use stream::{Incoming, ListenerCloser, ReadHalf, UdtListener, UdtStream, WriteHalf};
 
 bitflags! {
 /// This is a bitflag field that can be constructed with `UDT_EPOLL_IN`, `UDT_EPOLL_OUT`, or
@@ -120,10 +153,34 @@ bitflags! {
 macro_rules! impl_udt_opt {
     ($(#[$doc:meta])*
      impl $name:ident: $ty:ty) => {
-         $(#[$doc])*
+        impl_udt_opt!($(#[$doc])* impl $name: $ty as $ty, decode |v| v, encode |v| v);
+    };
+    ($(#[$doc:meta])*
+     impl $name:ident: $ty:ty, read only) => {
+        impl_udt_opt!($(#[$doc])* impl $name: $ty as $ty, decode |v| v);
+    };
+    ($(#[$doc:meta])*
+     impl $name:ident: $ty:ty as $raw:ty, decode $decode:expr, encode $encode:expr) => {
+        impl_udt_opt!($(#[$doc])* impl $name: $ty as $raw, decode $decode);
+        impl crate::SettableOption for $name {
+            fn encode(value: $ty) -> $raw {
+                let encode: fn($ty) -> $raw = $encode;
+                encode(value)
+            }
+        }
+    };
+    ($(#[$doc:meta])*
+     impl $name:ident: $ty:ty as $raw:ty, decode $decode:expr) => {
+        $(#[$doc])*
         pub struct $name;
-        impl crate::UdtOption<$ty> for $name {
+        impl crate::UdtOption for $name {
+            type Value = $ty;
+            type Raw = $raw;
             fn get_type(&self) -> ::raw::UDTOpt { ::raw::UDTOpt::$name }
+            fn decode(raw: $raw) -> $ty {
+                let decode: fn($raw) -> $ty = $decode;
+                decode(raw)
+            }
         }
     };
 }
@@ -168,12 +225,84 @@ pub struct UdtError {
     pub err_msg: String,
 }
 
-pub trait UdtOption<T> {
+/// Error code returned when an `accept` on a [`UdtListener`][1] was interrupted because the
+/// listener was closed (see [`ListenerCloser`][2])
+///
+/// UDT's own error codes are all below 8000, so codes generated by this crate start there.
+///
+/// [1]: struct.UdtListener.html
+/// [2]: struct.ListenerCloser.html
+pub const ELISTENERCLOSED: i32 = 8000;
+
+impl UdtError {
+    fn new(err_code: i32, err_msg: &str) -> UdtError {
+        UdtError {
+            err_code,
+            err_msg: err_msg.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for UdtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (UDT error {})", self.err_msg, self.err_code)
+    }
+}
+
+impl std::error::Error for UdtError {}
+
+impl From<UdtError> for io::Error {
+    fn from(e: UdtError) -> io::Error {
+        let kind = match e.err_code {
+            raw::ECONNREJ | raw::ENOSERVER => io::ErrorKind::ConnectionRefused,
+            raw::ECONNLOST => io::ErrorKind::ConnectionAborted,
+            raw::ENOCONN => io::ErrorKind::NotConnected,
+            raw::EASYNCSND | raw::EASYNCRCV => io::ErrorKind::WouldBlock,
+            raw::ETIMEOUT => io::ErrorKind::TimedOut,
+            raw::EINVPARAM => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// A socket option that can be read with `getsockopt`
+///
+/// Each option in [`UdtOpts`][1] converts between the C type UDT stores it as and a Rust type:
+/// for example `UDT_STATE` is read as a `UdtStatus` and `UDT_RCVTIMEO` as an
+/// `Option<Duration>`.
+///
+/// [1]: UdtOpts/index.html
+pub trait UdtOption {
+    /// The type the option is read and written as
+    type Value;
+    /// The C type UDT stores the option as
+    type Raw: Copy + Default;
+    /// Returns which option this is.
     fn get_type(&self) -> raw::UDTOpt;
+    /// Converts the value UDT returned.
+    fn decode(raw: Self::Raw) -> Self::Value;
+}
+
+/// A socket option that can also be changed with `setsockopt`
+///
+/// Read-only options like `UDT_STATE` don't implement this, so setting them doesn't compile:
+///
+/// ```compile_fail
+/// # use udt::*;
+/// let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+/// sock.setsockopt(UdtOpts::UDT_STATE, UdtStatus::CONNECTED).unwrap();
+/// ```
+pub trait SettableOption: UdtOption {
+    /// Converts a value to what UDT expects.
+    fn encode(value: Self::Value) -> Self::Raw;
 }
 
 #[repr(C)]
-/// Linger option
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Linger option, as UDT stores it
+///
+/// `UDT_LINGER` is read and written as an `Option<Duration>` instead.
 pub struct Linger {
     /// Nonzero to linger on close
     pub onoff: i32,
@@ -181,6 +310,55 @@ pub struct Linger {
     pub linger: i32,
 }
 
+fn linger_from_raw(l: Linger) -> Option<std::time::Duration> {
+    if l.onoff == 0 {
+        None
+    } else {
+        Some(std::time::Duration::from_secs(l.linger.max(0) as u64))
+    }
+}
+
+fn linger_to_raw(d: Option<std::time::Duration>) -> Linger {
+    match d {
+        None => Linger { onoff: 0, linger: 0 },
+        Some(d) => Linger {
+            onoff: 1,
+            // UDT only lingers in whole seconds
+            linger: std::cmp::min(
+                d.as_secs() + u64::from(d.subsec_nanos() > 0),
+                i32::MAX as u64,
+            ) as i32,
+        },
+    }
+}
+
+// UDT uses -1 for "wait forever"
+fn timeout_from_raw(ms: i32) -> Option<std::time::Duration> {
+    if ms < 0 {
+        None
+    } else {
+        Some(std::time::Duration::from_millis(ms as u64))
+    }
+}
+
+fn timeout_to_raw(d: Option<std::time::Duration>) -> i32 {
+    d.map_or(-1, |d| std::cmp::min(d.as_millis(), i32::MAX as u128) as i32)
+}
+
+fn status_from_raw(state: i32) -> UdtStatus {
+    match state {
+        1 => UdtStatus::INIT,
+        2 => UdtStatus::OPENED,
+        3 => UdtStatus::LISTENING,
+        4 => UdtStatus::CONNECTING,
+        5 => UdtStatus::CONNECTED,
+        6 => UdtStatus::BROKEN,
+        7 => UdtStatus::CLOSING,
+        8 => UdtStatus::CLOSED,
+        _ => UdtStatus::NONEXIST,
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 pub mod UdtOpts {
@@ -197,9 +375,15 @@ pub mod UdtOpts {
     //! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
     //! let recv_buf: i32 = sock.getsockopt(UdtOpts::UDT_RCVBUF).unwrap();
     //! let rendezvous: bool = sock.getsockopt(UdtOpts::UDT_RENDEZVOUS).unwrap();
+    //! let state: UdtStatus = sock.getsockopt(UdtOpts::UDT_STATE).unwrap();
+    //! sock.setsockopt(UdtOpts::UDT_RCVTIMEO, Some(std::time::Duration::from_secs(5))).unwrap();
     //!
     //! ```
     //!
+    //! Read-only options (`UDT_STATE`, `UDT_EVENT`, `UDT_SNDDATA` and `UDT_RCVDATA`) can only be
+    //! passed to `getsockopt`.
+
+    use std::time::Duration;
 
     impl_udt_opt! {
         /// Maximum Packet size (bytes)
@@ -253,22 +437,25 @@ pub mod UdtOpts {
         ///
         /// Default 1MB (1024000).
         impl UDP_RCVBUF: i32);
-    impl_udt_opt!(/// Linger time on close().
+    impl_udt_opt!(/// Linger time on close(), or `None` to not linger.
         ///
-        /// Default 180 seconds.
-        impl UDT_LINGER: crate::Linger);
+        /// Default 180 seconds.  UDT only lingers in whole seconds, so this is rounded up.
+        impl UDT_LINGER: Option<Duration> as crate::Linger,
+            decode crate::linger_from_raw, encode crate::linger_to_raw);
     impl_udt_opt!(/// Rendezvous connection setup.
         ///
         /// Default false (no rendezvous mode).
         impl UDT_RENDEZVOUS: bool);
-    impl_udt_opt!(/// Sending call timeout (milliseconds).
+    impl_udt_opt!(/// Sending call timeout, or `None` to wait forever.
         ///
-        /// Default -1 (infinite).
-        impl UDT_SNDTIMEO: i32);
-    impl_udt_opt!(/// Receiving call timeout (milliseconds).
+        /// Default `None`.  UDT stores this in milliseconds.
+        impl UDT_SNDTIMEO: Option<Duration> as i32,
+            decode crate::timeout_from_raw, encode crate::timeout_to_raw);
+    impl_udt_opt!(/// Receiving call timeout, or `None` to wait forever.
         ///
-        /// Default -1 (infinite).
-        impl UDT_RCVTIMEO: i32);
+        /// Default `None`.  UDT stores this in milliseconds.
+        impl UDT_RCVTIMEO: Option<Duration> as i32,
+            decode crate::timeout_from_raw, encode crate::timeout_to_raw);
     impl_udt_opt!(/// Reuse an existing address or create a new one.
         ///
         /// Default true (reuse).
@@ -278,13 +465,14 @@ pub mod UdtOpts {
         /// Default -1 (no upper limit).
         impl UDT_MAXBW: i64);
     impl_udt_opt!(/// Current status of the UDT socket. Read only.
-        impl UDT_STATE: i32);
+        impl UDT_STATE: crate::UdtStatus as i32, decode crate::status_from_raw);
     impl_udt_opt!(/// The EPOLL events available to this socket.    Read only.
-        impl UDT_EVENT: i32);
-    impl_udt_opt!(/// Size of pending data in the sending buffer.   Read only.
-        impl UDT_SNDDATA: i32);
-    impl_udt_opt!(/// Size of data available to read, in the receiving buffer.  Read only.
-        impl UDT_RCVDATA: i32);
+        impl UDT_EVENT: crate::EpollEvents as i32, decode crate::EpollEvents::from_bits_truncate);
+    impl_udt_opt!(/// Number of packets in the sending buffer, including those not acknowledged yet.
+        /// Read only.
+        impl UDT_SNDDATA: i32, read only);
+    impl_udt_opt!(/// Size of data available to read, in the receiving buffer (bytes).  Read only.
+        impl UDT_RCVDATA: i32, read only);
 
 }
 
@@ -589,11 +777,18 @@ impl UdtSocket {
         let mut peer = unsafe { std::mem::zeroed() };
         let mut size: i32 = size_of::<sockaddr>() as i32;
         let ret = unsafe { raw::udt_accept(self._sock, &mut peer, &mut size) };
-        assert_eq!(size, size_of::<sockaddr>() as i32);
         if ret == raw::INVALID_SOCK {
             Err(get_last_err())
         } else {
             let new_sock = UdtSocket::wrap_raw(ret);
+            if size as usize != size_of::<sockaddr>() {
+                // we only understand sockaddr_in peers so far, so don't leak the new socket
+                let _ = new_sock.close();
+                return Err(UdtError::new(
+                    raw::EINVPARAM,
+                    "unsupported peer address size returned by accept",
+                ));
+            }
             let addr = sockaddr_to_socketaddr(peer);
             Ok((new_sock, addr))
         }
@@ -626,6 +821,87 @@ impl UdtSocket {
         }
     }
 
+    /// Waits for the sending buffer to drain, then closes the socket
+    ///
+    /// Unlike `close` with `UDT_LINGER`, this waits at most `timeout` no matter how the socket
+    /// is configured, and reports what was lost.  Returns `None` if the buffer emptied, or
+    /// `Some` with the number of bytes still queued when the timeout expired or the connection
+    /// broke, which are discarded.  Like `pending_send_bytes`, this is rounded up to whole
+    /// packets, and it is 0 if the queue could not be read any more.
+    ///
+    /// Data in the buffer has not necessarily been acknowledged by the peer's application, only
+    /// by its UDT receive buffer.
+    pub fn flush_and_close(
+        self,
+        timeout: std::time::Duration,
+    ) -> Result<Option<usize>, UdtError> {
+        let abandoned = match self.wait_send_buffer_below(1, timeout) {
+            Ok(()) => None,
+            Err(_) => Some(self.pending_send_bytes().unwrap_or(0)),
+        };
+        if let Some(bytes) = abandoned {
+            trace!("abandoning {} bytes on {:?}", bytes, self);
+            // don't let close() linger over what we're giving up on
+            let _ = self.setsockopt(UdtOpts::UDT_LINGER, None);
+        }
+        self.close()?;
+        Ok(abandoned)
+    }
+
+    /// Returns how much data is waiting in the sending buffer
+    ///
+    /// This includes data that was sent but not acknowledged yet.  UDT only counts whole packets
+    /// (`UDT_SNDDATA`), so this is the number of packets times the packet payload size, and can
+    /// overestimate by up to one packet.
+    pub fn pending_send_bytes(&self) -> Result<usize, UdtError> {
+        let packets = self.getsockopt(UdtOpts::UDT_SNDDATA)?.max(0) as usize;
+        if packets == 0 {
+            return Ok(0);
+        }
+        // each packet carries the MSS minus the IP, UDP and UDT headers
+        let payload = self.getsockopt(UdtOpts::UDT_MSS)? - 44;
+        Ok(packets * payload.max(1) as usize)
+    }
+
+    /// Returns the number of bytes that can be read without blocking (`UDT_RCVDATA`)
+    pub fn available_bytes(&self) -> Result<usize, UdtError> {
+        Ok(self.getsockopt(UdtOpts::UDT_RCVDATA)?.max(0) as usize)
+    }
+
+    /// Blocks until `pending_send_bytes` is below `threshold`
+    ///
+    /// Fails with `ETIMEOUT` if that doesn't happen within `timeout`, and with `ECONNLOST` if
+    /// the connection breaks first.
+    pub fn wait_send_buffer_below(
+        &self,
+        threshold: usize,
+        timeout: std::time::Duration,
+    ) -> Result<(), UdtError> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if self.pending_send_bytes()? < threshold {
+                return Ok(());
+            }
+            if self.getstate() != UdtStatus::CONNECTED {
+                return Err(UdtError::new(
+                    raw::ECONNLOST,
+                    "connection lost with data still queued",
+                ));
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Err(UdtError::new(
+                    raw::ETIMEOUT,
+                    "timed out waiting for the send buffer to drain",
+                ));
+            }
+            std::thread::sleep(std::cmp::min(
+                deadline - now,
+                std::time::Duration::from_millis(5),
+            ));
+        }
+    }
+
     /// Retrieves the address information of the peer side of a connected UDT socket
     ///
     /// The getpeername retrieves the address of the peer side associated to the connection. The
@@ -714,13 +990,34 @@ impl UdtSocket {
     /// be retrieved by getlasterror. If UDT_SNDTIMEO is set to a positive value, zero will be
     /// returned if the message cannot be sent before the timer expires.
     pub fn sendmsg(&self, buf: &[u8]) -> Result<i32, UdtError> {
+        self.sendmsg_ttl(buf, None, true)
+    }
+
+    /// Sends a message with a limited life time.
+    ///
+    /// This is the same as [`sendmsg`](#method.sendmsg), but also takes the `ttl` and `inorder`
+    /// parameters described there.  `sendmsg` uses no TTL (`None`) and in-order delivery.
+    ///
+    /// A message whose TTL expires is silently discarded by the sender; the receiver never sees
+    /// it, and the next message it receives is simply a later one.  The TTL has millisecond
+    /// resolution.
+    pub fn sendmsg_ttl(
+        &self,
+        buf: &[u8],
+        ttl: Option<std::time::Duration>,
+        inorder: bool,
+    ) -> Result<i32, UdtError> {
+        let ttl: c_int = match ttl {
+            Some(d) => std::cmp::min(d.as_millis(), c_int::MAX as u128) as c_int,
+            None => -1,
+        };
         let ret = unsafe {
             raw::udt_sendmsg(
                 self._sock,
                 buf.as_ptr(),
                 buf.len() as i32,
-                -1 as i32,
-                1 as i32,
+                ttl,
+                inorder as c_int,
             )
         };
         if ret == raw::UDT_ERROR {
@@ -830,17 +1127,17 @@ impl UdtSocket {
     /// let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
     /// let recv_buf: i32 = sock.getsockopt(UdtOpts::UDP_RCVBUF).unwrap();
     /// ```
-    pub fn getsockopt<B: Default, T: UdtOption<B>>(&self, opt: T) -> Result<B, UdtError> {
-        let mut val: B = unsafe { std::mem::zeroed() };
-        let val_p: *mut B = &mut val;
+    pub fn getsockopt<T: UdtOption>(&self, opt: T) -> Result<T::Value, UdtError> {
+        let mut val = T::Raw::default();
+        let val_p: *mut T::Raw = &mut val;
         let ty: raw::UDTOpt = opt.get_type();
-        let mut size: c_int = size_of::<B>() as i32;
+        let mut size: c_int = size_of::<T::Raw>() as i32;
         let ret = unsafe {
             raw::udt_getsockopt(self._sock, 0, ty, val_p as *mut libc::c_void, &mut size)
         };
 
         if ret == raw::SUCCESS {
-            Ok(val)
+            Ok(T::decode(val))
         } else {
             Err(get_last_err())
         }
@@ -851,10 +1148,11 @@ impl UdtSocket {
     /// See the [`UdtOpts`][1] module for all the supported option types.
 
     /// [1]: UdtOpts/index.html
-    pub fn setsockopt<B, T: UdtOption<B>>(&self, opt: T, value: B) -> Result<(), UdtError> {
+    pub fn setsockopt<T: SettableOption>(&self, opt: T, value: T::Value) -> Result<(), UdtError> {
         let ty: raw::UDTOpt = opt.get_type();
-        let val_p: *const B = &value;
-        let size: c_int = size_of::<B>() as i32;
+        let value = T::encode(value);
+        let val_p: *const T::Raw = &value;
+        let size: c_int = size_of::<T::Raw>() as i32;
 
         let ret =
             unsafe { raw::udt_setsockopt(self._sock, 0, ty, val_p as *const libc::c_void, size) };
@@ -870,6 +1168,35 @@ impl UdtSocket {
         unsafe { raw::udt_getsockstate(self._sock) }
     }
 
+    /// Waits until the socket is in one of `states`, and returns that state
+    ///
+    /// The state is polled every few milliseconds, so a state that only lasts a moment can be
+    /// missed.  Fails with `ETIMEOUT` if none of `states` is reached within `timeout`.
+    pub fn wait_for_state(
+        &self,
+        states: &[UdtStatus],
+        timeout: std::time::Duration,
+    ) -> Result<UdtStatus, UdtError> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let state = self.getstate();
+            if states.contains(&state) {
+                return Ok(state);
+            }
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                return Err(UdtError::new(
+                    raw::ETIMEOUT,
+                    "timed out waiting for a socket state",
+                ));
+            }
+            std::thread::sleep(std::cmp::min(
+                deadline - now,
+                std::time::Duration::from_millis(5),
+            ));
+        }
+    }
+
     /// The perfmon method retrieves the internal protocol parameters and performance trace.
     ///
     /// The perfmon method reads the performance data since the last time perfmon is executed, or since the connection is started.
@@ -978,6 +1305,25 @@ impl Epoll {
         }
     }
 
+    // Adds back a socket that was taken out with `remove_usock`, without reserving more room for
+    // results.  UDT only ever adds events, so changing them also means removing the socket first.
+    pub(crate) fn readd_usock(&self, socket: &UdtSocket, events: EpollEvents) -> Result<(), UdtError> {
+        let b: c_int = events.bits();
+        let ret = unsafe { raw::udt_epoll_add_usock(self.eid, socket._sock, &b) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(get_last_err())
+        }
+    }
+
+    // Removes a socket for good, giving back the room `add_usock` reserved for it
+    pub(crate) fn release_usock(&mut self, socket: &UdtSocket) -> Result<(), UdtError> {
+        self.rd_vec.pop();
+        self.wr_vec.pop();
+        self.remove_usock(socket)
+    }
+
     /// Wait for events
     ///
     /// Timeout is in milliseconds.  If negative, wait forever.  If zero, return immediately.
@@ -1054,6 +1400,14 @@ impl Epoll {
     }
 }
 
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            raw::udt_epoll_release(self.eid);
+        }
+    }
+}
+
 #[test]
 fn test_udt_socket() {
     init();
@@ -1081,7 +1435,6 @@ fn test_udt_bind() {
 fn test_udt_socket_state() {
     use std::net::Ipv4Addr;
     use std::str::FromStr;
-    use std::thread::sleep;
     use std::time::Duration;
 
     init();
@@ -1102,8 +1455,37 @@ fn test_udt_socket_state() {
     assert_eq!(sock.getstate(), UdtStatus::LISTENING);
     sock.close().unwrap();
     assert_eq!(sock.getstate(), UdtStatus::BROKEN);
-    sleep(Duration::from_millis(4500));
     // after some time, the sock transitions to CLOSED and then NONEXIST
     // THe LISTENING -> CLOSED transition is made after a 3 second timeout
-    assert!(sock.getstate() == UdtStatus::NONEXIST || sock.getstate() == UdtStatus::CLOSED);
+    sock.wait_for_state(
+        &[UdtStatus::CLOSED, UdtStatus::NONEXIST],
+        Duration::from_secs(10),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_option_conversions() {
+    use std::time::Duration;
+
+    assert_eq!(UdtOpts::UDT_RCVTIMEO::decode(-1), None);
+    assert_eq!(
+        UdtOpts::UDT_RCVTIMEO::decode(250),
+        Some(Duration::from_millis(250))
+    );
+    assert_eq!(UdtOpts::UDT_SNDTIMEO::encode(None), -1);
+    assert_eq!(
+        UdtOpts::UDT_SNDTIMEO::encode(Some(Duration::from_secs(1 << 40))),
+        i32::MAX
+    );
+
+    assert_eq!(UdtOpts::UDT_LINGER::decode(Linger { onoff: 0, linger: 180 }), None);
+    assert_eq!(
+        UdtOpts::UDT_LINGER::encode(Some(Duration::from_millis(1500))),
+        Linger { onoff: 1, linger: 2 }
+    );
+
+    assert_eq!(UdtOpts::UDT_STATE::decode(5), UdtStatus::CONNECTED);
+    assert_eq!(UdtOpts::UDT_STATE::decode(42), UdtStatus::NONEXIST);
+    assert_eq!(UdtOpts::UDT_EVENT::decode(0x5), UDT_EPOLL_IN | UDT_EPOLL_OUT);
 }
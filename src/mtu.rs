@@ -0,0 +1,588 @@
+//! Path MTU probing to choose `UDT_MSS`
+//!
+//! UDT sends every data packet at the full `UDT_MSS`, 1500 bytes by default.  On a tunneled
+//! link with a smaller MTU those packets are fragmented or dropped, and on a jumbo-frame link
+//! most of the capacity per packet goes unused.  Before the UDT connection is set up, an
+//! `MtuConfig` exchanges probe datagrams of decreasing sizes with the peer over the plain
+//! `std::net::UdpSocket` that UDT will later take over with
+//! [`bind_from`](../struct.UdtSocket.html#method.bind_from).  The largest probe the peer
+//! acknowledges becomes the MSS, and is sent to the peer so both sides set `UDT_MSS` to it.
+//!
+//! A probe for an MSS carries the same UDP payload as a UDT packet of that MSS (`mss - 28`
+//! bytes).  On Linux, probes are sent with the don't-fragment bit set, so oversized probes are
+//! dropped instead of being fragmented; the socket's previous setting is restored afterwards.
+//! Elsewhere an oversized probe may be fragmented and still get through, so candidate sizes
+//! should not exceed the local interface MTU.
+//!
+//! # Wire format
+//!
+//! Every message starts with an 11-byte header:
+//!
+//! ```text
+//! magic "UMTU" (4) | kind (1) | mss: u16 BE (2) | nonce: u32 BE (4)
+//! ```
+//!
+//! The kinds are probe (1), probe acknowledgement (2), chosen MSS (3) and its acknowledgement
+//! (4).  Probes are padded with zeros to `mss - 28` bytes; the other messages are just the
+//! header, so acknowledgements get back even when the reverse path has a smaller MTU.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::net::UdpSocket;
+//! use udt::*;
+//! use udt::mtu::MtuConfig;
+//!
+//! init();
+//! // server
+//! let udp = UdpSocket::bind("0.0.0.0:9000").unwrap();
+//! let (listener, path) = MtuConfig::new().listen(udp, 16).unwrap();
+//! println!("{} uses an MSS of {}", path.peer, path.mss);
+//! let stream = listener.accept().unwrap();
+//!
+//! // client
+//! let udp = UdpSocket::bind("0.0.0.0:0").unwrap();
+//! let (stream, path) = MtuConfig::new()
+//!     .connect(udp, "192.0.2.10:9000".parse().unwrap())
+//!     .unwrap();
+//! ```
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{SocketFamily, SocketType, UdtError, UdtListener, UdtOpts, UdtSocket, UdtStream};
+
+const MAGIC: &[u8; 4] = b"UMTU";
+const HEADER_LEN: usize = 11;
+
+const KIND_PROBE: u8 = 1;
+const KIND_PROBE_ACK: u8 = 2;
+const KIND_DONE: u8 = 3;
+const KIND_DONE_ACK: u8 = 4;
+
+// IPv4 and UDP headers, which UDT subtracts from the MSS whatever the address family
+const UDP_OVERHEAD: usize = 28;
+// the smallest MSS UDT accepts
+const MIN_MSS: i32 = 76;
+
+/// Errors from probing the path MTU
+#[derive(Debug)]
+pub enum MtuError {
+    /// The peer acknowledged none of the candidate sizes, or no probe arrived in time
+    NoResponse,
+    /// The UDP socket failed
+    Io(io::Error),
+    /// Creating or configuring the UDT socket failed
+    Udt(UdtError),
+}
+
+impl fmt::Display for MtuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MtuError::NoResponse => write!(f, "no response to MTU probes"),
+            MtuError::Io(ref e) => write!(f, "MTU probe I/O error: {}", e),
+            MtuError::Udt(ref e) => write!(f, "UDT error after MTU probe: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MtuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            MtuError::Io(ref e) => Some(e),
+            MtuError::Udt(ref e) => Some(e),
+            MtuError::NoResponse => None,
+        }
+    }
+}
+
+impl From<io::Error> for MtuError {
+    fn from(e: io::Error) -> MtuError {
+        MtuError::Io(e)
+    }
+}
+
+impl From<UdtError> for MtuError {
+    fn from(e: UdtError) -> MtuError {
+        MtuError::Udt(e)
+    }
+}
+
+/// The outcome of a probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathMtu {
+    /// The address of the other side
+    pub peer: SocketAddr,
+    /// The MSS both sides agreed on
+    pub mss: i32,
+}
+
+/// Settings for probing the path MTU
+#[derive(Debug, Clone)]
+pub struct MtuConfig {
+    candidates: Vec<i32>,
+    probe_timeout: Duration,
+    attempts: u32,
+    wait: Duration,
+}
+
+impl Default for MtuConfig {
+    fn default() -> MtuConfig {
+        MtuConfig::new()
+    }
+}
+
+impl MtuConfig {
+    /// Creates the default configuration: jumbo frames, Ethernet, PPPoE, common tunnel sizes
+    /// and the IPv6 minimum are tried in turn, each probe gets three tries of 200ms, and the
+    /// responding side waits up to 30 seconds for the prober.
+    pub fn new() -> MtuConfig {
+        MtuConfig {
+            candidates: vec![9000, 4352, 1500, 1492, 1460, 1420, 1400, 1360, 1280],
+            probe_timeout: Duration::from_millis(200),
+            attempts: 3,
+            wait: Duration::from_secs(30),
+        }
+    }
+
+    /// Sets the MSS values to try.  They are tried from largest to smallest; values below
+    /// UDT's minimum of 76 or above 65535 are ignored.
+    pub fn candidates(mut self, sizes: &[i32]) -> MtuConfig {
+        let mut sizes: Vec<i32> = sizes
+            .iter()
+            .cloned()
+            .filter(|&s| (MIN_MSS..=65535).contains(&s))
+            .collect();
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        sizes.dedup();
+        self.candidates = sizes;
+        self
+    }
+
+    /// Sets how long to wait for each probe to be acknowledged.
+    pub fn probe_timeout(mut self, timeout: Duration) -> MtuConfig {
+        self.probe_timeout = std::cmp::max(timeout, Duration::from_millis(1));
+        self
+    }
+
+    /// Sets how many times a probe is sent before its size is given up on.
+    pub fn attempts(mut self, attempts: u32) -> MtuConfig {
+        self.attempts = std::cmp::max(attempts, 1);
+        self
+    }
+
+    /// Sets how long the responding side waits for the prober to finish.
+    pub fn wait(mut self, timeout: Duration) -> MtuConfig {
+        self.wait = timeout;
+        self
+    }
+
+    /// Probes the path to `peer`, which must be running [`respond`](#method.respond), and
+    /// tells it the chosen MSS.
+    ///
+    /// Fails with `NoResponse` if no candidate size is acknowledged.
+    pub fn probe(&self, udp: &UdpSocket, peer: SocketAddr) -> Result<PathMtu, MtuError> {
+        let _df = DontFragment::set(udp)?;
+        let old_timeout = udp.read_timeout()?;
+        let result = self.probe_sizes(udp, peer);
+        udp.set_read_timeout(old_timeout)?;
+        result
+    }
+
+    fn probe_sizes(&self, udp: &UdpSocket, peer: SocketAddr) -> Result<PathMtu, MtuError> {
+        let nonce = nonce();
+        let mut chosen = None;
+        for &mss in &self.candidates {
+            if self.exchange(udp, peer, KIND_PROBE, mss, nonce)? {
+                chosen = Some(mss);
+                break;
+            }
+            debug!("no acknowledgement for an MSS of {} to {}", mss, peer);
+        }
+        let mss = chosen.ok_or(MtuError::NoResponse)?;
+        // if only the acknowledgement is lost, the peer has already moved on; connecting will
+        // fail if it has not
+        if !self.exchange(udp, peer, KIND_DONE, mss, nonce)? {
+            warn!("{} did not acknowledge the chosen MSS of {}", peer, mss);
+        }
+        debug!("chose an MSS of {} for {}", mss, peer);
+        Ok(PathMtu { peer, mss })
+    }
+
+    // Sends a message until the matching acknowledgement arrives.  Returns false if it never
+    // does, or if the message is too large to send at all.
+    fn exchange(
+        &self,
+        udp: &UdpSocket,
+        peer: SocketAddr,
+        kind: u8,
+        mss: i32,
+        nonce: u32,
+    ) -> Result<bool, MtuError> {
+        let len = if kind == KIND_PROBE {
+            mss as usize - UDP_OVERHEAD
+        } else {
+            HEADER_LEN
+        };
+        let msg = encode(kind, mss as u16, nonce, len);
+        let mut buf = [0u8; 64];
+        for _ in 0..self.attempts {
+            match udp.send_to(&msg, peer) {
+                Ok(_) => {}
+                Err(ref e) if is_too_big(e) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            let deadline = Instant::now() + self.probe_timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                udp.set_read_timeout(Some(deadline - now))?;
+                match udp.recv_from(&mut buf) {
+                    Ok((n, from)) => {
+                        if from == peer && decode(&buf[..n]) == Some((kind + 1, mss as u16, nonce))
+                        {
+                            return Ok(true);
+                        }
+                    }
+                    Err(ref e) if is_lost(e) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Answers probes from a peer running [`probe`](#method.probe) until it sends the chosen
+    /// MSS.
+    ///
+    /// Probes from any address are acknowledged.  Fails with `NoResponse` if the prober does
+    /// not finish within the [`wait`](#method.wait) time.
+    pub fn respond(&self, udp: &UdpSocket) -> Result<PathMtu, MtuError> {
+        let old_timeout = udp.read_timeout()?;
+        let result = self.answer(udp);
+        udp.set_read_timeout(old_timeout)?;
+        result
+    }
+
+    fn answer(&self, udp: &UdpSocket) -> Result<PathMtu, MtuError> {
+        let deadline = Instant::now() + self.wait;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(MtuError::NoResponse);
+            }
+            udp.set_read_timeout(Some(deadline - now))?;
+            let (n, from) = match udp.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if is_lost(e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let msg = decode(&buf[..n]).filter(|&(_, mss, _)| i32::from(mss) >= MIN_MSS);
+            match msg {
+                Some((KIND_PROBE, mss, nonce))
+                    if (mss as usize).checked_sub(UDP_OVERHEAD) == Some(n) =>
+                {
+                    trace!("acknowledging an MSS of {} from {}", mss, from);
+                    udp.send_to(&encode(KIND_PROBE_ACK, mss, nonce, HEADER_LEN), from)?;
+                }
+                Some((KIND_DONE, mss, nonce)) => {
+                    udp.send_to(&encode(KIND_DONE_ACK, mss, nonce, HEADER_LEN), from)?;
+                    debug!("{} chose an MSS of {}", from, mss);
+                    return Ok(PathMtu {
+                        peer: from,
+                        mss: i32::from(mss),
+                    });
+                }
+                _ => trace!("ignoring a {} byte datagram from {}", n, from),
+            }
+        }
+    }
+
+    /// Probes the path to `peer`, then connects a `Stream` socket to it over `udp` with
+    /// `UDT_MSS` set to the chosen size.
+    ///
+    /// The peer must be running [`listen`](#method.listen).  `init()` must have been called
+    /// first.
+    pub fn connect(
+        &self,
+        udp: UdpSocket,
+        peer: SocketAddr,
+    ) -> Result<(UdtStream, PathMtu), MtuError> {
+        let path = self.probe(&udp, peer)?;
+        let sock = socket_with_mss(&peer, path.mss, udp)?;
+        if let Err(e) = sock.connect(peer) {
+            let _ = sock.close();
+            return Err(e.into());
+        }
+        Ok((UdtStream::from_socket(sock), path))
+    }
+
+    /// Answers one peer's probes, then listens for `Stream` connections over `udp` with
+    /// `UDT_MSS` set to the chosen size.
+    ///
+    /// UDT uses the smaller of the two sides' MSS for a connection, so other peers connecting
+    /// to the listener are limited to the same size.  `init()` must have been called first.
+    pub fn listen(&self, udp: UdpSocket, backlog: i32) -> Result<(UdtListener, PathMtu), MtuError> {
+        let path = self.respond(&udp)?;
+        let sock = socket_with_mss(&udp.local_addr()?, path.mss, udp)?;
+        if let Err(e) = sock.listen(backlog) {
+            let _ = sock.close();
+            return Err(e.into());
+        }
+        Ok((UdtListener::from_socket(sock), path))
+    }
+}
+
+// Creates a `Stream` socket with the given MSS, bound to `udp`.
+fn socket_with_mss(addr: &SocketAddr, mss: i32, udp: UdpSocket) -> Result<UdtSocket, UdtError> {
+    let family = match *addr {
+        SocketAddr::V4(..) => SocketFamily::AFInet,
+        SocketAddr::V6(..) => SocketFamily::AFInet6,
+    };
+    let sock = UdtSocket::new(family, SocketType::Stream)?;
+    // the MSS can only be set before the socket is bound
+    if let Err(e) = sock
+        .setsockopt(UdtOpts::UDT_MSS, mss)
+        .and_then(|_| sock.bind_from(udp))
+    {
+        let _ = sock.close();
+        return Err(e);
+    }
+    Ok(sock)
+}
+
+fn encode(kind: u8, mss: u16, nonce: u32, len: usize) -> Vec<u8> {
+    let mut msg = vec![0u8; std::cmp::max(len, HEADER_LEN)];
+    msg[..4].copy_from_slice(MAGIC);
+    msg[4] = kind;
+    msg[5..7].copy_from_slice(&mss.to_be_bytes());
+    msg[7..11].copy_from_slice(&nonce.to_be_bytes());
+    msg
+}
+
+fn decode(msg: &[u8]) -> Option<(u8, u16, u32)> {
+    if msg.len() < HEADER_LEN || &msg[..4] != MAGIC {
+        return None;
+    }
+    let mss = u16::from_be_bytes([msg[5], msg[6]]);
+    let nonce = u32::from_be_bytes([msg[7], msg[8], msg[9], msg[10]]);
+    Some((msg[4], mss, nonce))
+}
+
+// identifies one run of the prober, so late acknowledgements from an earlier run are ignored
+fn nonce() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0);
+    nanos ^ std::process::id().rotate_left(16)
+}
+
+fn is_too_big(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+// errors that just mean nothing useful arrived
+fn is_lost(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset => true,
+        _ => is_too_big(e),
+    }
+}
+
+// Sets the don't-fragment bit on a UDP socket and restores the previous setting when dropped.
+#[cfg(target_os = "linux")]
+struct DontFragment<'a> {
+    udp: &'a UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    old: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> DontFragment<'a> {
+    fn set(udp: &'a UdpSocket) -> io::Result<DontFragment<'a>> {
+        use std::os::unix::io::AsRawFd;
+
+        let (level, name, value) = match udp.local_addr()? {
+            SocketAddr::V4(..) => (
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_DO,
+            ),
+            SocketAddr::V6(..) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            ),
+        };
+        let fd = udp.as_raw_fd();
+        let mut old: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret =
+            unsafe { libc::getsockopt(fd, level, name, &mut old as *mut _ as *mut _, &mut len) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        set_int(fd, level, name, value)?;
+        Ok(DontFragment {
+            udp,
+            level,
+            name,
+            old,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> Drop for DontFragment<'a> {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        let _ = set_int(self.udp.as_raw_fd(), self.level, self.name, self.old);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_int(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const _,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct DontFragment;
+
+#[cfg(not(target_os = "linux"))]
+impl DontFragment {
+    fn set(_udp: &UdpSocket) -> io::Result<DontFragment> {
+        Ok(DontFragment)
+    }
+}
+
+// Forwards datagrams between a client and `server`, dropping those longer than `limit` bytes
+// on the way to the server, like a link with a small MTU.
+#[cfg(test)]
+fn narrow_link(server: SocketAddr, limit: usize) -> SocketAddr {
+    let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = relay.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        let mut client = None;
+        while let Ok((n, from)) = relay.recv_from(&mut buf) {
+            if from == server {
+                if let Some(client) = client {
+                    let _ = relay.send_to(&buf[..n], client);
+                }
+            } else {
+                client = Some(from);
+                if n <= limit {
+                    let _ = relay.send_to(&buf[..n], server);
+                }
+            }
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_mtu_picks_largest_working_size() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let relay = narrow_link(server.local_addr().unwrap(), 1400 - UDP_OVERHEAD);
+    let responder = std::thread::spawn(move || {
+        MtuConfig::new()
+            .wait(Duration::from_secs(10))
+            .respond(&server)
+            .unwrap()
+    });
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let path = MtuConfig::new()
+        .probe_timeout(Duration::from_millis(50))
+        .probe(&client, relay)
+        .unwrap();
+    assert_eq!(
+        path,
+        PathMtu {
+            peer: relay,
+            mss: 1400
+        }
+    );
+    assert_eq!(responder.join().unwrap().mss, 1400);
+    // the socket's own timeout is restored
+    assert_eq!(client.read_timeout().unwrap(), None);
+}
+
+#[test]
+fn test_mtu_no_response() {
+    // nothing answers on a bound but idle socket
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = MtuConfig::new()
+        .candidates(&[1280, 50, 1500, 1280])
+        .probe_timeout(Duration::from_millis(10))
+        .attempts(2);
+    assert_eq!(config.candidates, vec![1500, 1280]);
+    let err = config
+        .probe(&client, silent.local_addr().unwrap())
+        .unwrap_err();
+    assert!(matches!(err, MtuError::NoResponse));
+
+    let err = MtuConfig::new()
+        .wait(Duration::from_millis(20))
+        .respond(&silent)
+        .unwrap_err();
+    assert!(matches!(err, MtuError::NoResponse));
+}
+
+#[test]
+fn test_mtu_ignores_bad_sizes() {
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    // sizes below UDT's minimum, including ones smaller than the UDP overhead
+    client
+        .send_to(&encode(KIND_PROBE, 20, 1, HEADER_LEN), addr)
+        .unwrap();
+    client
+        .send_to(&encode(KIND_DONE, 40, 1, HEADER_LEN), addr)
+        .unwrap();
+
+    let responder = std::thread::spawn(move || {
+        MtuConfig::new()
+            .wait(Duration::from_secs(10))
+            .respond(&server)
+            .unwrap()
+    });
+    let path = MtuConfig::new()
+        .candidates(&[1280])
+        .probe(&client, addr)
+        .unwrap();
+    assert_eq!(path.mss, 1280);
+    assert_eq!(responder.join().unwrap().mss, 1280);
+}
@@ -0,0 +1,721 @@
+//! Multiplexed logical channels over a single `UdtStream`
+//!
+//! Every UDT connection has its own handshake, send and receive buffers and congestion control
+//! state, so opening one per logical flow is expensive.  A `Multiplexer` instead carries any
+//! number of numbered `Channel`s over one connection.  Each channel is a byte stream with its
+//! own `Read` and `Write`, and can be closed or reset independently of the others.
+//!
+//! Flow control is credit based: the receiving side of a channel grants the sender a window of
+//! bytes, and only hands out more credit as the application reads.  A consumer that stops
+//! reading therefore only stalls its own channel, never the connection.
+//!
+//! Both sides must create their `Multiplexer` with opposite roles (`client` and `server`), which
+//! decides how channel ids are allocated.  Either side may open channels.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::{Read, Write};
+//! use udt::*;
+//! use udt::mux::MuxConfig;
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let mux = MuxConfig::new().client(stream);
+//!
+//! let mut control = mux.open().unwrap();
+//! let mut bulk = mux.open().unwrap();
+//! control.write_all(b"STATUS\n").unwrap();
+//! bulk.write_all(&[0u8; 1 << 20]).unwrap();
+//! bulk.close();
+//!
+//! let mut reply = String::new();
+//! control.read_to_string(&mut reply).unwrap();
+//! ```
+//!
+//! # Wire format
+//!
+//! Every frame is a type byte, the channel id and the payload length (both 32-bit big-endian),
+//! and the payload:
+//!
+//! * `OPEN`: the sender created a channel; no payload
+//! * `DATA`: channel data, never more than the receiver's remaining credit
+//! * `WINDOW`: grants the peer more credit; the payload is a 32-bit byte count
+//! * `CLOSE`: the sender won't write to the channel any more
+//! * `RESET`: the sender abandoned the channel; any unread data is discarded
+//!
+//! Channels opened by the client have odd ids, the ones opened by the server even ids.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+
+use crate::stream::Transport;
+use crate::UdtStream;
+
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const WINDOW: u8 = 2;
+const CLOSE: u8 = 3;
+const RESET: u8 = 4;
+const HEADER_LEN: usize = 9;
+
+/// Settings for a `Multiplexer`
+#[derive(Debug, Clone)]
+pub struct MuxConfig {
+    window: u32,
+    max_frame: u32,
+}
+
+impl Default for MuxConfig {
+    fn default() -> MuxConfig {
+        MuxConfig::new()
+    }
+}
+
+impl MuxConfig {
+    /// Creates the default configuration: a 256 KiB window per channel and 16 KiB frames.
+    pub fn new() -> MuxConfig {
+        MuxConfig {
+            window: 256 * 1024,
+            max_frame: 16 * 1024,
+        }
+    }
+
+    /// Sets how many unread bytes each channel may buffer on the receiving side.
+    ///
+    /// This is the amount of credit granted to the peer, so it also limits the throughput of a
+    /// single channel to about one window per round trip.
+    pub fn window(mut self, bytes: u32) -> MuxConfig {
+        assert!(bytes > 0, "window must not be empty");
+        self.window = bytes;
+        self
+    }
+
+    /// Sets the largest `DATA` frame this side sends.
+    ///
+    /// Smaller frames let busy channels interleave more finely, at the cost of more headers.
+    pub fn max_frame(mut self, bytes: u32) -> MuxConfig {
+        assert!(bytes > 0, "frames must not be empty");
+        self.max_frame = bytes;
+        self
+    }
+
+    /// Starts multiplexing as the client side of the connection.
+    pub fn client(&self, stream: UdtStream) -> Multiplexer {
+        self.start(Arc::new(stream), true)
+    }
+
+    /// Starts multiplexing as the server side of the connection.
+    pub fn server(&self, stream: UdtStream) -> Multiplexer {
+        self.start(Arc::new(stream), false)
+    }
+
+    fn start(&self, transport: Arc<dyn Transport>, client: bool) -> Multiplexer {
+        let (tx, rx) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            transport: transport.clone(),
+            window: self.window,
+            max_frame: self.max_frame,
+            client,
+            state: Mutex::new(State {
+                channels: HashMap::new(),
+                incoming: VecDeque::new(),
+                next_id: if client { 1 } else { 2 },
+                broken: None,
+                tx,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let reader = shared.clone();
+        thread::spawn(move || {
+            let err = match reader.read_loop() {
+                Ok(()) => io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed"),
+                Err(e) => e,
+            };
+            debug!("multiplexer connection ended: {}", err);
+            reader.fail(err.kind());
+        });
+        let writer = Arc::downgrade(&shared);
+        thread::spawn(move || write_loop(transport, rx, writer));
+
+        Multiplexer { shared }
+    }
+}
+
+// The writer thread only holds a weak reference, so that it exits once every `Channel` and the
+// `Multiplexer` (and with them the queue's `Sender`) are gone.  An empty frame asks it to close
+// the connection once everything queued before has been written.
+fn write_loop(transport: Arc<dyn Transport>, rx: Receiver<Vec<u8>>, shared: Weak<Shared>) {
+    for frame in rx {
+        if frame.is_empty() {
+            break;
+        }
+        if let Err(e) = transport.write_all(&frame) {
+            debug!("multiplexer write failed: {}", e);
+            if let Some(shared) = shared.upgrade() {
+                shared.fail(e.kind());
+            }
+            break;
+        }
+    }
+    transport.shutdown();
+}
+
+fn frame(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut f = Vec::with_capacity(HEADER_LEN + payload.len());
+    f.push(kind);
+    f.extend_from_slice(&id.to_be_bytes());
+    f.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    f.extend_from_slice(payload);
+    f
+}
+
+fn protocol_err(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    // received but not yet read
+    buf: VecDeque<u8>,
+    // how much more the peer may send before it needs new credit
+    recv_credit: u32,
+    // bytes read since credit was last returned to the peer
+    unacked: u32,
+    // how much more we may send
+    send_credit: u32,
+    local_closed: bool,
+    remote_closed: bool,
+    reset: bool,
+    // the `Channel` was dropped; the state only lingers until the peer closes its side
+    detached: bool,
+}
+
+struct State {
+    channels: HashMap<u32, ChannelState>,
+    incoming: VecDeque<u32>,
+    next_id: u32,
+    broken: Option<io::ErrorKind>,
+    tx: Sender<Vec<u8>>,
+}
+
+// only fails once the writer thread has exited, after which the connection is unusable anyway
+fn send(tx: &Sender<Vec<u8>>, kind: u8, id: u32, payload: &[u8]) {
+    let _ = tx.send(frame(kind, id, payload));
+}
+
+impl State {
+    fn check(&self) -> io::Result<()> {
+        match self.broken {
+            Some(kind) => Err(io::Error::new(kind, "multiplexed connection failed")),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Shared {
+    transport: Arc<dyn Transport>,
+    window: u32,
+    max_frame: u32,
+    client: bool,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn wait<'a>(&self, guard: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.cond.wait(guard).unwrap()
+    }
+
+    fn fail(&self, kind: io::ErrorKind) {
+        let mut state = self.lock();
+        if state.broken.is_none() {
+            state.broken = Some(kind);
+        }
+        self.cond.notify_all();
+    }
+
+    fn read_exact(&self, buf: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            match self.transport.read(&mut buf[pos..])? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => pos += n,
+            }
+        }
+        Ok(())
+    }
+
+    // returns Ok(()) when the peer closed the connection between frames
+    fn read_loop(&self) -> io::Result<()> {
+        let mut header = [0u8; HEADER_LEN];
+        loop {
+            match self.transport.read(&mut header[..1])? {
+                0 => return Ok(()),
+                _ => self.read_exact(&mut header[1..])?,
+            }
+            let kind = header[0];
+            let mut word = [0u8; 4];
+            word.copy_from_slice(&header[1..5]);
+            let id = u32::from_be_bytes(word);
+            word.copy_from_slice(&header[5..9]);
+            let len = u32::from_be_bytes(word);
+
+            match kind {
+                DATA if len <= self.window => {
+                    let mut payload = vec![0u8; len as usize];
+                    self.read_exact(&mut payload)?;
+                    self.on_data(id, payload)?;
+                }
+                WINDOW if len == 4 => {
+                    self.read_exact(&mut word)?;
+                    self.on_window(id, u32::from_be_bytes(word));
+                }
+                OPEN | CLOSE | RESET if len == 0 => self.on_control(kind, id)?,
+                _ => return Err(protocol_err("bad multiplexer frame")),
+            }
+        }
+    }
+
+    fn on_data(&self, id: u32, payload: Vec<u8>) -> io::Result<()> {
+        let mut state = self.lock();
+        let chan = match state.channels.get_mut(&id) {
+            Some(chan) => chan,
+            // a channel we have already forgotten about after a reset
+            None => return Ok(()),
+        };
+        if payload.len() as u32 > chan.recv_credit {
+            return Err(protocol_err("peer exceeded its channel credit"));
+        }
+        if chan.detached {
+            // nobody will read this any more, so tell the peer to stop sending
+            state.channels.remove(&id);
+            send(&state.tx, RESET, id, &[]);
+            return Ok(());
+        }
+        if !chan.reset {
+            chan.recv_credit -= payload.len() as u32;
+            chan.buf.extend(payload);
+            self.cond.notify_all();
+        }
+        Ok(())
+    }
+
+    fn on_window(&self, id: u32, credit: u32) {
+        let mut state = self.lock();
+        if let Some(chan) = state.channels.get_mut(&id) {
+            chan.send_credit = chan.send_credit.saturating_add(credit);
+            self.cond.notify_all();
+        }
+    }
+
+    fn on_control(&self, kind: u8, id: u32) -> io::Result<()> {
+        let mut state = self.lock();
+        if kind == OPEN {
+            // the peer allocates ids of the other parity
+            if (id % 2 == 1) == self.client || state.channels.contains_key(&id) {
+                return Err(protocol_err("peer opened an invalid channel id"));
+            }
+            state.channels.insert(
+                id,
+                ChannelState {
+                    recv_credit: self.window,
+                    ..ChannelState::default()
+                },
+            );
+            send(&state.tx, WINDOW, id, &self.window.to_be_bytes());
+            state.incoming.push_back(id);
+            self.cond.notify_all();
+            return Ok(());
+        }
+
+        let remove = match state.channels.get_mut(&id) {
+            Some(chan) => {
+                if kind == CLOSE {
+                    chan.remote_closed = true;
+                } else {
+                    chan.reset = true;
+                    chan.buf.clear();
+                }
+                chan.detached
+            }
+            None => false,
+        };
+        if remove {
+            state.channels.remove(&id);
+        }
+        self.cond.notify_all();
+        Ok(())
+    }
+}
+
+/// Carries many `Channel`s over one `UdtStream`
+///
+/// Created by [`MuxConfig::client`](struct.MuxConfig.html#method.client) or
+/// [`MuxConfig::server`](struct.MuxConfig.html#method.server).  `open` and `accept` take
+/// `&self`, so a `Multiplexer` can be shared between threads in an `Arc`.
+///
+/// Dropping the `Multiplexer` closes the connection once the data already written has been
+/// sent.  Channels that are still open then fail with `ConnectionAborted`.
+pub struct Multiplexer {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Multiplexer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = self.shared.lock();
+        f.debug_struct("Multiplexer")
+            .field("client", &self.shared.client)
+            .field("channels", &state.channels.len())
+            .field("broken", &state.broken)
+            .finish()
+    }
+}
+
+impl Multiplexer {
+    /// Opens a new channel.
+    ///
+    /// This doesn't wait for the peer: data can be written right away, and is sent as soon as
+    /// the peer has granted credit for it.
+    pub fn open(&self) -> io::Result<Channel> {
+        let mut state = self.shared.lock();
+        state.check()?;
+        let id = state.next_id;
+        state.next_id = id
+            .checked_add(2)
+            .ok_or_else(|| io::Error::other("channel ids exhausted"))?;
+        state.channels.insert(
+            id,
+            ChannelState {
+                recv_credit: self.shared.window,
+                ..ChannelState::default()
+            },
+        );
+        send(&state.tx, OPEN, id, &[]);
+        send(&state.tx, WINDOW, id, &self.shared.window.to_be_bytes());
+        trace!("opened channel {}", id);
+        Ok(Channel {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Waits for the peer to open a channel.
+    ///
+    /// Returns an error once the connection has failed or was closed by the peer.
+    pub fn accept(&self) -> io::Result<Channel> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(id) = state.incoming.pop_front() {
+                trace!("accepted channel {}", id);
+                return Ok(Channel {
+                    id,
+                    shared: self.shared.clone(),
+                });
+            }
+            state.check()?;
+            state = self.shared.wait(state);
+        }
+    }
+
+    /// Returns the number of channels that haven't been fully closed yet.
+    pub fn channel_count(&self) -> usize {
+        self.shared.lock().channels.len()
+    }
+}
+
+impl Drop for Multiplexer {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        if state.broken.is_none() {
+            state.broken = Some(io::ErrorKind::ConnectionAborted);
+        }
+        // let the writer thread send what is already queued, then close the connection
+        let _ = state.tx.send(Vec::new());
+        self.shared.cond.notify_all();
+    }
+}
+
+/// A logical byte stream carried by a `Multiplexer`
+///
+/// Dropping a `Channel` closes it as with [`close`](#method.close); if the peer then sends more
+/// data, the channel is reset.
+pub struct Channel {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Channel").field("id", &self.id).finish()
+    }
+}
+
+impl Channel {
+    /// Returns the channel id.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Tells the peer that no more data will be written.
+    ///
+    /// The peer's reads return end of file once it has read all data sent before.  This side
+    /// can still read until the peer closes its side too.
+    pub fn close(&self) {
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        let chan = state.channels.get_mut(&self.id).unwrap();
+        if !chan.local_closed && !chan.reset {
+            chan.local_closed = true;
+            send(&state.tx, CLOSE, self.id, &[]);
+        }
+    }
+
+    /// Abandons the channel in both directions.
+    ///
+    /// Unread data is discarded on both sides, and further reads and writes on the peer's side
+    /// fail with `ConnectionReset`.
+    pub fn reset(&self) {
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        let chan = state.channels.get_mut(&self.id).unwrap();
+        if !chan.reset {
+            chan.reset = true;
+            chan.local_closed = true;
+            chan.buf.clear();
+            send(&state.tx, RESET, self.id, &[]);
+        }
+    }
+
+    /// Returns the number of received bytes that haven't been read yet.
+    pub fn available(&self) -> usize {
+        self.shared.lock().channels[&self.id].buf.len()
+    }
+
+    fn reset_err() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "channel was reset")
+    }
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut guard = self.shared.lock();
+        loop {
+            let state = &mut *guard;
+            let chan = state.channels.get_mut(&self.id).unwrap();
+            if chan.reset {
+                return Err(Channel::reset_err());
+            }
+            if !chan.buf.is_empty() {
+                let n = std::cmp::min(buf.len(), chan.buf.len());
+                for (dst, src) in buf.iter_mut().zip(chan.buf.drain(..n)) {
+                    *dst = src;
+                }
+                // return credit in batches, so a reader doing small reads doesn't produce a
+                // WINDOW frame for each of them
+                chan.unacked += n as u32;
+                if chan.unacked >= self.shared.window / 2 && !chan.remote_closed {
+                    let credit = chan.unacked;
+                    chan.recv_credit += credit;
+                    chan.unacked = 0;
+                    send(&state.tx, WINDOW, self.id, &credit.to_be_bytes());
+                }
+                return Ok(n);
+            }
+            if chan.remote_closed {
+                return Ok(0);
+            }
+            state.check()?;
+            guard = self.shared.wait(guard);
+        }
+    }
+}
+
+impl Write for Channel {
+    /// Queues as much of `buf` as the peer has granted credit for, waiting for credit if there
+    /// is none.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut guard = self.shared.lock();
+        loop {
+            let state = &mut *guard;
+            state.check()?;
+            let chan = state.channels.get_mut(&self.id).unwrap();
+            if chan.reset {
+                return Err(Channel::reset_err());
+            }
+            if chan.local_closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "channel was closed",
+                ));
+            }
+            if chan.send_credit > 0 {
+                let n = [
+                    buf.len(),
+                    chan.send_credit as usize,
+                    self.shared.max_frame as usize,
+                ]
+                .iter()
+                .copied()
+                .min()
+                .unwrap();
+                chan.send_credit -= n as u32;
+                send(&state.tx, DATA, self.id, &buf[..n]);
+                return Ok(n);
+            }
+            guard = self.shared.wait(guard);
+        }
+    }
+
+    /// Data is handed to the connection's writer thread as soon as it is written, so this
+    /// does nothing.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        let chan = state.channels.get_mut(&self.id).unwrap();
+        if !chan.local_closed && !chan.reset {
+            send(&state.tx, CLOSE, self.id, &[]);
+        }
+        if chan.remote_closed || chan.reset {
+            state.channels.remove(&self.id);
+        } else {
+            chan.local_closed = true;
+            chan.detached = true;
+            chan.buf.clear();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+fn pair(config: MuxConfig) -> (Multiplexer, Multiplexer) {
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    (
+        config.start(Arc::new(a), true),
+        config.start(Arc::new(b), false),
+    )
+}
+
+#[test]
+#[cfg(unix)]
+fn test_mux_channels() {
+    let (client, server) = pair(MuxConfig::new());
+    let t = thread::spawn(move || {
+        // echo every channel back, reversed
+        for _ in 0..2 {
+            let mut chan = server.accept().unwrap();
+            assert_eq!(chan.id() % 2, 1);
+            let mut data = Vec::new();
+            chan.read_to_end(&mut data).unwrap();
+            data.reverse();
+            chan.write_all(&data).unwrap();
+        }
+        server
+    });
+
+    let mut a = client.open().unwrap();
+    let mut b = client.open().unwrap();
+    assert_eq!((a.id(), b.id()), (1, 3));
+    let big: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+    a.write_all(&big).unwrap();
+    a.close();
+    b.write_all(b"hello").unwrap();
+    b.close();
+
+    let mut got = Vec::new();
+    a.read_to_end(&mut got).unwrap();
+    got.reverse();
+    assert!(got == big);
+    got.clear();
+    b.read_to_end(&mut got).unwrap();
+    assert_eq!(&got, b"olleh");
+    assert!(a.write(b"more").is_err());
+
+    drop(a);
+    drop(b);
+    let server = t.join().unwrap();
+    drop(client);
+    assert_eq!(
+        server.accept().unwrap_err().kind(),
+        io::ErrorKind::ConnectionAborted
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_mux_slow_consumer() {
+    let (client, server) = pair(MuxConfig::new().window(1024).max_frame(256));
+
+    let mut slow = client.open().unwrap();
+    let mut fast = client.open().unwrap();
+    let mut slow_peer = server.accept().unwrap();
+    let mut fast_peer = server.accept().unwrap();
+
+    // nobody reads `slow`, so writing to it stops after one window...
+    let writer = thread::spawn(move || {
+        slow.write_all(&[1u8; 4096]).unwrap();
+        slow
+    });
+    thread::sleep(std::time::Duration::from_millis(100));
+    assert_eq!(slow_peer.available(), 1024);
+
+    // ...but other channels keep going
+    let fast_writer = thread::spawn(move || fast.write_all(&[2u8; 10_000]).unwrap());
+    let mut buf = vec![0u8; 10_000];
+    fast_peer.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 2));
+    fast_writer.join().unwrap();
+
+    let mut buf = vec![0u8; 4096];
+    slow_peer.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 1));
+    drop(writer.join().unwrap());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_mux_reset() {
+    let (client, server) = pair(MuxConfig::new());
+    let mut chan = client.open().unwrap();
+    chan.write_all(b"discard me").unwrap();
+    let mut peer = server.accept().unwrap();
+    peer.reset();
+
+    let err = loop {
+        match chan.write_all(b"x") {
+            Ok(_) => thread::sleep(std::time::Duration::from_millis(10)),
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    let err = peer.read(&mut [0u8; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+    // the connection itself is still fine
+    let mut chan = server.open().unwrap();
+    assert_eq!(chan.id(), 2);
+    chan.write_all(b"still here").unwrap();
+    let mut peer = client.accept().unwrap();
+    let mut buf = [0u8; 10];
+    peer.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"still here");
+}
@@ -0,0 +1,506 @@
+//! A pool of client connections, keyed by peer address
+//!
+//! Opening a UDT connection costs a handshake round trip and a fresh congestion window.  A
+//! `ConnectionPool` keeps connections that are no longer in use and hands them out again for
+//! the next request to the same peer.
+//!
+//! A pooled connection is only reused if `getstate()` still reports it as `CONNECTED` and it
+//! hasn't been idle for longer than the configured maximum; other idle connections are closed
+//! and evicted.  The number of connections per peer, both idle and in use, is limited; when the
+//! limit is reached, `get` waits for a connection to be returned.
+//!
+//! A connection is returned to the pool when its `PooledStream` is dropped, so it must be left
+//! at a message boundary, without unread replies.  Use
+//! [`discard`](struct.PooledStream.html#method.discard) instead if an exchange was interrupted.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::{Read, Write};
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::pool::PoolConfig;
+//!
+//! init();
+//! let pool = PoolConfig::new()
+//!     .max_per_peer(4)
+//!     .max_idle_time(Duration::from_secs(30))
+//!     .build();
+//!
+//! let addr = "192.0.2.10:9000".parse().unwrap();
+//! for _ in 0..10 {
+//!     // only the first iteration pays for a handshake
+//!     let mut conn = pool.get(addr).unwrap();
+//!     conn.write_all(b"PING\n").unwrap();
+//!     let mut reply = [0u8; 5];
+//!     conn.read_exact(&mut reply).unwrap();
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{UdtError, UdtStatus, UdtStream};
+
+/// Errors from a `ConnectionPool`
+#[derive(Debug)]
+pub enum PoolError {
+    /// Connecting to the peer failed
+    Udt(UdtError),
+    /// The peer already had the maximum number of connections, and none was returned within
+    /// the checkout timeout
+    Exhausted(SocketAddr),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Udt(ref e) => write!(f, "{}", e),
+            PoolError::Exhausted(addr) => write!(f, "no connection to {} available", addr),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            PoolError::Udt(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UdtError> for PoolError {
+    fn from(e: UdtError) -> PoolError {
+        PoolError::Udt(e)
+    }
+}
+
+impl From<PoolError> for io::Error {
+    fn from(e: PoolError) -> io::Error {
+        match e {
+            PoolError::Udt(e) => e.into(),
+            e => io::Error::new(io::ErrorKind::TimedOut, e),
+        }
+    }
+}
+
+// What the pool needs to know about a connection
+trait Health: Send {
+    fn is_connected(&self) -> bool;
+}
+
+impl Health for UdtStream {
+    fn is_connected(&self) -> bool {
+        self.socket().getstate() == UdtStatus::CONNECTED
+    }
+}
+
+/// Settings for a `ConnectionPool`
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    max_per_peer: usize,
+    max_idle_time: Duration,
+    checkout_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig::new()
+    }
+}
+
+impl PoolConfig {
+    /// Creates the default configuration: at most 8 connections per peer, idle connections are
+    /// closed after 60 seconds, and `get` waits up to 10 seconds for a free connection.
+    pub fn new() -> PoolConfig {
+        PoolConfig {
+            max_per_peer: 8,
+            max_idle_time: Duration::from_secs(60),
+            checkout_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the maximum number of connections to one peer, counting both idle connections and
+    /// those in use.
+    pub fn max_per_peer(mut self, n: usize) -> PoolConfig {
+        assert!(n > 0, "max_per_peer must not be zero");
+        self.max_per_peer = n;
+        self
+    }
+
+    /// Sets how long a connection may sit in the pool before it is closed.
+    pub fn max_idle_time(mut self, idle: Duration) -> PoolConfig {
+        self.max_idle_time = idle;
+        self
+    }
+
+    /// Sets how long `get` waits for a connection when a peer is at its limit.
+    pub fn checkout_timeout(mut self, timeout: Duration) -> PoolConfig {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    /// Creates an empty pool.
+    pub fn build(&self) -> ConnectionPool {
+        ConnectionPool {
+            inner: Arc::new(Inner::new(self.clone(), Box::new(UdtStream::connect))),
+        }
+    }
+}
+
+/// Connection counters of a `ConnectionPool`
+#[derive(Debug, Clone, Default)]
+pub struct PoolStats {
+    /// New connections opened
+    pub connects: u64,
+    /// Requests served with a pooled connection
+    pub reuses: u64,
+    /// Connections closed because they were broken or idle for too long
+    pub evicted: u64,
+}
+
+struct Peer<C> {
+    // oldest first
+    idle: Vec<(C, Instant)>,
+    // idle connections and those in use
+    open: usize,
+}
+
+struct State<C> {
+    peers: HashMap<SocketAddr, Peer<C>>,
+    stats: PoolStats,
+}
+
+type Connector<C> = Box<dyn Fn(SocketAddr) -> Result<C, UdtError> + Send + Sync>;
+
+struct Inner<C> {
+    config: PoolConfig,
+    connect: Connector<C>,
+    state: Mutex<State<C>>,
+    cond: Condvar,
+}
+
+impl<C: Health> Inner<C> {
+    fn new(config: PoolConfig, connect: Connector<C>) -> Inner<C> {
+        Inner {
+            config,
+            connect,
+            state: Mutex::new(State {
+                peers: HashMap::new(),
+                stats: PoolStats::default(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    // Moves the idle connections that can't be reused any more into `evicted`, so they can be
+    // closed without holding the lock.
+    fn evict(&self, peer: &mut Peer<C>, stats: &mut PoolStats, evicted: &mut Vec<C>) {
+        let max_idle = self.config.max_idle_time;
+        let mut i = 0;
+        while i < peer.idle.len() {
+            let (ref conn, since) = peer.idle[i];
+            if since.elapsed() > max_idle || !conn.is_connected() {
+                evicted.push(peer.idle.remove(i).0);
+                peer.open -= 1;
+                stats.evicted += 1;
+                self.cond.notify_all();
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn checkout(&self, addr: SocketAddr) -> Result<C, PoolError> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut evicted = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let State {
+                ref mut peers,
+                ref mut stats,
+            } = *state;
+            let peer = peers.entry(addr).or_insert_with(|| Peer {
+                idle: Vec::new(),
+                open: 0,
+            });
+            self.evict(peer, stats, &mut evicted);
+            if let Some((conn, _)) = peer.idle.pop() {
+                stats.reuses += 1;
+                return Ok(conn);
+            }
+            if peer.open < self.config.max_per_peer {
+                peer.open += 1;
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(PoolError::Exhausted(addr));
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        drop(state);
+        drop(evicted);
+
+        match (self.connect)(addr) {
+            Ok(conn) => {
+                self.state.lock().unwrap().stats.connects += 1;
+                Ok(conn)
+            }
+            Err(e) => {
+                self.release(addr);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn checkin(&self, addr: SocketAddr, conn: C) {
+        let mut state = self.state.lock().unwrap();
+        if conn.is_connected() {
+            if let Some(peer) = state.peers.get_mut(&addr) {
+                peer.idle.push((conn, Instant::now()));
+                self.cond.notify_all();
+                return;
+            }
+        }
+        state.stats.evicted += 1;
+        drop(state);
+        self.release(addr);
+        drop(conn);
+    }
+
+    // forgets a connection that was checked out
+    fn release(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(peer) = state.peers.get_mut(&addr) {
+            peer.open -= 1;
+        }
+        self.cond.notify_all();
+    }
+
+    fn prune(&self) {
+        let mut evicted = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        let State {
+            ref mut peers,
+            ref mut stats,
+        } = *state;
+        for peer in peers.values_mut() {
+            self.evict(peer, stats, &mut evicted);
+        }
+        peers.retain(|_, peer| peer.open > 0);
+    }
+}
+
+/// A pool of `UdtStream`s, keyed by peer address
+///
+/// The pool can be cloned cheaply; all clones share the same connections.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    inner: Arc<Inner<UdtStream>>,
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("config", &self.inner.config)
+            .finish()
+    }
+}
+
+impl Default for ConnectionPool {
+    fn default() -> ConnectionPool {
+        PoolConfig::new().build()
+    }
+}
+
+impl ConnectionPool {
+    /// Returns a connection to `addr`, reusing an idle one if possible.
+    ///
+    /// `init()` must have been called first.
+    pub fn get(&self, addr: SocketAddr) -> Result<PooledStream, PoolError> {
+        let stream = self.inner.checkout(addr)?;
+        Ok(PooledStream {
+            pool: self.inner.clone(),
+            addr,
+            stream: Some(stream),
+        })
+    }
+
+    /// Closes the idle connections that are broken or have been idle for too long.
+    ///
+    /// This happens for a peer whenever a connection to it is requested; calling this
+    /// periodically also cleans up after peers that aren't used any more.
+    pub fn prune(&self) {
+        self.inner.prune();
+    }
+
+    /// Returns the number of idle connections to `addr`.
+    pub fn idle(&self, addr: SocketAddr) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.peers.get(&addr).map_or(0, |p| p.idle.len())
+    }
+
+    /// Returns the number of connections to `addr`, both idle and in use.
+    pub fn open(&self, addr: SocketAddr) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.peers.get(&addr).map_or(0, |p| p.open)
+    }
+
+    /// Returns a snapshot of the connection counters.
+    pub fn stats(&self) -> PoolStats {
+        self.inner.state.lock().unwrap().stats.clone()
+    }
+}
+
+/// A connection borrowed from a `ConnectionPool`
+///
+/// Dropping it returns the connection to the pool, unless it is no longer connected.
+pub struct PooledStream {
+    pool: Arc<Inner<UdtStream>>,
+    addr: SocketAddr,
+    stream: Option<UdtStream>,
+}
+
+impl fmt::Debug for PooledStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PooledStream")
+            .field("addr", &self.addr)
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl PooledStream {
+    /// Closes the connection instead of returning it to the pool.
+    pub fn discard(mut self) {
+        let stream = self.stream.take();
+        self.pool.release(self.addr);
+        drop(stream);
+    }
+
+    /// Takes the connection out of the pool for good.
+    pub fn detach(mut self) -> UdtStream {
+        self.pool.release(self.addr);
+        self.stream.take().unwrap()
+    }
+}
+
+impl Deref for PooledStream {
+    type Target = UdtStream;
+
+    fn deref(&self) -> &UdtStream {
+        self.stream.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            self.pool.checkin(self.addr, stream);
+        }
+    }
+}
+
+impl Read for PooledStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&**self).read(buf)
+    }
+}
+
+impl Write for PooledStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&**self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&**self).flush()
+    }
+}
+
+#[cfg(test)]
+struct FakeConn(Arc<std::sync::atomic::AtomicBool>);
+
+#[cfg(test)]
+impl Health for FakeConn {
+    fn is_connected(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_pool_reuse_and_eviction() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let config = PoolConfig::new()
+        .max_per_peer(2)
+        .max_idle_time(Duration::from_millis(50))
+        .checkout_timeout(Duration::from_millis(50));
+    let pool = Inner::new(
+        config,
+        Box::new(|_| Ok(FakeConn(Arc::new(AtomicBool::new(true))))),
+    );
+    let a: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+    let b: SocketAddr = "192.0.2.2:9000".parse().unwrap();
+
+    let c1 = pool.checkout(a).unwrap();
+    let health = c1.0.clone();
+    pool.checkin(a, c1);
+    let c1 = pool.checkout(a).unwrap();
+    assert!(Arc::ptr_eq(&c1.0, &health));
+    let c2 = pool.checkout(a).unwrap();
+    assert!(matches!(pool.checkout(a), Err(PoolError::Exhausted(_))));
+    // the limit is per peer
+    let c3 = pool.checkout(b).unwrap();
+
+    // a broken connection isn't pooled again, which frees its slot
+    health.store(false, Ordering::SeqCst);
+    pool.checkin(a, c1);
+    let c1 = pool.checkout(a).unwrap();
+    assert!(!Arc::ptr_eq(&c1.0, &health));
+
+    // neither is one that was idle for too long
+    pool.checkin(a, c1);
+    pool.checkin(a, c2);
+    std::thread::sleep(Duration::from_millis(100));
+    pool.checkin(b, c3);
+    pool.prune();
+    {
+        let state = pool.state.lock().unwrap();
+        assert!(!state.peers.contains_key(&a));
+        assert_eq!(state.peers[&b].idle.len(), 1);
+        assert_eq!(state.stats.connects, 4);
+        assert_eq!(state.stats.reuses, 1);
+        assert_eq!(state.stats.evicted, 3);
+    }
+}
+
+#[test]
+fn test_pool_waits_for_checkin() {
+    use std::sync::atomic::AtomicBool;
+
+    let config = PoolConfig::new()
+        .max_per_peer(1)
+        .checkout_timeout(Duration::from_secs(10));
+    let pool = Arc::new(Inner::new(
+        config,
+        Box::new(|_| Ok(FakeConn(Arc::new(AtomicBool::new(true))))),
+    ));
+    let addr: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+
+    let conn = pool.checkout(addr).unwrap();
+    let health = conn.0.clone();
+    let waiter = {
+        let pool = pool.clone();
+        std::thread::spawn(move || pool.checkout(addr).map(|c| c.0))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    pool.checkin(addr, conn);
+    assert!(Arc::ptr_eq(&waiter.join().unwrap().unwrap(), &health));
+}
@@ -0,0 +1,687 @@
+//! A publish/subscribe broker over UDT `Datagram` sockets
+//!
+//! A `Broker` accepts connections from `Publisher`s and `Subscriber`s.  Subscribers declare the
+//! topics they are interested in with patterns, and every message a publisher sends is forwarded
+//! to each subscriber with a matching pattern.
+//!
+//! Topics are dot-separated names such as `quotes.nyse.IBM`.  In a pattern, `*` matches exactly
+//! one segment and a final `>` matches one or more trailing segments, so `quotes.*.IBM` and
+//! `quotes.>` both match the topic above.
+//!
+//! Messages are sent with `sendmsg` and a TTL, both by the publisher and by the broker.  A
+//! subscriber that can't keep up therefore loses stale messages instead of making the broker
+//! queue them.  The broker never blocks on a subscriber: their sockets don't block on sending
+//! (`UDT_SNDSYN` is off), and a message that doesn't fit into a subscriber's send buffer is
+//! skipped.  The broker also tracks how many packets are waiting in each subscriber's send
+//! buffer (`UDT_SNDDATA`), and can be configured to skip subscribers that lag too far behind.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::init;
+//! use udt::pubsub::{BrokerConfig, Publisher, Subscriber};
+//!
+//! init();
+//! let broker = BrokerConfig::new()
+//!     .max_lag(1000)
+//!     .bind("0.0.0.0:9000".parse().unwrap())
+//!     .unwrap();
+//!
+//! let sub = Subscriber::connect("127.0.0.1:9000".parse().unwrap()).unwrap();
+//! sub.subscribe("quotes.>").unwrap();
+//!
+//! let publisher = Publisher::connect("127.0.0.1:9000".parse().unwrap()).unwrap();
+//! publisher
+//!     .publish("quotes.nyse.IBM", b"142.17", Some(Duration::from_millis(500)))
+//!     .unwrap();
+//!
+//! let msg = sub.recv().unwrap();
+//! println!("{}: {:?}", msg.topic, msg.payload);
+//! ```
+//!
+//! # Wire format
+//!
+//! Every UDT message starts with a kind byte.  `SUBSCRIBE` (1) and `UNSUBSCRIBE` (2) are followed
+//! by the pattern.  `PUBLISH` (3), sent by publishers, and `MESSAGE` (4), sent by the broker, are
+//! followed by the TTL in milliseconds as a 32-bit big-endian integer (0 for none), the length of
+//! the topic as a 16-bit big-endian integer, the topic and the payload.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::raw;
+use crate::stream::OwnedSocket;
+use crate::{SocketFamily, SocketType, UdtError, UdtOpts, UdtSocket};
+
+/// The largest UDT message used by the protocol, including the header and topic
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+const SUBSCRIBE: u8 = 1;
+const UNSUBSCRIBE: u8 = 2;
+const PUBLISH: u8 = 3;
+const MESSAGE: u8 = 4;
+const HEADER_LEN: usize = 7;
+
+// how long the broker waits before accepting again after an unexpected error
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Errors from the pub/sub broker and its clients
+#[derive(Debug)]
+pub enum PubSubError {
+    /// The underlying socket failed
+    Udt(UdtError),
+    /// A topic or pattern was malformed
+    BadTopic(String),
+    /// A message was larger than `MAX_MESSAGE_SIZE`
+    TooLarge {
+        /// The length of the message, including the header and topic
+        len: usize,
+        /// The maximum
+        max: usize,
+    },
+    /// The peer sent a message that doesn't follow the protocol
+    Protocol(&'static str),
+}
+
+impl fmt::Display for PubSubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PubSubError::Udt(ref e) => write!(f, "{}", e),
+            PubSubError::BadTopic(ref t) => write!(f, "malformed topic or pattern {:?}", t),
+            PubSubError::TooLarge { len, max } => {
+                write!(f, "message of {} bytes exceeds the maximum of {}", len, max)
+            }
+            PubSubError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PubSubError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            PubSubError::Udt(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<UdtError> for PubSubError {
+    fn from(e: UdtError) -> PubSubError {
+        PubSubError::Udt(e)
+    }
+}
+
+fn check_topic(topic: &str, pattern: bool) -> Result<(), PubSubError> {
+    let bad = || PubSubError::BadTopic(topic.to_string());
+    if topic.len() > usize::from(u16::MAX) {
+        return Err(bad());
+    }
+    let segments: Vec<&str> = topic.split('.').collect();
+    for (i, seg) in segments.iter().enumerate() {
+        let ok = match *seg {
+            "" => false,
+            "*" => pattern,
+            ">" => pattern && i == segments.len() - 1,
+            s => !s.contains('*') && !s.contains('>'),
+        };
+        if !ok {
+            return Err(bad());
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `topic` matches `pattern`.
+///
+/// Both are expected to be well-formed.
+fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('.');
+    for seg in pattern.split('.') {
+        match (seg, topic.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (p, Some(t)) if p == t => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+fn ttl_millis(ttl: Option<Duration>) -> u32 {
+    match ttl {
+        // UDT can't express a TTL of 0; round it up rather than sending without one
+        Some(d) => std::cmp::max(1, std::cmp::min(d.as_millis(), u128::from(u32::MAX)) as u32),
+        None => 0,
+    }
+}
+
+fn encode_message(kind: u8, ttl: Option<Duration>, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + topic.len() + payload.len());
+    msg.push(kind);
+    msg.extend_from_slice(&ttl_millis(ttl).to_be_bytes());
+    msg.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    msg.extend_from_slice(topic.as_bytes());
+    msg.extend_from_slice(payload);
+    msg
+}
+
+// returns the TTL, topic and payload of a PUBLISH or MESSAGE
+fn decode_message(msg: &[u8]) -> Result<(Option<Duration>, &str, &[u8]), PubSubError> {
+    if msg.len() < HEADER_LEN {
+        return Err(PubSubError::Protocol("truncated message"));
+    }
+    let ttl = u32::from_be_bytes([msg[1], msg[2], msg[3], msg[4]]);
+    let topic_len = usize::from(u16::from_be_bytes([msg[5], msg[6]]));
+    if msg.len() < HEADER_LEN + topic_len {
+        return Err(PubSubError::Protocol("truncated topic"));
+    }
+    let topic = std::str::from_utf8(&msg[HEADER_LEN..HEADER_LEN + topic_len])
+        .map_err(|_| PubSubError::Protocol("topic is not UTF-8"))?;
+    let ttl = if ttl == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(u64::from(ttl)))
+    };
+    Ok((ttl, topic, &msg[HEADER_LEN + topic_len..]))
+}
+
+fn connect(addr: SocketAddr) -> Result<UdtSocket, UdtError> {
+    let family = match addr {
+        SocketAddr::V4(..) => SocketFamily::AFInet,
+        SocketAddr::V6(..) => SocketFamily::AFInet6,
+    };
+    let sock = UdtSocket::new(family, SocketType::Datagram)?;
+    if let Err(e) = sock.connect(addr) {
+        let _ = sock.close();
+        return Err(e);
+    }
+    Ok(sock)
+}
+
+fn is_disconnect(e: &UdtError) -> bool {
+    e.err_code == raw::ECONNLOST || e.err_code == raw::ENOCONN || e.err_code == raw::EINVSOCK
+}
+
+/// Settings for a `Broker`
+#[derive(Debug, Clone, Default)]
+pub struct BrokerConfig {
+    max_lag: Option<i32>,
+    default_ttl: Option<Duration>,
+}
+
+impl BrokerConfig {
+    /// Creates the default configuration: subscribers are never skipped for lagging, and
+    /// messages published without a TTL are forwarded without one.
+    pub fn new() -> BrokerConfig {
+        BrokerConfig::default()
+    }
+
+    /// Skips subscribers with at least `packets` packets waiting in their send buffer.
+    pub fn max_lag(mut self, packets: i32) -> BrokerConfig {
+        self.max_lag = Some(packets);
+        self
+    }
+
+    /// Sets the TTL used to forward messages that were published without one.
+    pub fn default_ttl(mut self, ttl: Duration) -> BrokerConfig {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Binds a `Datagram` socket to `addr` and starts a broker on it.
+    pub fn bind(self, addr: SocketAddr) -> Result<Broker, UdtError> {
+        let family = match addr {
+            SocketAddr::V4(..) => SocketFamily::AFInet,
+            SocketAddr::V6(..) => SocketFamily::AFInet6,
+        };
+        let sock = UdtSocket::new(family, SocketType::Datagram)?;
+        if let Err(e) = sock.bind(addr).and_then(|_| sock.listen(128)) {
+            let _ = sock.close();
+            return Err(e);
+        }
+        Ok(self.serve(sock))
+    }
+
+    /// Starts a broker on a `Datagram` socket that is already listening.
+    pub fn serve(self, listener: UdtSocket) -> Broker {
+        let shared = Arc::new(Shared {
+            config: self,
+            closed: AtomicBool::new(false),
+            conns: Mutex::new(Vec::new()),
+            published: AtomicU64::new(0),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || accept_loop(&shared, listener))
+        };
+        Broker {
+            listener,
+            shared,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Per-subscriber counters, see [`Broker::subscribers`](struct.Broker.html#method.subscribers)
+#[derive(Debug, Clone)]
+pub struct SubscriberStats {
+    /// The address of the subscriber
+    pub addr: SocketAddr,
+    /// The patterns it subscribed to
+    pub patterns: Vec<String>,
+    /// The number of packets waiting in its send buffer (`UDT_SNDDATA`)
+    pub lag: i32,
+    /// Messages handed to UDT for this subscriber
+    pub delivered: u64,
+    /// Messages skipped because the subscriber was lagging or its send buffer was full
+    pub skipped: u64,
+}
+
+struct Conn {
+    // closed by whichever of `Broker::shutdown` and the connection's reader gets there first
+    sock: OwnedSocket,
+    addr: SocketAddr,
+    patterns: Mutex<Vec<String>>,
+    delivered: AtomicU64,
+    skipped: AtomicU64,
+}
+
+struct Shared {
+    config: BrokerConfig,
+    closed: AtomicBool,
+    conns: Mutex<Vec<Arc<Conn>>>,
+    published: AtomicU64,
+}
+
+/// A running pub/sub broker
+///
+/// The broker accepts connections and forwards messages on background threads until it is
+/// closed or dropped, which also closes all connections.
+pub struct Broker {
+    listener: UdtSocket,
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+impl Broker {
+    /// Returns the address the broker is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.listener.getsockname()
+    }
+
+    /// Returns the number of messages received from publishers.
+    pub fn published(&self) -> u64 {
+        self.shared.published.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters of every connection that subscribed to at least one pattern.
+    ///
+    /// The lag is read from each socket when this is called.
+    pub fn subscribers(&self) -> Vec<SubscriberStats> {
+        let conns = self.shared.conns.lock().unwrap().clone();
+        conns
+            .iter()
+            .filter_map(|conn| {
+                let patterns = conn.patterns.lock().unwrap().clone();
+                if patterns.is_empty() {
+                    return None;
+                }
+                Some(SubscriberStats {
+                    addr: conn.addr,
+                    patterns,
+                    lag: conn.sock.getsockopt(UdtOpts::UDT_SNDDATA).unwrap_or(0),
+                    delivered: conn.delivered.load(Ordering::Relaxed),
+                    skipped: conn.skipped.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+
+    /// Stops the broker and closes all of its connections.
+    pub fn close(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let thread = match self.thread.take() {
+            Some(t) => t,
+            None => return,
+        };
+        {
+            let conns = self.shared.conns.lock().unwrap();
+            self.shared.closed.store(true, Ordering::SeqCst);
+            for conn in conns.iter() {
+                let _ = conn.sock.close();
+            }
+        }
+        let _ = self.listener.close();
+        let _ = thread.join();
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn accept_loop(shared: &Arc<Shared>, listener: UdtSocket) {
+    let mut readers = Vec::new();
+    loop {
+        let (sock, addr) = match listener.accept() {
+            Ok(c) => c,
+            Err(_) if shared.closed.load(Ordering::SeqCst) => break,
+            // the listener was closed behind the broker's back
+            Err(ref e) if e.err_code == raw::EINVSOCK || e.err_code == raw::ENOLISTEN => {
+                warn!("pubsub broker stopped accepting connections: {}", e);
+                break;
+            }
+            Err(e) => {
+                warn!("pubsub broker failed to accept a connection: {}", e);
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+        };
+        // a slow subscriber must never hold up the thread forwarding a message
+        if let Err(e) = sock.setsockopt(UdtOpts::UDT_SNDSYN, false) {
+            warn!("pubsub broker failed to configure {}: {}", addr, e);
+            let _ = sock.close();
+            continue;
+        }
+        let conn = Arc::new(Conn {
+            sock: OwnedSocket::new(sock),
+            addr,
+            patterns: Mutex::new(Vec::new()),
+            delivered: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        });
+        {
+            let mut conns = shared.conns.lock().unwrap();
+            // dropping `conn` closes the socket
+            if shared.closed.load(Ordering::SeqCst) {
+                break;
+            }
+            conns.push(conn.clone());
+        }
+        debug!("pubsub broker accepted {}", addr);
+        let shared = shared.clone();
+        readers.push(thread::spawn(move || read_loop(&shared, &conn)));
+        readers.retain(|t: &thread::JoinHandle<()>| !t.is_finished());
+    }
+    for t in readers {
+        let _ = t.join();
+    }
+}
+
+fn read_loop(shared: &Shared, conn: &Conn) {
+    // one spare byte tells us whether UDT had to truncate the message
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE + 1];
+    loop {
+        let len = match conn.sock.recvmsg(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if is_disconnect(e) => break,
+            Err(e) => {
+                debug!("pubsub broker failed to read from {}: {}", conn.addr, e);
+                break;
+            }
+        };
+        let truncated = len == buf.len();
+        if let Err(e) = handle(shared, conn, &mut buf[..len], truncated) {
+            debug!("pubsub broker dropping {}: {}", conn.addr, e);
+            break;
+        }
+    }
+    shared
+        .conns
+        .lock()
+        .unwrap()
+        .retain(|c| !std::ptr::eq(&**c, conn));
+    let _ = conn.sock.close();
+    debug!("pubsub broker closed {}", conn.addr);
+}
+
+fn handle(
+    shared: &Shared,
+    conn: &Conn,
+    msg: &mut [u8],
+    truncated: bool,
+) -> Result<(), PubSubError> {
+    if truncated {
+        return Err(PubSubError::TooLarge {
+            len: msg.len(),
+            max: MAX_MESSAGE_SIZE,
+        });
+    }
+    match msg.first() {
+        Some(&SUBSCRIBE) | Some(&UNSUBSCRIBE) => {
+            let pattern = std::str::from_utf8(&msg[1..])
+                .map_err(|_| PubSubError::Protocol("pattern is not UTF-8"))?;
+            check_topic(pattern, true)?;
+            let mut patterns = conn.patterns.lock().unwrap();
+            if msg[0] == SUBSCRIBE {
+                if !patterns.iter().any(|p| p == pattern) {
+                    patterns.push(pattern.to_string());
+                }
+            } else {
+                patterns.retain(|p| p != pattern);
+            }
+            Ok(())
+        }
+        Some(&PUBLISH) => {
+            let (ttl, topic, _) = decode_message(msg)?;
+            check_topic(topic, false)?;
+            let ttl = ttl.or(shared.config.default_ttl);
+            let topic = topic.to_string();
+            shared.published.fetch_add(1, Ordering::Relaxed);
+
+            // forward the message as it is, apart from the kind and the TTL
+            msg[0] = MESSAGE;
+            msg[1..5].copy_from_slice(&ttl_millis(ttl).to_be_bytes());
+            let conns = shared.conns.lock().unwrap().clone();
+            for sub in conns.iter() {
+                if sub
+                    .patterns
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|p| matches(p, &topic))
+                {
+                    forward(shared, sub, msg, ttl);
+                }
+            }
+            Ok(())
+        }
+        _ => Err(PubSubError::Protocol("unknown message kind")),
+    }
+}
+
+fn forward(shared: &Shared, sub: &Conn, msg: &[u8], ttl: Option<Duration>) {
+    if let Some(max_lag) = shared.config.max_lag {
+        match sub.sock.getsockopt(UdtOpts::UDT_SNDDATA) {
+            Ok(lag) if lag >= max_lag => {
+                trace!("skipping {}, {} packets behind", sub.addr, lag);
+                sub.skipped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            _ => {}
+        }
+    }
+    match sub.sock.sendmsg_ttl(msg, ttl, true) {
+        Ok(_) => {
+            sub.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(ref e) if e.err_code == raw::EASYNCSND => {
+            trace!("skipping {}, send buffer is full", sub.addr);
+            sub.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        // the subscriber's own reader notices that the connection is gone
+        Err(e) => debug!("pubsub broker failed to forward to {}: {}", sub.addr, e),
+    }
+}
+
+/// A connection that publishes messages to a `Broker`
+///
+/// The socket is closed when the `Publisher` is dropped.
+#[derive(Debug)]
+pub struct Publisher {
+    sock: OwnedSocket,
+}
+
+impl Publisher {
+    /// Connects to a broker.
+    pub fn connect(addr: SocketAddr) -> Result<Publisher, PubSubError> {
+        Ok(Publisher {
+            sock: OwnedSocket::new(connect(addr)?),
+        })
+    }
+
+    /// Publishes a message on `topic`.
+    ///
+    /// If `ttl` is given, the message is dropped by UDT, both here and at the broker, if it
+    /// can't be delivered within that time.
+    pub fn publish(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<(), PubSubError> {
+        check_topic(topic, false)?;
+        let msg = encode_message(PUBLISH, ttl, topic, payload);
+        if msg.len() > MAX_MESSAGE_SIZE {
+            return Err(PubSubError::TooLarge {
+                len: msg.len(),
+                max: MAX_MESSAGE_SIZE,
+            });
+        }
+        self.sock.sendmsg_ttl(&msg, ttl, true)?;
+        Ok(())
+    }
+
+    /// Returns the underlying socket, e.g. for use with `getsockopt` or `perfmon`.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Closes the connection.
+    pub fn close(self) -> Result<(), UdtError> {
+        self.sock.close()
+    }
+}
+
+/// A message received by a `Subscriber`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// The topic it was published on
+    pub topic: String,
+    /// The payload
+    pub payload: Vec<u8>,
+}
+
+/// A connection that receives messages from a `Broker`
+///
+/// The socket is closed when the `Subscriber` is dropped.
+#[derive(Debug)]
+pub struct Subscriber {
+    sock: OwnedSocket,
+    buf: Mutex<Vec<u8>>,
+}
+
+impl Subscriber {
+    /// Connects to a broker, without subscribing to anything yet.
+    pub fn connect(addr: SocketAddr) -> Result<Subscriber, PubSubError> {
+        Ok(Subscriber {
+            sock: OwnedSocket::new(connect(addr)?),
+            buf: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Subscribes to the topics matching `pattern`.
+    ///
+    /// The subscription takes effect once the broker has received it; messages published before
+    /// that aren't delivered.
+    pub fn subscribe(&self, pattern: &str) -> Result<(), PubSubError> {
+        self.send_pattern(SUBSCRIBE, pattern)
+    }
+
+    /// Removes a subscription made with `subscribe`.
+    pub fn unsubscribe(&self, pattern: &str) -> Result<(), PubSubError> {
+        self.send_pattern(UNSUBSCRIBE, pattern)
+    }
+
+    fn send_pattern(&self, kind: u8, pattern: &str) -> Result<(), PubSubError> {
+        check_topic(pattern, true)?;
+        let mut msg = Vec::with_capacity(1 + pattern.len());
+        msg.push(kind);
+        msg.extend_from_slice(pattern.as_bytes());
+        self.sock.sendmsg(&msg)?;
+        Ok(())
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&self) -> Result<Message, PubSubError> {
+        let mut buf = self.buf.lock().unwrap();
+        buf.resize(MAX_MESSAGE_SIZE + 1, 0);
+        let len = self.sock.recvmsg(&mut buf)?;
+        if len == buf.len() {
+            return Err(PubSubError::TooLarge {
+                len,
+                max: MAX_MESSAGE_SIZE,
+            });
+        }
+        if buf.first() != Some(&MESSAGE) {
+            return Err(PubSubError::Protocol("unexpected message kind"));
+        }
+        let (_, topic, payload) = decode_message(&buf[..len])?;
+        Ok(Message {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Returns the underlying socket, e.g. for use with `getsockopt` or `Epoll`.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Closes the connection.
+    pub fn close(self) -> Result<(), UdtError> {
+        self.sock.close()
+    }
+}
+
+#[test]
+fn test_topic_patterns() {
+    assert!(check_topic("quotes.nyse.IBM", false).is_ok());
+    assert!(check_topic("quotes.*.IBM", false).is_err());
+    assert!(check_topic("quotes..IBM", false).is_err());
+    assert!(check_topic("", true).is_err());
+    assert!(check_topic("quotes.>.IBM", true).is_err());
+    assert!(check_topic("quotes.*.>", true).is_ok());
+
+    assert!(matches("quotes.nyse.IBM", "quotes.nyse.IBM"));
+    assert!(matches("quotes.*.IBM", "quotes.nyse.IBM"));
+    assert!(!matches("quotes.*", "quotes.nyse.IBM"));
+    assert!(matches("quotes.>", "quotes.nyse.IBM"));
+    assert!(!matches("quotes.>", "quotes"));
+    assert!(!matches("quotes.nyse.IBM", "quotes.nyse"));
+    assert!(matches(">", "quotes"));
+
+    let msg = encode_message(MESSAGE, Some(Duration::from_millis(250)), "a.b", b"xyz");
+    let (ttl, topic, payload) = decode_message(&msg).unwrap();
+    assert_eq!(ttl, Some(Duration::from_millis(250)));
+    assert_eq!((topic, payload), ("a.b", &b"xyz"[..]));
+    assert!(decode_message(&msg[..8]).is_err());
+}
@@ -0,0 +1,994 @@
+//! Streams that survive connection loss by reconnecting and resuming
+//!
+//! When a link fails, a UDT socket goes to the `BROKEN` state and whatever was in flight is
+//! lost.  A `ResilientStream` numbers every byte the application writes and keeps it buffered
+//! until the peer acknowledges it.  If the connection breaks, the client side reconnects with
+//! exponential backoff, the two sides exchange how much they have received, and each resends
+//! exactly what the other is missing.  The application only sees a stall.
+//!
+//! The server side is a `ResilientListener`, which tells new sessions apart from returning ones
+//! and hands reconnections to the `ResilientStream` they belong to.  A session gives up, and
+//! its reads and writes fail, if it can't be resumed within the reconnect timeout.
+//!
+//! Data is acknowledged once it is in the receiving stream's buffer, not when the application
+//! reads it, so at most `max_unacked` bytes are held for resending.  Since the peer can't tell an
+//! orderly close from a broken connection, a `ResilientStream` must be finished with
+//! [`close`](struct.ResilientStream.html#method.close); just dropping it makes the peer try to
+//! resume until the reconnect timeout expires.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::Write;
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::resilient::ResilientConfig;
+//!
+//! init();
+//! let mut stream = ResilientConfig::new()
+//!     .backoff(Duration::from_millis(100), Duration::from_secs(5))
+//!     .reconnect_timeout(Duration::from_secs(300))
+//!     .connect("192.0.2.10:9000".parse().unwrap())
+//!     .unwrap();
+//!
+//! for record in vec![vec![0u8; 4096]; 1000] {
+//!     stream.write_all(&record).unwrap();
+//! }
+//! stream.close().unwrap();
+//! ```
+//!
+//! # Wire format
+//!
+//! A connection starts with each side sending a 20 byte handshake: the magic `URS1`, the session
+//! id and the number of bytes received so far in this session, both as 64-bit big-endian
+//! integers.  The client sends session id 0 to start a new session.  The server replies with the
+//! session id, or 0 if the session can't be resumed.
+//!
+//! After that, every frame is a type byte, the payload length as a 32-bit big-endian integer,
+//! and the payload:
+//!
+//! * `DATA` (0): up to 64 KiB of application data
+//! * `ACK` (1): the number of bytes received in this session, as a 64-bit integer
+//! * `FIN` (2): the sender closed the stream; the payload is the total number of bytes it sent
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::raw;
+use crate::stream::Transport;
+use crate::{UdtListener, UdtStream};
+
+const MAGIC: &[u8; 4] = b"URS1";
+const HANDSHAKE_LEN: usize = 20;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// how often `accept` checks for sessions whose handshake finished
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const FIN: u8 = 2;
+const HEADER_LEN: usize = 5;
+const MAX_DATA: usize = 64 * 1024;
+// acknowledge at least this often, in bytes received
+const ACK_EVERY: u64 = 64 * 1024;
+
+type Dialer = Box<dyn Fn() -> io::Result<Arc<dyn Transport>> + Send + Sync>;
+
+fn read_exact(link: &dyn Transport, buf: &mut [u8]) -> io::Result<bool> {
+    let mut done = 0;
+    while done < buf.len() {
+        match link.read(&mut buf[done..]) {
+            Ok(0) if done == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn write_handshake(link: &dyn Transport, session: u64, received: u64) -> io::Result<()> {
+    let mut msg = [0u8; HANDSHAKE_LEN];
+    msg[..4].copy_from_slice(MAGIC);
+    msg[4..12].copy_from_slice(&session.to_be_bytes());
+    msg[12..].copy_from_slice(&received.to_be_bytes());
+    link.write_all(&msg)
+}
+
+// returns the session id and the number of bytes the peer has received
+fn read_handshake(link: &dyn Transport) -> io::Result<(u64, u64)> {
+    let mut msg = [0u8; HANDSHAKE_LEN];
+    link.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    if !read_exact(link, &mut msg)? {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    link.set_read_timeout(None)?;
+    if &msg[..4] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a resilient stream",
+        ));
+    }
+    let mut session = [0u8; 8];
+    let mut received = [0u8; 8];
+    session.copy_from_slice(&msg[4..12]);
+    received.copy_from_slice(&msg[12..]);
+    Ok((u64::from_be_bytes(session), u64::from_be_bytes(received)))
+}
+
+fn new_session_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    loop {
+        let mut h = RandomState::new().build_hasher();
+        h.write_u64(nanos);
+        let id = h.finish();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+fn rejected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        "the server doesn't know this session",
+    )
+}
+
+/// Settings for a `ResilientStream`
+#[derive(Debug, Clone)]
+pub struct ResilientConfig {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    reconnect_timeout: Duration,
+    max_unacked: usize,
+    recv_buffer: usize,
+}
+
+impl Default for ResilientConfig {
+    fn default() -> ResilientConfig {
+        ResilientConfig::new()
+    }
+}
+
+impl ResilientConfig {
+    /// Creates the default configuration: reconnect attempts start 100ms apart and back off to
+    /// 5 seconds, a session is abandoned after 60 seconds without a connection, and up to 4 MiB
+    /// each of unacknowledged and unread data are buffered.
+    pub fn new() -> ResilientConfig {
+        ResilientConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            reconnect_timeout: Duration::from_secs(60),
+            max_unacked: 4 * 1024 * 1024,
+            recv_buffer: 4 * 1024 * 1024,
+        }
+    }
+
+    /// Sets the delay before the first reconnect attempt, which doubles after each failed
+    /// attempt up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ResilientConfig {
+        self.initial_backoff = initial;
+        self.max_backoff = std::cmp::max(initial, max);
+        self
+    }
+
+    /// Sets how long a session may go without a connection before it fails.
+    ///
+    /// The server side should use at least the same timeout as its clients.
+    pub fn reconnect_timeout(mut self, timeout: Duration) -> ResilientConfig {
+        self.reconnect_timeout = timeout;
+        self
+    }
+
+    /// Sets how much written data may wait for an acknowledgement before writes block.
+    ///
+    /// The receiver only acknowledges every 64 KiB, so this is at least 128 KiB.
+    pub fn max_unacked(mut self, bytes: usize) -> ResilientConfig {
+        self.max_unacked = std::cmp::max(bytes, 2 * ACK_EVERY as usize);
+        self
+    }
+
+    /// Sets how much received data is buffered before the peer has to wait for the application
+    /// to read.
+    pub fn recv_buffer(mut self, bytes: usize) -> ResilientConfig {
+        self.recv_buffer = std::cmp::max(bytes, 1);
+        self
+    }
+
+    /// Opens a new session to a `ResilientListener`.
+    ///
+    /// `init()` must have been called first.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<ResilientStream> {
+        self.dial(Box::new(move || {
+            let link: Arc<dyn Transport> = Arc::new(UdtStream::connect(addr)?);
+            Ok(link)
+        }))
+    }
+
+    fn dial(&self, dialer: Dialer) -> io::Result<ResilientStream> {
+        let link = dialer()?;
+        write_handshake(&*link, 0, 0)?;
+        let (session, _) = read_handshake(&*link)?;
+        if session == 0 {
+            return Err(rejected());
+        }
+        let shared = Shared::new(self.clone(), session, Some(dialer), link);
+        Ok(ResilientStream::start(shared))
+    }
+
+    /// Accepts resilient sessions on `listener`.
+    pub fn listen(&self, listener: UdtListener) -> ResilientListener {
+        let (tx, rx) = mpsc::channel();
+        ResilientListener {
+            listener,
+            sessions: Arc::new(Sessions {
+                config: self.clone(),
+                map: Mutex::new(HashMap::new()),
+            }),
+            new_tx: Mutex::new(tx),
+            new_rx: Mutex::new(rx),
+        }
+    }
+}
+
+struct State {
+    link: Option<Arc<dyn Transport>>,
+    // set by a server side reader thread while it waits to be handed a new connection
+    waiting: bool,
+    send_buf: VecDeque<u8>,
+    // the stream offset of the first byte in `send_buf`
+    send_base: u64,
+    recv_buf: VecDeque<u8>,
+    received: u64,
+    acked: u64,
+    fin_sent: bool,
+    peer_closed: bool,
+    closed: bool,
+    failed: Option<io::ErrorKind>,
+    reconnects: u64,
+}
+
+struct Shared {
+    config: ResilientConfig,
+    session: u64,
+    // only the client side reconnects
+    dialer: Option<Dialer>,
+    // keeps the frames of concurrent writers apart
+    writer: Mutex<()>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn new(
+        config: ResilientConfig,
+        session: u64,
+        dialer: Option<Dialer>,
+        link: Arc<dyn Transport>,
+    ) -> Arc<Shared> {
+        Arc::new(Shared {
+            config,
+            session,
+            dialer,
+            writer: Mutex::new(()),
+            state: Mutex::new(State {
+                link: Some(link),
+                waiting: false,
+                send_buf: VecDeque::new(),
+                send_base: 0,
+                recv_buf: VecDeque::new(),
+                received: 0,
+                acked: 0,
+                fin_sent: false,
+                peer_closed: false,
+                closed: false,
+                failed: None,
+                reconnects: 0,
+            }),
+            cond: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn fail(&self, state: &mut State, kind: io::ErrorKind) {
+        if state.failed.is_none() {
+            warn!("resilient session {:x} failed: {:?}", self.session, kind);
+            state.failed = Some(kind);
+        }
+        if let Some(link) = state.link.take() {
+            link.shutdown();
+        }
+        self.cond.notify_all();
+    }
+
+    fn failed_err(kind: io::ErrorKind) -> io::Error {
+        io::Error::new(kind, "resilient stream failed")
+    }
+
+    // the writer lock must be held
+    fn send_frame(link: &dyn Transport, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        link.write_all(&frame)
+    }
+
+    fn send_ack(&self, link: &dyn Transport) -> io::Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let received = self.lock().received;
+        Shared::send_frame(link, ACK, &received.to_be_bytes())?;
+        self.lock().acked = received;
+        Ok(())
+    }
+
+    // Makes `link` the current connection, after the peer said it has received `peer_received`
+    // bytes, and resends everything after that.
+    fn attach(&self, link: Arc<dyn Transport>, peer_received: u64) -> io::Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut state = self.lock();
+        if let Some(kind) = state.failed {
+            return Err(Shared::failed_err(kind));
+        }
+        let end = state.send_base + state.send_buf.len() as u64;
+        if peer_received < state.send_base || peer_received > end {
+            let kind = io::ErrorKind::InvalidData;
+            self.fail(&mut state, kind);
+            return Err(io::Error::new(kind, "peer resumed at an invalid offset"));
+        }
+        let acked = (peer_received - state.send_base) as usize;
+        state.send_buf.drain(..acked);
+        state.send_base = peer_received;
+        state.link = Some(link.clone());
+        state.waiting = false;
+        state.reconnects += 1;
+        let pending: Vec<u8> = state.send_buf.iter().copied().collect();
+        let fin = if state.fin_sent { Some(end) } else { None };
+        self.cond.notify_all();
+        drop(state);
+
+        debug!(
+            "resuming session {:x}, resending {} bytes",
+            self.session,
+            pending.len()
+        );
+        let res = pending
+            .chunks(MAX_DATA)
+            .try_for_each(|chunk| Shared::send_frame(&*link, DATA, chunk))
+            .and_then(|_| match fin {
+                Some(end) => Shared::send_frame(&*link, FIN, &end.to_be_bytes()),
+                None => Ok(()),
+            });
+        if let Err(e) = res {
+            // the reader thread notices and tries again
+            debug!("resending failed: {}", e);
+            link.shutdown();
+        }
+        Ok(())
+    }
+
+    fn read_loop(&self) {
+        loop {
+            let link = {
+                let state = self.lock();
+                if state.closed || state.peer_closed || state.failed.is_some() {
+                    return;
+                }
+                match state.link {
+                    Some(ref link) => link.clone(),
+                    None => return,
+                }
+            };
+            let res = self.read_frames(&link);
+
+            let mut state = self.lock();
+            if state.closed || state.peer_closed || state.failed.is_some() {
+                self.cond.notify_all();
+                return;
+            }
+            let err = match res {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            if err.kind() == io::ErrorKind::InvalidData || !link.is_broken() {
+                debug!("resilient session {:x}: {}", self.session, err);
+                self.fail(&mut state, err.kind());
+                return;
+            }
+            debug!(
+                "resilient session {:x} lost its connection: {}",
+                self.session, err
+            );
+            if state.link.as_ref().is_some_and(|l| Arc::ptr_eq(l, &link)) {
+                state.link = None;
+            }
+            drop(state);
+            link.shutdown();
+
+            let resumed = match self.dialer {
+                Some(ref dialer) => self.reconnect(dialer),
+                None => self.wait_for_reconnect(),
+            };
+            if !resumed {
+                return;
+            }
+        }
+    }
+
+    // returns once the peer closed the stream
+    fn read_frames(&self, link: &Arc<dyn Transport>) -> io::Result<()> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut payload = vec![0u8; MAX_DATA];
+        loop {
+            if !read_exact(&**link, &mut header)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection lost",
+                ));
+            }
+            let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            let valid = match header[0] {
+                DATA => len <= MAX_DATA,
+                ACK | FIN => len == 8,
+                _ => false,
+            };
+            if !valid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed resilient stream frame",
+                ));
+            }
+            if !read_exact(&**link, &mut payload[..len])? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            // the payload of an ACK or FIN
+            let mut offset = [0u8; 8];
+            if len == 8 {
+                offset.copy_from_slice(&payload[..8]);
+            }
+            let offset = u64::from_be_bytes(offset);
+
+            let mut state = self.lock();
+            match header[0] {
+                DATA => {
+                    state.recv_buf.extend(&payload[..len]);
+                    state.received += len as u64;
+                    self.cond.notify_all();
+                    if state.received - state.acked >= ACK_EVERY {
+                        drop(state);
+                        self.send_ack(&**link)?;
+                        state = self.lock();
+                    }
+                    while state.recv_buf.len() >= self.config.recv_buffer {
+                        if state.closed {
+                            return Ok(());
+                        }
+                        // a resuming client must not have to wait for the application to read
+                        if !state.link.as_ref().is_some_and(|l| Arc::ptr_eq(l, link)) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "connection replaced",
+                            ));
+                        }
+                        state = self.cond.wait(state).unwrap();
+                    }
+                }
+                ACK => {
+                    let end = state.send_base + state.send_buf.len() as u64;
+                    if offset < state.send_base || offset > end {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "acknowledgement for data that wasn't sent",
+                        ));
+                    }
+                    let acked = (offset - state.send_base) as usize;
+                    state.send_buf.drain(..acked);
+                    state.send_base = offset;
+                    self.cond.notify_all();
+                }
+                _ => {
+                    if offset != state.received {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "stream closed at the wrong offset",
+                        ));
+                    }
+                    state.peer_closed = true;
+                    self.cond.notify_all();
+                    drop(state);
+                    // the peer waits for this acknowledgement before it closes the connection
+                    let _ = self.send_ack(&**link);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // client side: returns true once the session has been resumed
+    fn reconnect(&self, dialer: &Dialer) -> bool {
+        let deadline = Instant::now() + self.config.reconnect_timeout;
+        let mut delay = self.config.initial_backoff;
+        loop {
+            let received = {
+                let state = self.lock();
+                if state.closed {
+                    return false;
+                }
+                state.received
+            };
+            let res = dialer().and_then(|link| {
+                write_handshake(&*link, self.session, received)?;
+                let (session, peer_received) = read_handshake(&*link)?;
+                if session != self.session {
+                    return Err(rejected());
+                }
+                self.attach(link, peer_received)
+            });
+            match res {
+                Ok(()) => return true,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    self.fail(&mut self.lock(), e.kind());
+                    return false;
+                }
+                Err(e) => debug!("reconnecting session {:x} failed: {}", self.session, e),
+            }
+
+            let mut state = self.lock();
+            if state.failed.is_some() {
+                return false;
+            }
+            let now = Instant::now();
+            if now + delay > deadline {
+                self.fail(&mut state, io::ErrorKind::TimedOut);
+                return false;
+            }
+            let wake = now + delay;
+            while !state.closed && Instant::now() < wake {
+                state = self
+                    .cond
+                    .wait_timeout(state, wake - Instant::now())
+                    .unwrap()
+                    .0;
+            }
+            delay = std::cmp::min(delay * 2, self.config.max_backoff);
+        }
+    }
+
+    // server side: returns true once the listener handed over a new connection
+    fn wait_for_reconnect(&self) -> bool {
+        let deadline = Instant::now() + self.config.reconnect_timeout;
+        let mut state = self.lock();
+        state.waiting = true;
+        self.cond.notify_all();
+        loop {
+            if state.closed || state.failed.is_some() {
+                return false;
+            }
+            if state.link.is_some() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting = false;
+                self.fail(&mut state, io::ErrorKind::TimedOut);
+                return false;
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    // server side: stops using the current connection and waits until the reader thread is
+    // ready for a new one
+    fn detach(&self) -> io::Result<u64> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let mut state = self.lock();
+        if let Some(link) = state.link.take() {
+            link.shutdown();
+        }
+        loop {
+            if let Some(kind) = state.failed {
+                return Err(Shared::failed_err(kind));
+            }
+            if state.closed || state.peer_closed {
+                return Err(rejected());
+            }
+            if state.waiting {
+                return Ok(state.received);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+/// A stream that reconnects and resumes after the connection is lost
+///
+/// See the [module documentation](index.html).
+pub struct ResilientStream {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for ResilientStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResilientStream")
+            .field("session", &self.shared.session)
+            .finish()
+    }
+}
+
+impl ResilientStream {
+    fn start(shared: Arc<Shared>) -> ResilientStream {
+        let reader = shared.clone();
+        thread::spawn(move || reader.read_loop());
+        ResilientStream { shared }
+    }
+
+    /// Returns the id the server assigned to this session.
+    pub fn session_id(&self) -> u64 {
+        self.shared.session
+    }
+
+    /// Returns how often the session was resumed on a new connection.
+    pub fn reconnects(&self) -> u64 {
+        self.shared.lock().reconnects
+    }
+
+    /// Returns the number of written bytes the peer hasn't acknowledged yet.
+    pub fn unacked(&self) -> usize {
+        self.shared.lock().send_buf.len()
+    }
+
+    /// Closes the stream, after waiting until the peer has received everything written to it.
+    ///
+    /// This may have to wait for a reconnect, and fails if the session can't be resumed.
+    pub fn close(self) -> io::Result<()> {
+        let shared = &self.shared;
+        {
+            let _writer = shared.writer.lock().unwrap();
+            let mut state = shared.lock();
+            if let Some(kind) = state.failed {
+                return Err(Shared::failed_err(kind));
+            }
+            state.fin_sent = true;
+            let end = state.send_base + state.send_buf.len() as u64;
+            if let Some(link) = state.link.clone() {
+                drop(state);
+                if Shared::send_frame(&*link, FIN, &end.to_be_bytes()).is_err() {
+                    link.shutdown();
+                }
+            }
+        }
+        let mut state = shared.lock();
+        loop {
+            if let Some(kind) = state.failed {
+                return Err(Shared::failed_err(kind));
+            }
+            if state.send_buf.is_empty() || state.peer_closed {
+                return Ok(());
+            }
+            state = shared.cond.wait(state).unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    fn break_connection(&self) {
+        if let Some(ref link) = self.shared.lock().link {
+            link.shutdown();
+        }
+    }
+}
+
+impl Drop for ResilientStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.closed = true;
+        if let Some(link) = state.link.take() {
+            link.shutdown();
+        }
+        self.shared.cond.notify_all();
+    }
+}
+
+impl Read for &ResilientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        loop {
+            if !state.recv_buf.is_empty() {
+                let n = std::cmp::min(buf.len(), state.recv_buf.len());
+                for (dst, src) in buf.iter_mut().zip(state.recv_buf.drain(..n)) {
+                    *dst = src;
+                }
+                shared.cond.notify_all();
+                return Ok(n);
+            }
+            if state.peer_closed || buf.is_empty() {
+                return Ok(0);
+            }
+            if let Some(kind) = state.failed {
+                return Err(Shared::failed_err(kind));
+            }
+            state = shared.cond.wait(state).unwrap();
+        }
+    }
+}
+
+impl Write for &ResilientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shared = &self.shared;
+        let check = |state: &State| match state.failed {
+            Some(kind) => Err(Shared::failed_err(kind)),
+            None if state.peer_closed || state.fin_sent => Err(io::ErrorKind::BrokenPipe.into()),
+            None => Ok(()),
+        };
+        {
+            let mut state = shared.lock();
+            loop {
+                check(&state)?;
+                if state.send_buf.len() < shared.config.max_unacked {
+                    break;
+                }
+                state = shared.cond.wait(state).unwrap();
+            }
+        }
+
+        let _writer = shared.writer.lock().unwrap();
+        let mut state = shared.lock();
+        check(&state)?;
+        let n = std::cmp::min(buf.len(), MAX_DATA);
+        state.send_buf.extend(&buf[..n]);
+        let link = state.link.clone();
+        drop(state);
+        // if this fails, the data is resent once the session is resumed
+        if let Some(link) = link {
+            if let Err(e) = Shared::send_frame(&*link, DATA, &buf[..n]) {
+                debug!("resilient session {:x}: {}", shared.session, e);
+                link.shutdown();
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ResilientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for ResilientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// The sessions of a `ResilientListener`
+struct Sessions {
+    config: ResilientConfig,
+    map: Mutex<HashMap<u64, Weak<Shared>>>,
+}
+
+impl Sessions {
+    // returns the stream of a new session, or None if an existing one was resumed
+    fn handshake(&self, link: Arc<dyn Transport>) -> io::Result<Option<ResilientStream>> {
+        let (session, peer_received) = read_handshake(&*link)?;
+        let mut map = self.map.lock().unwrap();
+        map.retain(|_, s| s.strong_count() > 0);
+        if session == 0 {
+            let mut session = new_session_id();
+            while map.contains_key(&session) {
+                session = new_session_id();
+            }
+            write_handshake(&*link, session, 0)?;
+            let shared = Shared::new(self.config.clone(), session, None, link);
+            map.insert(session, Arc::downgrade(&shared));
+            return Ok(Some(ResilientStream::start(shared)));
+        }
+
+        let shared = map.get(&session).and_then(Weak::upgrade);
+        drop(map);
+        let received = match shared.as_ref().map(|s| s.detach()) {
+            Some(Ok(received)) => received,
+            _ => {
+                let _ = write_handshake(&*link, 0, 0);
+                return Err(rejected());
+            }
+        };
+        let shared = shared.unwrap();
+        write_handshake(&*link, session, received)?;
+        shared.attach(link, peer_received)?;
+        Ok(None)
+    }
+}
+
+/// Accepts `ResilientStream` sessions, and reconnects them when their clients return
+pub struct ResilientListener {
+    listener: UdtListener,
+    sessions: Arc<Sessions>,
+    // new sessions from the handshake threads
+    new_tx: Mutex<Sender<ResilientStream>>,
+    new_rx: Mutex<Receiver<ResilientStream>>,
+}
+
+impl fmt::Debug for ResilientListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResilientListener")
+            .field("listener", &self.listener)
+            .finish()
+    }
+}
+
+impl ResilientListener {
+    /// Returns the address this listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Returns the underlying listener, e.g. to get a `ListenerCloser`.
+    pub fn listener(&self) -> &UdtListener {
+        &self.listener
+    }
+
+    /// Waits for a new session.
+    ///
+    /// Connections that resume an existing session are handed over to its `ResilientStream`
+    /// while waiting, so this must be called continually, e.g. from a dedicated thread, for
+    /// sessions to survive reconnects.  Each connection's handshake runs on a thread of its
+    /// own, so a slow or silent client doesn't hold up the others.
+    pub fn accept(&self) -> io::Result<ResilientStream> {
+        let new = self.new_rx.lock().unwrap();
+        loop {
+            if let Ok(stream) = new.try_recv() {
+                return Ok(stream);
+            }
+            let (stream, addr) = match self.listener.accept_timeout(ACCEPT_POLL_INTERVAL) {
+                Ok(conn) => conn,
+                Err(ref e) if e.err_code == raw::ETIMEOUT => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let sessions = self.sessions.clone();
+            let tx = self.new_tx.lock().unwrap().clone();
+            thread::spawn(move || match sessions.handshake(Arc::new(stream)) {
+                Ok(Some(stream)) => {
+                    let _ = tx.send(stream);
+                }
+                Ok(None) => debug!("resumed a session from {}", addr),
+                Err(e) => debug!("handshake with {} failed: {}", addr, e),
+            });
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+fn test_session(
+    config: &ResilientConfig,
+) -> (ResilientStream, ResilientStream, thread::JoinHandle<()>) {
+    use std::os::unix::net::UnixStream;
+
+    let (tx, rx) = mpsc::channel::<UnixStream>();
+    let (new_tx, new_rx) = mpsc::channel();
+    let sessions = Sessions {
+        config: config.clone(),
+        map: Mutex::new(HashMap::new()),
+    };
+    let server = thread::spawn(move || {
+        for conn in rx {
+            if let Ok(Some(stream)) = sessions.handshake(Arc::new(conn)) {
+                new_tx.send(stream).unwrap();
+            }
+        }
+    });
+    let tx = Mutex::new(tx);
+    let client = config
+        .dial(Box::new(move || {
+            let (a, b) = UnixStream::pair()?;
+            tx.lock()
+                .unwrap()
+                .send(b)
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            let link: Arc<dyn Transport> = Arc::new(a);
+            Ok(link)
+        }))
+        .unwrap();
+    (client, new_rx.recv().unwrap(), server)
+}
+
+#[test]
+#[cfg(unix)]
+fn test_resume_after_breaks() {
+    let config = ResilientConfig::new()
+        .backoff(Duration::from_millis(1), Duration::from_millis(20))
+        .max_unacked(200_000)
+        .recv_buffer(100_000);
+    let (client, mut server, _) = test_session(&config);
+    let client = Arc::new(client);
+
+    let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let writer = {
+        let client = client.clone();
+        thread::spawn(move || {
+            let mut c = &*client;
+            for chunk in data.chunks(10_000) {
+                c.write_all(chunk).unwrap();
+            }
+        })
+    };
+
+    let mut got = vec![0u8; expected.len()];
+    let mut done = 0;
+    let mut breaks = 0;
+    while done < got.len() {
+        let n = server.read(&mut got[done..]).unwrap();
+        assert!(n > 0);
+        done += n;
+        if done > (breaks + 1) * 500_000 {
+            // alternately break it from either side
+            if breaks % 2 == 0 {
+                client.break_connection();
+            } else {
+                server.break_connection();
+            }
+            breaks += 1;
+        }
+    }
+    assert!(got == expected);
+    writer.join().unwrap();
+    assert!(client.reconnects() >= 1);
+
+    server.write_all(b"done").unwrap();
+    let mut reply = [0u8; 4];
+    (&*client).read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"done");
+
+    Arc::try_unwrap(client).unwrap().close().unwrap();
+    assert_eq!(server.read(&mut [0u8; 16]).unwrap(), 0);
+    assert!(server.write(b"late").is_err());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_resume_gives_up() {
+    let config = ResilientConfig::new()
+        .backoff(Duration::from_millis(1), Duration::from_millis(5))
+        .reconnect_timeout(Duration::from_millis(200));
+    let (mut client, server, accept) = test_session(&config);
+    client.write_all(b"hello").unwrap();
+
+    // the server side goes away for good, so the client can't resume
+    drop(server);
+    let err = client.read(&mut [0u8; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(client.write(b"more").is_err());
+    drop(client);
+    accept.join().unwrap();
+}
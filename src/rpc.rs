@@ -0,0 +1,703 @@
+//! Request/response RPC over a `Datagram` connection
+//!
+//! A [`Client`](struct.Client.html) sends requests to a [`Server`](struct.Server.html), which
+//! dispatches them to handlers registered by method name.  Every request carries a correlation
+//! id, so any number of calls can be in flight over one connection, and their responses may
+//! arrive in any order.
+//!
+//! Each call has a deadline.  When it passes, or when the caller cancels the call, the client
+//! stops waiting and tells the server, which skips the request if it hasn't started yet and
+//! otherwise lets the handler notice through [`Request::is_cancelled`](struct.Request.html#method.is_cancelled).
+//!
+//! Messages are sent with a [`FragmentSocket`](../fragment/struct.FragmentSocket.html), so
+//! payloads may be larger than the UDT send buffer.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::fragment::FragmentConfig;
+//! use udt::rpc::{Client, Server};
+//!
+//! init();
+//! let listener = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+//! listener.bind("0.0.0.0:7000".parse().unwrap()).unwrap();
+//! listener.listen(16).unwrap();
+//!
+//! let server = Server::new()
+//!     .register("echo", |req| Ok(req.payload().to_vec()))
+//!     .register("uptime", |_| Ok(b"42".to_vec()));
+//! std::thread::spawn(move || loop {
+//!     let (sock, _) = listener.accept().unwrap();
+//!     let server = server.clone();
+//!     std::thread::spawn(move || server.serve(FragmentConfig::new().wrap(sock)));
+//! });
+//!
+//! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+//! sock.connect("127.0.0.1:7000".parse().unwrap()).unwrap();
+//! let client = Client::new(FragmentConfig::new().wrap(sock));
+//! let reply = client.call("echo", b"ping", Duration::from_secs(1)).unwrap();
+//! assert_eq!(reply, b"ping");
+//! ```
+//!
+//! # Wire format
+//!
+//! Every message starts with a type byte and the 64-bit big-endian correlation id:
+//!
+//! * request: the time left until the deadline in milliseconds (32-bit), the method name
+//!   (length-prefixed with one byte) and the payload
+//! * response: the payload
+//! * error: an error code byte and a UTF-8 message
+//! * cancel: nothing else
+
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fragment::{FragmentError, FragmentSocket};
+use crate::raw;
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const ERROR: u8 = 2;
+const CANCEL: u8 = 3;
+
+const ERR_NO_METHOD: u8 = 1;
+const ERR_HANDLER: u8 = 2;
+
+/// Errors from an RPC call
+#[derive(Debug)]
+pub enum RpcError {
+    /// The deadline passed before a response arrived
+    Timeout,
+    /// The server has no handler for the method
+    NoSuchMethod(String),
+    /// The handler returned an error, or panicked
+    Remote(String),
+    /// The connection was closed
+    Closed,
+    /// The peer sent a malformed message
+    Protocol(&'static str),
+    /// The underlying socket failed
+    Transport(FragmentError),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RpcError::Timeout => write!(f, "call timed out"),
+            RpcError::NoSuchMethod(ref m) => write!(f, "no such method: {}", m),
+            RpcError::Remote(ref msg) => write!(f, "remote error: {}", msg),
+            RpcError::Closed => write!(f, "connection closed"),
+            RpcError::Protocol(msg) => write!(f, "RPC protocol error: {}", msg),
+            RpcError::Transport(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            RpcError::Transport(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The connection messages are exchanged over, shared between the reader thread and callers
+trait Link: Send + Sync {
+    fn send(&self, msg: &[u8]) -> Result<(), RpcError>;
+    // returns None once the connection is closed
+    fn recv(&self) -> Result<Option<Vec<u8>>, RpcError>;
+    // must wake up a blocked `recv`
+    fn shutdown(&self);
+}
+
+impl Link for FragmentSocket {
+    fn send(&self, msg: &[u8]) -> Result<(), RpcError> {
+        FragmentSocket::send(self, msg).map_err(RpcError::Transport)
+    }
+
+    fn recv(&self) -> Result<Option<Vec<u8>>, RpcError> {
+        loop {
+            match FragmentSocket::recv(self) {
+                Ok(msg) => return Ok(Some(msg)),
+                Err(FragmentError::BadFragment(why)) => debug!("ignoring bad fragment: {}", why),
+                Err(FragmentError::Udt(ref e))
+                    if e.err_code == raw::ECONNLOST
+                        || e.err_code == raw::ENOCONN
+                        || e.err_code == raw::EINVSOCK =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(RpcError::Transport(e)),
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        let _ = self.close_shared();
+    }
+}
+
+#[cfg(all(test, unix))]
+impl Link for std::os::unix::net::UnixDatagram {
+    fn send(&self, msg: &[u8]) -> Result<(), RpcError> {
+        std::os::unix::net::UnixDatagram::send(self, msg)
+            .map(|_| ())
+            .map_err(|_| RpcError::Closed)
+    }
+
+    fn recv(&self) -> Result<Option<Vec<u8>>, RpcError> {
+        let mut buf = vec![0u8; 65536];
+        match std::os::unix::net::UnixDatagram::recv(self, &mut buf) {
+            Ok(0) | Err(_) => Ok(None),
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    // datagram socket pairs don't pass a shutdown on to the peer, so send an empty message
+    fn shutdown(&self) {
+        let _ = std::os::unix::net::UnixDatagram::send(self, &[]);
+        let _ = std::os::unix::net::UnixDatagram::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+fn header(kind: u8, id: u64, capacity: usize) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(9 + capacity);
+    msg.push(kind);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg
+}
+
+fn parse_header(msg: &[u8]) -> Result<(u8, u64, &[u8]), RpcError> {
+    if msg.len() < 9 {
+        return Err(RpcError::Protocol("message too short"));
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&msg[1..9]);
+    Ok((msg[0], u64::from_be_bytes(id), &msg[9..]))
+}
+
+fn millis(d: Duration) -> u32 {
+    std::cmp::min(d.as_millis(), u128::from(u32::MAX)) as u32
+}
+
+type Reply = Result<Vec<u8>, RpcError>;
+
+struct ClientShared {
+    link: Arc<dyn Link>,
+    next_id: AtomicU64,
+    // `None` once the connection has been closed
+    pending: Mutex<Option<HashMap<u64, SyncSender<Reply>>>>,
+}
+
+impl ClientShared {
+    fn read_loop(&self) -> Result<(), RpcError> {
+        while let Some(msg) = self.link.recv()? {
+            let (kind, id, body) = parse_header(&msg)?;
+            let reply = match kind {
+                RESPONSE => Ok(body.to_vec()),
+                ERROR if !body.is_empty() => {
+                    let text = String::from_utf8_lossy(&body[1..]).into_owned();
+                    match body[0] {
+                        ERR_NO_METHOD => Err(RpcError::NoSuchMethod(text)),
+                        _ => Err(RpcError::Remote(text)),
+                    }
+                }
+                _ => return Err(RpcError::Protocol("unexpected message type")),
+            };
+            let waiter = match *self.pending.lock().unwrap() {
+                Some(ref mut pending) => pending.remove(&id),
+                None => None,
+            };
+            match waiter {
+                // the buffer has room for exactly this reply
+                Some(tx) => {
+                    let _ = tx.try_send(reply);
+                }
+                None => trace!("dropping reply to call {}, which is no longer pending", id),
+            }
+        }
+        Ok(())
+    }
+
+    fn cancel(&self, id: u64) {
+        let was_pending = match *self.pending.lock().unwrap() {
+            Some(ref mut pending) => pending.remove(&id).is_some(),
+            None => false,
+        };
+        if was_pending {
+            let _ = self.link.send(&header(CANCEL, id, 0));
+        }
+    }
+}
+
+/// The calling side of an RPC connection
+///
+/// A `Client` can be shared between threads (e.g. in an `Arc`); calls from different threads
+/// run concurrently.  Dropping it closes the connection.
+pub struct Client {
+    shared: Arc<ClientShared>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending = self.shared.pending.lock().unwrap();
+        f.debug_struct("Client")
+            .field("pending", &pending.as_ref().map(|p| p.len()))
+            .finish()
+    }
+}
+
+impl Client {
+    /// Starts a client on a connected socket.
+    ///
+    /// This spawns a thread that receives the responses.
+    pub fn new(sock: FragmentSocket) -> Client {
+        Client::start(Arc::new(sock))
+    }
+
+    fn start(link: Arc<dyn Link>) -> Client {
+        let shared = Arc::new(ClientShared {
+            link,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(Some(HashMap::new())),
+        });
+        let reader = shared.clone();
+        thread::spawn(move || {
+            if let Err(e) = reader.read_loop() {
+                debug!("RPC client connection failed: {}", e);
+            }
+            // wakes up every caller with `RpcError::Closed`
+            reader.pending.lock().unwrap().take();
+            reader.link.shutdown();
+        });
+        Client { shared }
+    }
+
+    /// Calls `method` and waits for the response.
+    ///
+    /// If no response arrives within `timeout`, the call is cancelled and `RpcError::Timeout`
+    /// is returned.
+    pub fn call(
+        &self,
+        method: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError> {
+        self.start_call(method, payload, timeout)?.wait()
+    }
+
+    /// Sends a request without waiting for the response.
+    ///
+    /// The returned `PendingCall` is used to wait for the response or to cancel the call.
+    pub fn start_call(
+        &self,
+        method: &str,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<PendingCall, RpcError> {
+        assert!(method.len() <= 255, "method names are limited to 255 bytes");
+        let deadline = Instant::now() + timeout;
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = mpsc::sync_channel(1);
+        match *self.shared.pending.lock().unwrap() {
+            Some(ref mut pending) => pending.insert(id, tx),
+            None => return Err(RpcError::Closed),
+        };
+
+        let mut msg = header(REQUEST, id, 5 + method.len() + payload.len());
+        msg.extend_from_slice(&millis(timeout).to_be_bytes());
+        msg.push(method.len() as u8);
+        msg.extend_from_slice(method.as_bytes());
+        msg.extend_from_slice(payload);
+        let call = PendingCall {
+            id,
+            rx,
+            deadline,
+            shared: self.shared.clone(),
+            done: false,
+        };
+        self.shared.link.send(&msg)?;
+        Ok(call)
+    }
+
+    /// Returns the number of calls waiting for a response.
+    pub fn pending_calls(&self) -> usize {
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |p| p.len())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.shared.link.shutdown();
+    }
+}
+
+/// A call that has been sent, but whose response hasn't been received yet
+///
+/// Dropping a `PendingCall` without waiting for it cancels the call.
+#[derive(Debug)]
+pub struct PendingCall {
+    id: u64,
+    rx: Receiver<Reply>,
+    deadline: Instant,
+    shared: Arc<ClientShared>,
+    done: bool,
+}
+
+impl fmt::Debug for ClientShared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientShared").finish()
+    }
+}
+
+impl PendingCall {
+    /// Returns the call's correlation id.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Waits for the response, until the call's deadline.
+    pub fn wait(mut self) -> Result<Vec<u8>, RpcError> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        let reply = match self.rx.recv_timeout(left) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => {
+                self.shared.cancel(self.id);
+                Err(RpcError::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(RpcError::Closed),
+        };
+        self.done = true;
+        reply
+    }
+
+    /// Cancels the call.  The server is told to skip it, or to stop working on it.
+    pub fn cancel(mut self) {
+        self.shared.cancel(self.id);
+        self.done = true;
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        if !self.done {
+            self.shared.cancel(self.id);
+        }
+    }
+}
+
+/// An incoming call, as seen by a handler
+#[derive(Debug)]
+pub struct Request {
+    id: u64,
+    method: String,
+    payload: Vec<u8>,
+    deadline: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Request {
+    /// Returns the called method.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Returns the request payload.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns the time after which the caller no longer waits for the response.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns true if the caller cancelled the call or its deadline has passed.
+    ///
+    /// Long-running handlers should check this and give up early; their result is discarded
+    /// anyway.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || Instant::now() >= self.deadline
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Result<Vec<u8>, String> + Send + Sync;
+
+/// The serving side of RPC connections
+///
+/// A `Server` is a set of handlers.  It is cheap to clone, so the same handlers can serve many
+/// connections, each in its own thread.
+#[derive(Clone)]
+pub struct Server {
+    handlers: Arc<HashMap<String, Box<Handler>>>,
+    workers: usize,
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut methods: Vec<&String> = self.handlers.keys().collect();
+        methods.sort();
+        f.debug_struct("Server")
+            .field("methods", &methods)
+            .field("workers", &self.workers)
+            .finish()
+    }
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    /// Creates a server without handlers, running 4 calls at a time per connection.
+    pub fn new() -> Server {
+        Server {
+            handlers: Arc::new(HashMap::new()),
+            workers: 4,
+        }
+    }
+
+    /// Registers the handler for `method`, replacing any previous one.
+    ///
+    /// Errors returned by the handler are passed to the caller as `RpcError::Remote`.
+    pub fn register<F>(mut self, method: &str, handler: F) -> Server
+    where
+        F: Fn(&Request) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        // handlers can't be cloned, so they have to be complete before the server is shared
+        Arc::get_mut(&mut self.handlers)
+            .expect("handlers must be registered before the server is cloned")
+            .insert(method.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Sets how many calls are handled at the same time on each connection.
+    pub fn workers(mut self, n: usize) -> Server {
+        assert!(n > 0, "at least one worker is needed");
+        self.workers = n;
+        self
+    }
+
+    /// Serves calls on a connected socket until the peer closes the connection.
+    pub fn serve(&self, sock: FragmentSocket) -> Result<(), RpcError> {
+        self.serve_link(Arc::new(sock))
+    }
+
+    fn serve_link(&self, link: Arc<dyn Link>) -> Result<(), RpcError> {
+        let in_flight: Arc<Mutex<HashMap<u64, Arc<AtomicBool>>>> = Default::default();
+        let (tx, rx) = mpsc::channel::<Request>();
+        let rx = Arc::new(Mutex::new(rx));
+        let workers: Vec<_> = (0..self.workers)
+            .map(|_| {
+                let rx = rx.clone();
+                let link = link.clone();
+                let handlers = self.handlers.clone();
+                let in_flight = in_flight.clone();
+                thread::spawn(move || loop {
+                    let req = match rx.lock().unwrap().recv() {
+                        Ok(req) => req,
+                        Err(_) => return,
+                    };
+                    run(&handlers, &*link, &req);
+                    in_flight.lock().unwrap().remove(&req.id);
+                })
+            })
+            .collect();
+
+        let result = (|| {
+            while let Some(msg) = link.recv()? {
+                let (kind, id, body) = parse_header(&msg)?;
+                match kind {
+                    REQUEST => {
+                        let req = parse_request(id, body)?;
+                        in_flight.lock().unwrap().insert(id, req.cancelled.clone());
+                        let _ = tx.send(req);
+                    }
+                    CANCEL => {
+                        if let Some(flag) = in_flight.lock().unwrap().get(&id) {
+                            flag.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    _ => return Err(RpcError::Protocol("unexpected message type")),
+                }
+            }
+            Ok(())
+        })();
+
+        // let the workers finish what they have started, then stop them
+        for flag in in_flight.lock().unwrap().values() {
+            flag.store(true, Ordering::SeqCst);
+        }
+        drop(tx);
+        for w in workers {
+            let _ = w.join();
+        }
+        link.shutdown();
+        result
+    }
+}
+
+fn parse_request(id: u64, body: &[u8]) -> Result<Request, RpcError> {
+    if body.len() < 5 || body.len() < 5 + body[4] as usize {
+        return Err(RpcError::Protocol("truncated request"));
+    }
+    let mut ms = [0u8; 4];
+    ms.copy_from_slice(&body[..4]);
+    let timeout = Duration::from_millis(u64::from(u32::from_be_bytes(ms)));
+    let end = 5 + body[4] as usize;
+    let method = std::str::from_utf8(&body[5..end])
+        .map_err(|_| RpcError::Protocol("method name is not UTF-8"))?;
+    Ok(Request {
+        id,
+        method: method.to_string(),
+        payload: body[end..].to_vec(),
+        deadline: Instant::now() + timeout,
+        cancelled: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+fn run(handlers: &HashMap<String, Box<Handler>>, link: &dyn Link, req: &Request) {
+    if req.is_cancelled() {
+        trace!("skipping cancelled call {}", req.id);
+        return;
+    }
+    let reply = match handlers.get(&req.method) {
+        Some(handler) => match catch_unwind(AssertUnwindSafe(|| handler(req))) {
+            Ok(Ok(payload)) => {
+                let mut msg = header(RESPONSE, req.id, payload.len());
+                msg.extend_from_slice(&payload);
+                msg
+            }
+            Ok(Err(e)) => error_msg(req.id, ERR_HANDLER, &e),
+            Err(_) => error_msg(req.id, ERR_HANDLER, "handler panicked"),
+        },
+        None => error_msg(req.id, ERR_NO_METHOD, &req.method),
+    };
+    if req.is_cancelled() {
+        return;
+    }
+    if let Err(e) = link.send(&reply) {
+        debug!("could not send reply to call {}: {}", req.id, e);
+    }
+}
+
+fn error_msg(id: u64, code: u8, text: &str) -> Vec<u8> {
+    let mut msg = header(ERROR, id, 1 + text.len());
+    msg.push(code);
+    msg.extend_from_slice(text.as_bytes());
+    msg
+}
+
+#[cfg(all(test, unix))]
+fn test_pair(server: Server) -> (Client, thread::JoinHandle<Result<(), RpcError>>) {
+    let (a, b) = std::os::unix::net::UnixDatagram::pair().unwrap();
+    let t = thread::spawn(move || server.serve_link(Arc::new(b)));
+    (Client::start(Arc::new(a)), t)
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rpc_dispatch() {
+    let server = Server::new()
+        .workers(8)
+        .register("double", |req| {
+            Ok(req.payload().iter().map(|b| b.wrapping_mul(2)).collect())
+        })
+        .register("fail", |_| Err("nope".to_string()))
+        .register("panic", |_| panic!("handler bug"));
+    let (client, server) = test_pair(server);
+    let client = Arc::new(client);
+
+    let callers: Vec<_> = (0..200u8)
+        .map(|i| {
+            let client = client.clone();
+            thread::spawn(move || {
+                let reply = client
+                    .call("double", &[i, 1], Duration::from_secs(10))
+                    .unwrap();
+                assert_eq!(reply, vec![i.wrapping_mul(2), 2]);
+            })
+        })
+        .collect();
+    for c in callers {
+        c.join().unwrap();
+    }
+
+    let t = Duration::from_secs(10);
+    assert!(matches!(client.call("fail", b"", t), Err(RpcError::Remote(ref e)) if e == "nope"));
+    assert!(matches!(
+        client.call("panic", b"", t),
+        Err(RpcError::Remote(_))
+    ));
+    assert!(
+        matches!(client.call("missing", b"", t), Err(RpcError::NoSuchMethod(ref m)) if m == "missing")
+    );
+    assert_eq!(client.pending_calls(), 0);
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rpc_timeout_and_cancel() {
+    let (started_tx, started_rx) = mpsc::channel();
+    let started_tx = Mutex::new(started_tx);
+    let (seen_tx, seen_rx) = mpsc::channel();
+    let seen_tx = Mutex::new(seen_tx);
+    let server = Server::new()
+        .workers(2)
+        .register("slow", move |req| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            while !req.is_cancelled() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            seen_tx.lock().unwrap().send(()).unwrap();
+            Ok(Vec::new())
+        })
+        .register("fast", |_| Ok(b"ok".to_vec()));
+    let (client, server) = test_pair(server);
+
+    let err = client
+        .call("slow", b"", Duration::from_millis(50))
+        .unwrap_err();
+    assert!(matches!(err, RpcError::Timeout));
+    started_rx.recv().unwrap();
+    seen_rx.recv().unwrap();
+
+    // an explicitly cancelled call is stopped on the server too, long before its deadline
+    let call = client
+        .start_call("slow", b"", Duration::from_secs(3600))
+        .unwrap();
+    started_rx.recv().unwrap();
+    call.cancel();
+    seen_rx.recv().unwrap();
+    assert_eq!(client.pending_calls(), 0);
+
+    // and the connection still works
+    assert_eq!(
+        client.call("fast", b"", Duration::from_secs(10)).unwrap(),
+        b"ok"
+    );
+    drop(client);
+    server.join().unwrap().unwrap();
+}
@@ -0,0 +1,616 @@
+//! An event-loop server for `Stream` connections
+//!
+//! A `Server` owns a `UdtListener` and an `Epoll`.  It accepts connections, waits for them to
+//! become readable (and, on request, writable), and calls a `ConnectionHandler` for each event,
+//! so services don't have to write their own epoll loop.
+//!
+//! By default the handler runs on the event loop thread, so it must not block.  With
+//! [`workers`](struct.ServerConfig.html#method.workers), events are handed to a pool of worker
+//! threads instead.  A connection is never handled by two threads at once, and its events are
+//! delivered in order.
+//!
+//! Connections are closed when a handler returns an error or calls
+//! [`Connection::close`](struct.Connection.html#method.close), and when the peer disconnects
+//! and there's nothing left to read.  `on_close` is called for every connection that was
+//! accepted, including those still open when the server shuts down.
+//!
+//! Shutting down stops accepting, lets running callbacks finish, and then gives every open
+//! connection up to the [drain timeout](struct.ServerConfig.html#method.drain_timeout) to send
+//! what is left in its buffer.  The returned `ShutdownReport` says how much was abandoned.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::io::{self, Read, Write};
+//! use udt::*;
+//! use udt::server::{Connection, ConnectionHandler, ServerConfig};
+//!
+//! struct Echo;
+//!
+//! impl ConnectionHandler for Echo {
+//!     type State = ();
+//!
+//!     fn on_connect(&self, conn: &Connection) -> io::Result<()> {
+//!         println!("{} connected", conn.peer_addr());
+//!         Ok(())
+//!     }
+//!
+//!     fn on_readable(&self, mut conn: &Connection, _: &mut ()) -> io::Result<()> {
+//!         let mut buf = [0u8; 65536];
+//!         match conn.read(&mut buf)? {
+//!             0 => conn.close(),
+//!             n => conn.write_all(&buf[..n])?,
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! init();
+//! let server = ServerConfig::new()
+//!     .workers(4)
+//!     .bind("0.0.0.0:9000".parse().unwrap(), Echo)
+//!     .unwrap();
+//! server.join().unwrap();
+//! ```
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    Epoll, EpollEvents, UdtError, UdtListener, UdtOpts, UdtSocket, UdtStatus, UdtStream,
+    UDT_EPOLL_ERR, UDT_EPOLL_IN, UDT_EPOLL_OUT,
+};
+
+/// Callbacks for the connections of a `Server`
+///
+/// Only `on_connect` and `on_readable` are required.  A handler that returns an error has its
+/// connection closed.
+pub trait ConnectionHandler: Send + Sync + 'static {
+    /// Per-connection state, created by `on_connect` and handed back to every other callback
+    type State: Send + 'static;
+
+    /// Called on the event loop thread for every accepted connection.  Returning an error
+    /// rejects the connection.
+    fn on_connect(&self, conn: &Connection) -> io::Result<Self::State>;
+
+    /// Called when data can be read from the connection, or the peer disconnected.
+    fn on_readable(&self, conn: &Connection, state: &mut Self::State) -> io::Result<()>;
+
+    /// Called when data can be written, while the connection is
+    /// [watching for it](struct.Connection.html#method.watch_writable).
+    fn on_writable(&self, conn: &Connection, state: &mut Self::State) -> io::Result<()> {
+        let _ = (conn, state);
+        Ok(())
+    }
+
+    /// Called once the connection is being closed, with the error that caused it, if any.
+    fn on_close(&self, conn: &Connection, state: Self::State, error: Option<io::Error>) {
+        let _ = (conn, state, error);
+    }
+}
+
+/// A connection accepted by a `Server`
+///
+/// `&Connection` implements `Read` and `Write`.
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    stream: UdtStream,
+    peer: SocketAddr,
+    close: Cell<bool>,
+    writable: Cell<bool>,
+}
+
+impl Connection {
+    /// Returns a number that identifies this connection for the lifetime of the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the connection's stream.
+    pub fn stream(&self) -> &UdtStream {
+        &self.stream
+    }
+
+    /// Returns the underlying socket, e.g. for use with `getsockopt`.
+    pub fn socket(&self) -> &UdtSocket {
+        self.stream.socket()
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Closes the connection once the current callback returns.
+    pub fn close(&self) {
+        self.close.set(true);
+    }
+
+    /// Sets whether `on_writable` should be called when the connection can be written to.
+    ///
+    /// This takes effect once the current callback returns.  It should only be turned on while
+    /// there's data waiting to be written, since an idle connection is always writable.
+    pub fn watch_writable(&self, watch: bool) {
+        self.writable.set(watch);
+    }
+
+    fn events(&self) -> EpollEvents {
+        if self.writable.get() {
+            UDT_EPOLL_IN | UDT_EPOLL_OUT | UDT_EPOLL_ERR
+        } else {
+            UDT_EPOLL_IN | UDT_EPOLL_ERR
+        }
+    }
+
+    // true once the peer is gone and everything it sent has been read
+    fn is_finished(&self) -> bool {
+        match self.socket().getstate() {
+            UdtStatus::BROKEN | UdtStatus::CLOSING | UdtStatus::CLOSED | UdtStatus::NONEXIST => {
+                self.socket()
+                    .getsockopt(UdtOpts::UDT_RCVDATA)
+                    .map_or(true, |n| n <= 0)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.stream).read(buf)
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.stream).flush()
+    }
+}
+
+/// Settings for a `Server`
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    workers: usize,
+    poll_interval: Duration,
+    backlog: i32,
+    drain_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig::new()
+    }
+}
+
+impl ServerConfig {
+    /// Creates the default configuration: handlers run on the event loop thread, which checks
+    /// for shutdown every 10ms, `bind` listens with a backlog of 128, and shutting down waits up
+    /// to 10 seconds for connections to drain.
+    pub fn new() -> ServerConfig {
+        ServerConfig {
+            workers: 0,
+            poll_interval: Duration::from_millis(10),
+            backlog: 128,
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Runs handlers on a pool of `n` worker threads, or on the event loop thread if `n` is 0.
+    pub fn workers(mut self, n: usize) -> ServerConfig {
+        self.workers = n;
+        self
+    }
+
+    /// Sets how long the event loop waits for events at a time.
+    ///
+    /// This bounds how quickly the server notices a shutdown request and, with workers, how
+    /// soon a connection is watched again after a worker finished with it.
+    pub fn poll_interval(mut self, interval: Duration) -> ServerConfig {
+        self.poll_interval = std::cmp::max(interval, Duration::from_millis(1));
+        self
+    }
+
+    /// Sets how long shutting down waits for the send buffers of open connections to empty.
+    ///
+    /// The timeout is shared by all connections, since they drain at the same time.
+    pub fn drain_timeout(mut self, timeout: Duration) -> ServerConfig {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Sets the listen backlog used by `bind`.
+    pub fn backlog(mut self, backlog: i32) -> ServerConfig {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Binds a listener to `addr` and starts serving it with `handler`.
+    pub fn bind<H: ConnectionHandler>(
+        &self,
+        addr: SocketAddr,
+        handler: H,
+    ) -> Result<Server, UdtError> {
+        self.serve(UdtListener::bind(addr, self.backlog)?, handler)
+    }
+
+    /// Starts serving `listener` with `handler`.
+    pub fn serve<H: ConnectionHandler>(
+        &self,
+        listener: UdtListener,
+        handler: H,
+    ) -> Result<Server, UdtError> {
+        let addr = listener.local_addr()?;
+        let mut epoll = Epoll::create()?;
+        epoll.add_usock(listener.socket(), Some(UDT_EPOLL_IN | UDT_EPOLL_ERR))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let event_loop = EventLoop {
+            config: self.clone(),
+            handler: Arc::new(handler),
+            listener,
+            epoll,
+            stop: stop.clone(),
+            conns: HashMap::new(),
+            next_id: 0,
+        };
+        let thread = thread::spawn(move || event_loop.run());
+        Ok(Server {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running event-loop server
+///
+/// Dropping the `Server` shuts it down.
+#[derive(Debug)]
+pub struct Server {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<Result<ShutdownReport, UdtError>>>,
+}
+
+/// What happened to the connections that were open when a `Server` shut down
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections whose send buffer emptied before they were closed
+    pub drained: usize,
+    /// Connections closed with data still queued, because the drain timeout expired or the
+    /// connection broke
+    pub abandoned: usize,
+    /// Bytes that were still queued on the abandoned connections, rounded up to whole packets
+    pub abandoned_bytes: usize,
+}
+
+impl Server {
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a handle that can shut the server down from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stop: self.stop.clone(),
+        }
+    }
+
+    /// Shuts the server down and waits until it has stopped.
+    ///
+    /// The listener is closed, callbacks that are already running are allowed to finish, and
+    /// then every open connection is flushed and closed.
+    pub fn shutdown(mut self) -> Result<ShutdownReport, UdtError> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wait()
+    }
+
+    /// Waits until the server is shut down through a `ShutdownHandle`, or fails.
+    pub fn join(mut self) -> Result<ShutdownReport, UdtError> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<ShutdownReport, UdtError> {
+        match self.thread.take().map(thread::JoinHandle::join) {
+            Some(Ok(res)) => res,
+            Some(Err(e)) => panic::resume_unwind(e),
+            None => Ok(ShutdownReport::default()),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// A handle used to shut down a `Server` from another thread
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to shut down.  This returns immediately; use `Server::join` to wait.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Slot<S> {
+    conn: Connection,
+    state: S,
+    events: EpollEvents,
+}
+
+enum Event {
+    Readable,
+    Writable,
+}
+
+fn dispatch<H: ConnectionHandler>(
+    handler: &H,
+    slot: &mut Slot<H::State>,
+    event: Event,
+) -> io::Result<()> {
+    let res = panic::catch_unwind(AssertUnwindSafe(|| match event {
+        Event::Readable => handler.on_readable(&slot.conn, &mut slot.state),
+        Event::Writable => handler.on_writable(&slot.conn, &mut slot.state),
+    }));
+    res.unwrap_or_else(|_| Err(io::Error::other("handler panicked")))
+}
+
+struct Pool<S> {
+    jobs: Sender<(Box<Slot<S>>, Event)>,
+    done: Receiver<(Box<Slot<S>>, io::Result<()>)>,
+    threads: Vec<thread::JoinHandle<()>>,
+    // slots handed to a worker and not back yet
+    busy: usize,
+}
+
+impl<S: Send + 'static> Pool<S> {
+    fn start<H: ConnectionHandler<State = S>>(handler: &Arc<H>, n: usize) -> Pool<S> {
+        let (jobs, job_rx) = mpsc::channel::<(Box<Slot<S>>, Event)>();
+        let (done_tx, done) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let threads = (0..n)
+            .map(|_| {
+                let handler = handler.clone();
+                let job_rx = job_rx.clone();
+                let done_tx = done_tx.clone();
+                thread::spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (mut slot, event) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let res = dispatch(&*handler, &mut slot, event);
+                    if done_tx.send((slot, res)).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        Pool {
+            jobs,
+            done,
+            threads,
+            busy: 0,
+        }
+    }
+}
+
+struct EventLoop<H: ConnectionHandler> {
+    config: ServerConfig,
+    handler: Arc<H>,
+    listener: UdtListener,
+    epoll: Epoll,
+    stop: Arc<AtomicBool>,
+    // `None` while a worker has the connection
+    conns: HashMap<UdtSocket, Option<Box<Slot<H::State>>>>,
+    next_id: u64,
+}
+
+impl<H: ConnectionHandler> EventLoop<H> {
+    fn run(mut self) -> Result<ShutdownReport, UdtError> {
+        let mut pool = if self.config.workers > 0 {
+            Some(Pool::start(&self.handler, self.config.workers))
+        } else {
+            None
+        };
+        let timeout = self.config.poll_interval.as_millis() as i64;
+        let listener = *self.listener.socket();
+
+        let mut res = Ok(ShutdownReport::default());
+        while !self.stop.load(Ordering::SeqCst) {
+            let (readable, writable) = match self.epoll.wait(timeout, true) {
+                Ok(r) => r,
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            };
+            if let Some(ref mut pool) = pool {
+                while let Ok((slot, res)) = pool.done.try_recv() {
+                    pool.busy -= 1;
+                    self.finish(slot, res, true);
+                }
+            }
+            for sock in readable {
+                if sock == listener {
+                    self.accept();
+                } else {
+                    self.ready(sock, Event::Readable, pool.as_mut());
+                }
+            }
+            for sock in writable {
+                let watching = match self.conns.get(&sock) {
+                    Some(Some(slot)) => slot.conn.writable.get(),
+                    _ => false,
+                };
+                if watching {
+                    self.ready(sock, Event::Writable, pool.as_mut());
+                }
+            }
+        }
+
+        debug!("server on {:?} shutting down", self.listener.local_addr());
+        self.listener.closer().close();
+        if let Some(mut pool) = pool {
+            while pool.busy > 0 {
+                match pool.done.recv() {
+                    Ok((slot, res)) => {
+                        pool.busy -= 1;
+                        self.finish(slot, res, true);
+                    }
+                    Err(_) => break,
+                }
+            }
+            drop(pool.jobs);
+            for t in pool.threads {
+                let _ = t.join();
+            }
+        }
+        let mut open = Vec::new();
+        for (sock, slot) in std::mem::take(&mut self.conns) {
+            if let Some(slot) = slot {
+                let _ = self.epoll.release_usock(&sock);
+                self.handler.on_close(&slot.conn, slot.state, None);
+                open.push(slot.conn);
+            }
+        }
+        // UDT keeps sending in the background, so the connections drain in parallel while we
+        // wait on each in turn
+        let deadline = Instant::now() + self.config.drain_timeout;
+        let report = open
+            .into_iter()
+            .fold(ShutdownReport::default(), |mut report, conn| {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match conn.stream.flush_and_close(timeout) {
+                    Ok(None) => report.drained += 1,
+                    Ok(Some(n)) => {
+                        report.abandoned += 1;
+                        report.abandoned_bytes += n;
+                    }
+                    Err(e) => {
+                        debug!("failed to close connection from {}: {}", conn.peer, e);
+                        report.abandoned += 1;
+                    }
+                }
+                report
+            });
+        res.map(|_| report)
+    }
+
+    fn accept(&mut self) {
+        let (stream, peer) = match self.listener.accept() {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("server failed to accept a connection: {}", e);
+                return;
+            }
+        };
+        self.next_id += 1;
+        let conn = Connection {
+            id: self.next_id,
+            stream,
+            peer,
+            close: Cell::new(false),
+            writable: Cell::new(false),
+        };
+        let state = match self.handler.on_connect(&conn) {
+            Ok(state) => state,
+            Err(e) => {
+                debug!("rejected connection from {}: {}", peer, e);
+                return;
+            }
+        };
+        let events = conn.events();
+        let sock = *conn.socket();
+        if let Err(e) = self.epoll.add_usock(&sock, Some(events)) {
+            warn!("server failed to watch connection from {}: {}", peer, e);
+            self.handler.on_close(&conn, state, Some(e.into()));
+            return;
+        }
+        let slot = Slot {
+            conn,
+            state,
+            events,
+        };
+        self.conns.insert(sock, Some(Box::new(slot)));
+    }
+
+    fn ready(&mut self, sock: UdtSocket, event: Event, pool: Option<&mut Pool<H::State>>) {
+        let slot = match self.conns.get_mut(&sock).and_then(Option::take) {
+            Some(slot) => slot,
+            // already handed to a worker
+            None => return,
+        };
+        match pool {
+            None => {
+                let mut slot = slot;
+                let res = dispatch(&*self.handler, &mut slot, event);
+                self.finish(slot, res, false);
+            }
+            Some(pool) => {
+                // a worker has it now, so stop watching it until it comes back
+                let _ = self.epoll.remove_usock(&sock);
+                pool.busy += 1;
+                if let Err(mpsc::SendError((slot, _))) = pool.jobs.send((slot, event)) {
+                    pool.busy -= 1;
+                    self.finish(slot, Err(io::ErrorKind::BrokenPipe.into()), true);
+                }
+            }
+        }
+    }
+
+    // puts a connection back after a callback, or closes it
+    fn finish(&mut self, mut slot: Box<Slot<H::State>>, res: io::Result<()>, removed: bool) {
+        let sock = *slot.conn.socket();
+        let close = match res {
+            Err(e) => Some(Some(e)),
+            Ok(()) if slot.conn.close.get() || slot.conn.is_finished() => Some(None),
+            Ok(()) => None,
+        };
+        if let Some(error) = close {
+            let _ = self.epoll.release_usock(&sock);
+            self.conns.remove(&sock);
+            let Slot { conn, state, .. } = *slot;
+            self.handler.on_close(&conn, state, error);
+            return;
+        }
+
+        let events = slot.conn.events();
+        if removed || events != slot.events {
+            if !removed {
+                let _ = self.epoll.remove_usock(&sock);
+            }
+            if let Err(e) = self.epoll.readd_usock(&sock, events) {
+                let _ = self.epoll.release_usock(&sock);
+                self.conns.remove(&sock);
+                let Slot { conn, state, .. } = *slot;
+                self.handler.on_close(&conn, state, Some(e.into()));
+                return;
+            }
+            slot.events = events;
+        }
+        self.conns.insert(sock, Some(slot));
+    }
+}
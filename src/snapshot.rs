@@ -0,0 +1,191 @@
+//! Reading, copying and comparing all of a socket's options at once
+//!
+//! Accepted sockets inherit their options from the listener, but sockets created by hand start
+//! from UDT's defaults.  [`UdtSocket::options`](struct.UdtSocket.html#method.options) captures
+//! every option in an `OptionSnapshot`, which can be applied to another socket with
+//! [`apply_options`](struct.UdtSocket.html#method.apply_options) or compared with
+//! [`diff`](struct.OptionSnapshot.html#method.diff).
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::{EpollEvents, UdtError, UdtOpts, UdtSocket, UdtStatus};
+
+// Defines `OptionSnapshot` and the methods that go through every option.  Settable options are
+// listed in the order they must be set: UDT sizes its buffers in packets of the current MSS and
+// caps them at the flight flag size, so those two come first.
+macro_rules! snapshot {
+    (settable {
+        $($(#[$doc:meta])* $field:ident: $opt:ident: $ty:ty,)*
+    }
+    read only {
+        $($(#[$rdoc:meta])* $rfield:ident: $ropt:ident: $rty:ty,)*
+    }) => {
+        /// The value of every option of a socket, as returned by
+        /// [`UdtSocket::options`](struct.UdtSocket.html#method.options)
+        ///
+        /// The fields are named after the options in [`UdtOpts`](UdtOpts/index.html).
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct OptionSnapshot {
+            $($(#[$doc])* pub $field: $ty,)*
+            $($(#[$rdoc])* pub $rfield: $rty,)*
+        }
+
+        impl UdtSocket {
+            /// Reads every option of the socket.
+            pub fn options(&self) -> Result<OptionSnapshot, UdtError> {
+                Ok(OptionSnapshot {
+                    $($field: self.getsockopt(UdtOpts::$opt)?,)*
+                    $($rfield: self.getsockopt(UdtOpts::$ropt)?,)*
+                })
+            }
+
+            /// Sets every settable option to its value in `snapshot`.
+            ///
+            /// Only options that differ from the socket's current values are written, in an
+            /// order UDT accepts.  Some options, like `UDT_MSS` and the buffer sizes, can only
+            /// be changed before the socket is bound or connected, so applying a snapshot from
+            /// a differently configured socket to a connected one fails.  Options set before
+            /// the error stay set.
+            pub fn apply_options(&self, snapshot: &OptionSnapshot) -> Result<(), UdtError> {
+                $(
+                    if self.getsockopt(UdtOpts::$opt)? != snapshot.$field {
+                        self.setsockopt(UdtOpts::$opt, snapshot.$field)?;
+                    }
+                )*
+                Ok(())
+            }
+        }
+
+        impl OptionSnapshot {
+            /// Lists the settable options whose values differ between `self`, the expected
+            /// configuration, and `actual`.
+            ///
+            /// Read-only values like the state and buffer occupancy are not compared.
+            pub fn diff(&self, actual: &OptionSnapshot) -> Vec<OptionDiff> {
+                let mut diffs = Vec::new();
+                $(
+                    if self.$field != actual.$field {
+                        diffs.push(OptionDiff {
+                            option: stringify!($opt),
+                            expected: format!("{:?}", self.$field),
+                            actual: format!("{:?}", actual.$field),
+                        });
+                    }
+                )*
+                diffs
+            }
+        }
+    };
+}
+
+snapshot! {
+    settable {
+        /// Maximum packet size in bytes
+        mss: UDT_MSS: i32,
+        /// Maximum window size in packets
+        fc: UDT_FC: i32,
+        /// UDT sender buffer size in bytes
+        sndbuf: UDT_SNDBUF: i32,
+        /// UDT receiver buffer size in bytes
+        rcvbuf: UDT_RCVBUF: i32,
+        /// UDP socket sender buffer size in bytes
+        udp_sndbuf: UDP_SNDBUF: i32,
+        /// UDP socket receiver buffer size in bytes
+        udp_rcvbuf: UDP_RCVBUF: i32,
+        /// Whether an existing address is reused
+        reuseaddr: UDT_REUSEADDR: bool,
+        /// Whether connections are set up in rendezvous mode
+        rendezvous: UDT_RENDEZVOUS: bool,
+        /// Whether sending blocks
+        sndsyn: UDT_SNDSYN: bool,
+        /// Whether receiving blocks
+        rcvsyn: UDT_RCVSYN: bool,
+        /// Linger time on close
+        linger: UDT_LINGER: Option<Duration>,
+        /// Sending call timeout
+        sndtimeo: UDT_SNDTIMEO: Option<Duration>,
+        /// Receiving call timeout
+        rcvtimeo: UDT_RCVTIMEO: Option<Duration>,
+        /// Maximum bandwidth in bytes per second, or -1 for no limit
+        maxbw: UDT_MAXBW: i64,
+    }
+    read only {
+        /// The socket's state
+        state: UDT_STATE: UdtStatus,
+        /// The epoll events available on the socket
+        event: UDT_EVENT: EpollEvents,
+        /// Packets in the sending buffer
+        snddata: UDT_SNDDATA: i32,
+        /// Bytes available to read
+        rcvdata: UDT_RCVDATA: i32,
+    }
+}
+
+/// An option whose value differs between two `OptionSnapshot`s
+///
+/// Displays as e.g. `UDT_MSS: expected 1500, got 1400`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionDiff {
+    /// The name of the option, e.g. `"UDT_MSS"`
+    pub option: &'static str,
+    /// The expected value
+    pub expected: String,
+    /// The actual value
+    pub actual: String,
+}
+
+impl fmt::Display for OptionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {}, got {}",
+            self.option, self.expected, self.actual
+        )
+    }
+}
+
+// UDT's defaults for a new socket
+#[cfg(test)]
+pub(crate) fn defaults() -> OptionSnapshot {
+    OptionSnapshot {
+        mss: 1500,
+        fc: 25600,
+        sndbuf: 10_240_000,
+        rcvbuf: 10_240_000,
+        udp_sndbuf: 1_024_000,
+        udp_rcvbuf: 1_024_000,
+        reuseaddr: true,
+        rendezvous: false,
+        sndsyn: true,
+        rcvsyn: true,
+        linger: Some(Duration::from_secs(180)),
+        sndtimeo: None,
+        rcvtimeo: None,
+        maxbw: -1,
+        state: UdtStatus::INIT,
+        event: EpollEvents::empty(),
+        snddata: 0,
+        rcvdata: 0,
+    }
+}
+
+#[test]
+fn test_snapshot_diff() {
+    let expected = defaults();
+    let mut actual = expected.clone();
+    // read-only values are ignored
+    actual.state = UdtStatus::CONNECTED;
+    actual.snddata = 12;
+    assert!(expected.diff(&actual).is_empty());
+
+    actual.mss = 1400;
+    actual.rcvtimeo = Some(Duration::from_millis(500));
+    let diffs = expected.diff(&actual);
+    assert_eq!(diffs.len(), 2);
+    assert_eq!(diffs[0].to_string(), "UDT_MSS: expected 1500, got 1400");
+    assert_eq!(
+        diffs[1].to_string(),
+        "UDT_RCVTIMEO: expected None, got Some(500ms)"
+    );
+}
@@ -0,0 +1,544 @@
+//! `std::net`-style wrappers around a `UdtSocket` in `Stream` mode
+//!
+//! A raw `UdtSocket` is `Copy` and is never closed implicitly.  `UdtStream` and `UdtListener`
+//! instead own their socket and close it when they are dropped, and `UdtStream` implements
+//! `Read` and `Write` so it can be used anywhere a `TcpStream` could.
+
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::raw;
+use crate::{
+    Epoll, SocketFamily, SocketType, UdtError, UdtSocket, UdtStatus, ELISTENERCLOSED,
+    UDT_EPOLL_ERR, UDT_EPOLL_IN,
+};
+
+// how long `accept_timeout` sleeps in epoll before checking if the listener was closed
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn family_of(addr: &SocketAddr) -> SocketFamily {
+    match *addr {
+        SocketAddr::V4(..) => SocketFamily::AFInet,
+        SocketAddr::V6(..) => SocketFamily::AFInet6,
+    }
+}
+
+fn millis(d: Duration) -> i64 {
+    d.as_secs() as i64 * 1000 + i64::from(d.subsec_millis())
+}
+
+/// A connected UDT socket in `Stream` mode
+///
+/// The socket is closed when the `UdtStream` and all of its clones and halves (see `try_clone`
+/// and `split`) are dropped.
+#[derive(Debug)]
+pub struct UdtStream {
+    inner: Arc<OwnedSocket>,
+}
+
+// A socket that is closed exactly once: by `close`, or when the guard is dropped.  Shared by a
+// stream and its clones, and owned by the other wrappers around a `UdtSocket`.
+#[derive(Debug)]
+pub(crate) struct OwnedSocket {
+    sock: UdtSocket,
+    // set once the socket was closed or someone took it over, so dropping the guard must not
+    // close it
+    released: AtomicBool,
+}
+
+impl OwnedSocket {
+    pub(crate) fn new(sock: UdtSocket) -> OwnedSocket {
+        OwnedSocket {
+            sock,
+            released: AtomicBool::new(false),
+        }
+    }
+
+    // Closes the socket, unless that already happened or the socket was released.
+    pub(crate) fn close(&self) -> Result<(), UdtError> {
+        if self.released.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.sock.close()
+    }
+
+    // Gives up ownership of the socket without closing it.
+    pub(crate) fn release(&self) -> UdtSocket {
+        self.released.store(true, Ordering::SeqCst);
+        self.sock
+    }
+}
+
+impl Deref for OwnedSocket {
+    type Target = UdtSocket;
+
+    fn deref(&self) -> &UdtSocket {
+        &self.sock
+    }
+}
+
+impl Drop for OwnedSocket {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl UdtStream {
+    /// Opens a UDT connection to a remote host.
+    ///
+    /// `init()` must have been called first.
+    pub fn connect(addr: SocketAddr) -> Result<UdtStream, UdtError> {
+        let sock = UdtSocket::new(family_of(&addr), SocketType::Stream)?;
+        if let Err(e) = sock.connect(addr) {
+            let _ = sock.close();
+            return Err(e);
+        }
+        Ok(UdtStream::from_socket(sock))
+    }
+
+    /// Takes ownership of an already connected socket.
+    pub fn from_socket(sock: UdtSocket) -> UdtStream {
+        UdtStream {
+            inner: Arc::new(OwnedSocket::new(sock)),
+        }
+    }
+
+    /// Returns the underlying socket, e.g. for use with `getsockopt` or `Epoll`.
+    ///
+    /// The socket is still owned by this `UdtStream` and must not be closed directly.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+
+    /// Releases ownership of the underlying socket without closing it.
+    ///
+    /// Clones of this stream can still be used, but none of them will close the socket any
+    /// more.
+    pub fn into_socket(self) -> UdtSocket {
+        self.inner.release()
+    }
+
+    /// Creates another handle to the same connection.
+    ///
+    /// UDT allows one thread to send while another receives, so a clone can be moved to a
+    /// second thread.  The socket is closed once every handle has been dropped.  Fails if the
+    /// socket has already been closed.
+    pub fn try_clone(&self) -> Result<UdtStream, UdtError> {
+        match self.inner.sock.getstate() {
+            UdtStatus::CLOSED | UdtStatus::NONEXIST => {
+                Err(UdtError::new(raw::EINVSOCK, "socket is closed"))
+            }
+            _ => Ok(UdtStream {
+                inner: self.inner.clone(),
+            }),
+        }
+    }
+
+    /// Splits the stream into a half that can only read and a half that can only write.
+    ///
+    /// The halves can be moved to different threads.  The socket is closed once both have been
+    /// dropped.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        let read = ReadHalf {
+            inner: self.inner.clone(),
+        };
+        (read, WriteHalf { inner: self.inner })
+    }
+
+    /// Returns the address of the remote peer.
+    pub fn peer_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.inner.sock.getpeername()
+    }
+
+    /// Returns the local address of this connection.
+    pub fn local_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.inner.sock.getsockname()
+    }
+
+    /// Closes the connection, reporting any error from UDT.
+    ///
+    /// This closes the socket even if there are other clones of the stream; their reads and
+    /// writes will fail from now on.  Dropping all handles to a `UdtStream` also closes it, but
+    /// ignores errors.
+    pub fn close(self) -> Result<(), UdtError> {
+        self.inner.close()
+    }
+
+    /// Returns how much written data UDT has not sent or had acknowledged yet.
+    ///
+    /// See [`UdtSocket::pending_send_bytes`](struct.UdtSocket.html#method.pending_send_bytes).
+    pub fn pending_send_bytes(&self) -> Result<usize, UdtError> {
+        self.inner.sock.pending_send_bytes()
+    }
+
+    /// Returns the number of bytes that can be read without blocking.
+    pub fn available_bytes(&self) -> Result<usize, UdtError> {
+        self.inner.sock.available_bytes()
+    }
+
+    /// Blocks until less than `threshold` bytes are waiting to be sent.
+    ///
+    /// See [`UdtSocket::wait_send_buffer_below`](struct.UdtSocket.html#method.wait_send_buffer_below).
+    pub fn wait_send_buffer_below(
+        &self,
+        threshold: usize,
+        timeout: Duration,
+    ) -> Result<(), UdtError> {
+        self.inner.sock.wait_send_buffer_below(threshold, timeout)
+    }
+
+    /// Waits up to `timeout` for everything written to be sent, then closes the connection.
+    ///
+    /// Returns `None` if everything was sent, or the number of bytes that were discarded; see
+    /// [`UdtSocket::flush_and_close`](struct.UdtSocket.html#method.flush_and_close).
+    pub fn flush_and_close(self, timeout: Duration) -> Result<Option<usize>, UdtError> {
+        self.into_socket().flush_and_close(timeout)
+    }
+}
+
+impl AsRef<UdtSocket> for UdtStream {
+    fn as_ref(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+}
+
+fn recv(sock: &UdtSocket, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    match sock.recv(buf, buf.len()) {
+        Ok(n) => Ok(n as usize),
+        // UDT doesn't distinguish an orderly close by the peer from a broken connection, so
+        // report both as end of stream like a closed TcpStream would
+        Err(ref e) if e.err_code == raw::ECONNLOST => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn send(sock: &UdtSocket, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    Ok(sock.send(buf)? as usize)
+}
+
+impl io::Read for &UdtStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv(&self.inner.sock, buf)
+    }
+}
+
+impl io::Write for &UdtStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        send(&self.inner.sock, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for UdtStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv(&self.inner.sock, buf)
+    }
+}
+
+impl io::Write for UdtStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// A connection that a background reader thread and the application use at the same time, so
+// it is accessed through `&self`.  Implemented for `UdtStream`, and for `UnixStream` in unit
+// tests.
+pub(crate) trait Transport: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    // must wake up a blocked `read`
+    fn shutdown(&self);
+    // true if the connection failed, rather than the peer misbehaving on a working one
+    fn is_broken(&self) -> bool;
+}
+
+impl Transport for UdtStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        recv(&self.inner.sock, buf)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut s = self;
+        io::Write::write_all(&mut s, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Ok(self
+            .inner
+            .sock
+            .setsockopt(crate::UdtOpts::UDT_RCVTIMEO, timeout)?)
+    }
+
+    fn shutdown(&self) {
+        let _ = self.inner.close();
+    }
+
+    fn is_broken(&self) -> bool {
+        matches!(
+            self.inner.sock.getstate(),
+            UdtStatus::BROKEN | UdtStatus::CLOSING | UdtStatus::CLOSED | UdtStatus::NONEXIST
+        )
+    }
+}
+
+#[cfg(all(test, unix))]
+impl Transport for std::os::unix::net::UnixStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut s = self;
+        io::Read::read(&mut s, buf)
+    }
+
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut s = self;
+        io::Write::write_all(&mut s, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) {
+        let _ = std::os::unix::net::UnixStream::shutdown(self, std::net::Shutdown::Both);
+    }
+
+    fn is_broken(&self) -> bool {
+        true
+    }
+}
+
+/// The reading half of a `UdtStream`, created by [`split`](struct.UdtStream.html#method.split)
+#[derive(Debug)]
+pub struct ReadHalf {
+    inner: Arc<OwnedSocket>,
+}
+
+impl ReadHalf {
+    /// Returns the underlying socket.  It must not be closed directly.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+
+    /// Returns the address of the remote peer.
+    pub fn peer_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.inner.sock.getpeername()
+    }
+}
+
+impl AsRef<UdtSocket> for ReadHalf {
+    fn as_ref(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+}
+
+impl io::Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv(&self.inner.sock, buf)
+    }
+}
+
+/// The writing half of a `UdtStream`, created by [`split`](struct.UdtStream.html#method.split)
+#[derive(Debug)]
+pub struct WriteHalf {
+    inner: Arc<OwnedSocket>,
+}
+
+impl WriteHalf {
+    /// Returns the underlying socket.  It must not be closed directly.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+
+    /// Returns the address of the remote peer.
+    pub fn peer_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.inner.sock.getpeername()
+    }
+}
+
+impl AsRef<UdtSocket> for WriteHalf {
+    fn as_ref(&self) -> &UdtSocket {
+        &self.inner.sock
+    }
+}
+
+impl io::Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        send(&self.inner.sock, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A UDT socket listening for `Stream` connections
+///
+/// The socket is closed when the `UdtListener` is dropped, or earlier through a
+/// [`ListenerCloser`](struct.ListenerCloser.html).
+#[derive(Debug)]
+pub struct UdtListener {
+    sock: UdtSocket,
+    closed: Arc<AtomicBool>,
+}
+
+impl UdtListener {
+    /// Creates a new `Stream` socket bound to `addr` and starts listening on it.
+    ///
+    /// `init()` must have been called first.  To configure socket options before binding, set
+    /// up the socket by hand and use [`from_socket`](#method.from_socket).
+    pub fn bind(addr: SocketAddr, backlog: i32) -> Result<UdtListener, UdtError> {
+        let sock = UdtSocket::new(family_of(&addr), SocketType::Stream)?;
+        if let Err(e) = sock.bind(addr).and_then(|_| sock.listen(backlog)) {
+            let _ = sock.close();
+            return Err(e);
+        }
+        Ok(UdtListener::from_socket(sock))
+    }
+
+    /// Takes ownership of a socket that is already bound and listening.
+    pub fn from_socket(sock: UdtSocket) -> UdtListener {
+        UdtListener {
+            sock,
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the underlying listening socket.
+    pub fn socket(&self) -> &UdtSocket {
+        &self.sock
+    }
+
+    /// Returns the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, UdtError> {
+        self.sock.getsockname()
+    }
+
+    /// Returns a handle that can close this listener from another thread.
+    pub fn closer(&self) -> ListenerCloser {
+        ListenerCloser {
+            sock: self.sock,
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// Returns true once the listener has been closed through a `ListenerCloser`.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn closed_err(&self) -> UdtError {
+        UdtError::new(ELISTENERCLOSED, "listener was closed")
+    }
+
+    /// Waits for a new connection.
+    ///
+    /// If the listener is closed while this call is blocked, it returns an error with the code
+    /// [`ELISTENERCLOSED`](constant.ELISTENERCLOSED.html).
+    pub fn accept(&self) -> Result<(UdtStream, SocketAddr), UdtError> {
+        if self.is_closed() {
+            return Err(self.closed_err());
+        }
+        match self.sock.accept() {
+            Ok((sock, addr)) => Ok((UdtStream::from_socket(sock), addr)),
+            Err(_) if self.is_closed() => Err(self.closed_err()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits at most `timeout` for a new connection.
+    ///
+    /// Returns an error with the code `ETIMEOUT` if no connection arrived in time, or
+    /// `ELISTENERCLOSED` if the listener was closed while waiting.
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<(UdtStream, SocketAddr), UdtError> {
+        let deadline = Instant::now() + timeout;
+        let mut epoll = Epoll::create()?;
+        epoll.add_usock(&self.sock, Some(UDT_EPOLL_IN | UDT_EPOLL_ERR))?;
+        loop {
+            if self.is_closed() {
+                return Err(self.closed_err());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(UdtError::new(
+                    raw::ETIMEOUT,
+                    "timed out waiting for a connection",
+                ));
+            }
+            let wait = std::cmp::min(deadline - now, ACCEPT_POLL_INTERVAL);
+            let (rd, wr) = epoll.wait(millis(wait), true)?;
+            if !rd.is_empty() || !wr.is_empty() {
+                return self.accept();
+            }
+        }
+    }
+
+    /// Returns an iterator over incoming connections.
+    ///
+    /// The iterator ends once the listener is closed through a `ListenerCloser`, which makes it
+    /// convenient for accept loops that need to shut down cleanly.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+impl Drop for UdtListener {
+    fn drop(&mut self) {
+        self.closer().close();
+    }
+}
+
+/// An iterator over the connections accepted by a `UdtListener`
+///
+/// See [`UdtListener::incoming`](struct.UdtListener.html#method.incoming)
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a UdtListener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<(UdtStream, SocketAddr), UdtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.listener.accept() {
+            Err(ref e) if e.err_code == ELISTENERCLOSED => None,
+            r => Some(r),
+        }
+    }
+}
+
+/// A handle used to close a `UdtListener` from another thread
+///
+/// Closing wakes up any thread blocked in `accept`, `accept_timeout` or `incoming`.
+#[derive(Debug, Clone)]
+pub struct ListenerCloser {
+    sock: UdtSocket,
+    closed: Arc<AtomicBool>,
+}
+
+impl ListenerCloser {
+    /// Closes the listener.  Calling this more than once has no further effect.
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            trace!("closing listener {:?}", self.sock);
+            let _ = self.sock.close();
+        }
+    }
+}
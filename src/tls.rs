@@ -0,0 +1,155 @@
+//! TLS encryption for `UdtStream`s, using rustls
+//!
+//! A `TlsStream` runs a rustls client or server session over a connected `UdtStream`, so data
+//! can be sent over untrusted links without an external VPN.  Certificates, keys and trust
+//! roots are configured through the usual rustls `ClientConfig` and `ServerConfig`; this
+//! module re-exports the `rustls` crate so the versions always match.
+//!
+//! This only works for `SocketType::Stream` sockets.  TLS needs a reliable, ordered byte
+//! stream, which `Datagram` sockets with message TTLs don't provide.
+//!
+//! This module requires the `tls` feature.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::convert::TryFrom;
+//! use std::io::Write;
+//! use std::sync::Arc;
+//! use udt::*;
+//! use udt::tls::rustls::pki_types::ServerName;
+//! use udt::tls::rustls::{ClientConfig, RootCertStore};
+//!
+//! # let roots = RootCertStore::empty();
+//! init();
+//! let config = ClientConfig::builder()
+//!     .with_root_certificates(roots)
+//!     .with_no_client_auth();
+//!
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let name = ServerName::try_from("files.example.com").unwrap();
+//! let mut tls = udt::tls::connect(Arc::new(config), name, stream).unwrap();
+//! tls.write_all(b"hello").unwrap();
+//! tls.shutdown().unwrap();
+//! ```
+
+pub use rustls;
+
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, ServerConfig, ServerConnection, SideData,
+    StreamOwned,
+};
+
+use crate::UdtStream;
+
+/// A TLS session running over a `UdtStream`
+///
+/// `C` is either `rustls::ClientConnection` or `rustls::ServerConnection`.  The handshake has
+/// already completed by the time a `TlsStream` is returned by [`connect`](fn.connect.html) or
+/// [`accept`](fn.accept.html).
+#[derive(Debug)]
+pub struct TlsStream<C> {
+    inner: StreamOwned<C, UdtStream>,
+}
+
+/// The client side of a TLS session
+pub type TlsClientStream = TlsStream<ClientConnection>;
+
+/// The server side of a TLS session
+pub type TlsServerStream = TlsStream<ServerConnection>;
+
+fn tls_err(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Starts a TLS client session on a connected stream and completes the handshake.
+///
+/// `server_name` is checked against the certificate presented by the server.  If the handshake
+/// fails, the stream is closed.
+pub fn connect(
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    stream: UdtStream,
+) -> io::Result<TlsClientStream> {
+    let conn = ClientConnection::new(config, server_name).map_err(tls_err)?;
+    TlsStream::handshake(StreamOwned::new(conn, stream))
+}
+
+/// Starts a TLS server session on an accepted stream and completes the handshake.
+///
+/// If the handshake fails, the stream is closed.
+pub fn accept(config: Arc<ServerConfig>, stream: UdtStream) -> io::Result<TlsServerStream> {
+    let conn = ServerConnection::new(config).map_err(tls_err)?;
+    TlsStream::handshake(StreamOwned::new(conn, stream))
+}
+
+impl<C, S> TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn handshake(mut inner: StreamOwned<C, UdtStream>) -> io::Result<TlsStream<C>> {
+        while inner.conn.is_handshaking() {
+            inner.conn.complete_io(&mut inner.sock)?;
+        }
+        trace!(
+            "TLS handshake done on {:?}: {:?}",
+            inner.sock.socket(),
+            inner.conn.negotiated_cipher_suite()
+        );
+        Ok(TlsStream { inner })
+    }
+
+    /// Returns the underlying stream.
+    ///
+    /// Reading or writing it directly will corrupt the TLS session.
+    pub fn get_ref(&self) -> &UdtStream {
+        &self.inner.sock
+    }
+
+    /// Returns the rustls session, e.g. to inspect the peer certificates or the negotiated
+    /// cipher suite.
+    pub fn connection(&self) -> &C {
+        &self.inner.conn
+    }
+
+    /// Sends a TLS `close_notify` alert, telling the peer that no more data will follow.
+    ///
+    /// The underlying stream stays open until the `TlsStream` is dropped.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.conn.send_close_notify();
+        while self.inner.conn.wants_write() {
+            self.inner.conn.write_tls(&mut self.inner.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C, S> Read for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<C, S> Write for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,383 @@
+//! Buffer and window tuning from the measured bandwidth-delay product
+//!
+//! UDT's default buffers (10MB) and flow window (25600 packets) are too small to fill a long,
+//! fast link: a 10Gb/s path with a 100ms round trip holds 125MB in flight.  A `TuningConfig`
+//! samples the RTT and estimated bandwidth from `perfmon` during the first seconds of a
+//! connection, computes the bandwidth-delay product (BDP), and sizes `UDT_FC`, `UDT_SNDBUF`,
+//! `UDT_RCVBUF`, `UDP_SNDBUF` and `UDP_RCVBUF` to hold it with some headroom.  Settings are
+//! only ever raised, never lowered.
+//!
+//! UDT4 refuses to change any of these options once the socket is bound (the buffer sizes) or
+//! connected (`UDT_FC`), so the measured connection itself keeps its settings: the tuner still
+//! tries each option, but on a connected socket every attempt fails and `Tuning::adjusted` is
+//! empty.  The recommendation is meant for the next connection's socket, before it connects.
+//! Every decision is logged at debug level and included in the returned `Tuning`.
+//!
+//! The connection should be busy while it is sampled, since UDT only estimates bandwidth from
+//! the data it sends.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::tuning::TuningConfig;
+//!
+//! init();
+//! let addr = "192.0.2.10:9000".parse().unwrap();
+//! let stream = UdtStream::connect(addr).unwrap();
+//! // ... start a transfer on another thread ...
+//! let tuning = TuningConfig::new()
+//!     .sample_time(Duration::from_secs(2))
+//!     .tune(stream.socket())
+//!     .unwrap();
+//! for decision in &tuning.decisions {
+//!     println!("{}", decision);
+//! }
+//!
+//! // later connections start out tuned
+//! let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+//! tuning.recommended.apply(&sock).unwrap();
+//! sock.connect(addr).unwrap();
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{OptionSnapshot, UdtError, UdtOpts, UdtSocket};
+
+/// Settings for the tuner
+#[derive(Debug, Clone)]
+pub struct TuningConfig {
+    sample_time: Duration,
+    sample_interval: Duration,
+    headroom: f64,
+    max_buffer: usize,
+}
+
+impl Default for TuningConfig {
+    fn default() -> TuningConfig {
+        TuningConfig::new()
+    }
+}
+
+impl TuningConfig {
+    /// Creates the default configuration: sample every 100ms for 3 seconds, size buffers at
+    /// twice the BDP, and never above 256 MiB.
+    pub fn new() -> TuningConfig {
+        TuningConfig {
+            sample_time: Duration::from_secs(3),
+            sample_interval: Duration::from_millis(100),
+            headroom: 2.0,
+            max_buffer: 256 * 1024 * 1024,
+        }
+    }
+
+    /// Sets how long to sample the connection for.
+    pub fn sample_time(mut self, time: Duration) -> TuningConfig {
+        self.sample_time = time;
+        self
+    }
+
+    /// Sets how often to sample the connection.
+    pub fn sample_interval(mut self, interval: Duration) -> TuningConfig {
+        self.sample_interval = std::cmp::max(interval, Duration::from_millis(1));
+        self
+    }
+
+    /// Sets the buffer size as a multiple of the BDP.  Values below 1 are raised to 1.
+    pub fn headroom(mut self, factor: f64) -> TuningConfig {
+        self.headroom = factor.max(1.0);
+        self
+    }
+
+    /// Sets the largest buffer size the tuner will choose, in bytes.
+    pub fn max_buffer(mut self, bytes: usize) -> TuningConfig {
+        self.max_buffer = std::cmp::min(bytes, i32::MAX as usize);
+        self
+    }
+
+    /// Samples `sock` for the configured time and recommends settings for the path.
+    ///
+    /// The recommended options are also tried on `sock`, but UDT4 refuses all of them on a
+    /// connected socket, so in practice only later connections benefit.
+    ///
+    /// This blocks for the sample time.  It fails if the socket can't be read from, e.g.
+    /// because the connection broke.
+    pub fn tune(&self, sock: &UdtSocket) -> Result<Tuning, UdtError> {
+        let current = sock.options()?;
+        let mut rtts = Vec::new();
+        let mut bandwidths = Vec::new();
+        let start = Instant::now();
+        loop {
+            let perf = sock.perfmon()?;
+            if perf.ms_rtt > 0.0 {
+                rtts.push(perf.ms_rtt);
+            }
+            if perf.mbps_bandwidth > 0.0 {
+                bandwidths.push(perf.mbps_bandwidth);
+            }
+            if start.elapsed() >= self.sample_time {
+                break;
+            }
+            thread::sleep(self.sample_interval);
+        }
+
+        let mut decisions = Vec::new();
+        let (rtt_ms, mbps) = match (median(&mut rtts), median(&mut bandwidths)) {
+            (Some(rtt), Some(mbps)) => (rtt, mbps),
+            _ => {
+                log_decision(
+                    &mut decisions,
+                    format!(
+                        "no RTT or bandwidth measured on {:?}; keeping current settings",
+                        sock
+                    ),
+                );
+                return Ok(Tuning {
+                    rtt: Duration::from_secs(0),
+                    bandwidth_mbps: 0.0,
+                    bdp_bytes: 0,
+                    recommended: Recommendation::from_snapshot(&current),
+                    adjusted: Vec::new(),
+                    decisions,
+                });
+            }
+        };
+        let rtt = Duration::from_micros((rtt_ms * 1000.0) as u64);
+        let bdp = bdp_bytes(rtt, mbps);
+        log_decision(
+            &mut decisions,
+            format!(
+                "measured {:?}: RTT {:.1}ms, bandwidth {:.1}Mb/s, BDP {} bytes",
+                sock, rtt_ms, mbps, bdp
+            ),
+        );
+
+        let recommended = self.recommend(rtt, mbps, &current);
+        let mut adjusted = Vec::new();
+        // in the same order as `apply_options`: the window before the buffers it caps
+        let changes: [(&'static str, i32, i32, Setter); 5] = [
+            ("UDT_FC", current.fc, recommended.fc, |s, v| {
+                s.setsockopt(UdtOpts::UDT_FC, v)
+            }),
+            ("UDT_SNDBUF", current.sndbuf, recommended.sndbuf, |s, v| {
+                s.setsockopt(UdtOpts::UDT_SNDBUF, v)
+            }),
+            ("UDT_RCVBUF", current.rcvbuf, recommended.rcvbuf, |s, v| {
+                s.setsockopt(UdtOpts::UDT_RCVBUF, v)
+            }),
+            (
+                "UDP_SNDBUF",
+                current.udp_sndbuf,
+                recommended.udp_sndbuf,
+                |s, v| s.setsockopt(UdtOpts::UDP_SNDBUF, v),
+            ),
+            (
+                "UDP_RCVBUF",
+                current.udp_rcvbuf,
+                recommended.udp_rcvbuf,
+                |s, v| s.setsockopt(UdtOpts::UDP_RCVBUF, v),
+            ),
+        ];
+        for &(name, from, to, set) in changes.iter() {
+            if to <= from {
+                log_decision(
+                    &mut decisions,
+                    format!("{} of {} is large enough", name, from),
+                );
+                continue;
+            }
+            match set(sock, to) {
+                Ok(()) => {
+                    adjusted.push(name);
+                    log_decision(
+                        &mut decisions,
+                        format!("raised {} from {} to {}", name, from, to),
+                    );
+                }
+                Err(e) => log_decision(
+                    &mut decisions,
+                    format!(
+                        "{} can't be raised from {} to {} on this connection ({}); \
+                         recommended for the next one",
+                        name, from, to, e
+                    ),
+                ),
+            }
+        }
+
+        Ok(Tuning {
+            rtt,
+            bandwidth_mbps: mbps,
+            bdp_bytes: bdp,
+            recommended,
+            adjusted,
+            decisions,
+        })
+    }
+
+    /// Computes the settings for a path with the given RTT and bandwidth, starting from the
+    /// `current` ones.
+    pub fn recommend(
+        &self,
+        rtt: Duration,
+        bandwidth_mbps: f64,
+        current: &OptionSnapshot,
+    ) -> Recommendation {
+        let bdp = bdp_bytes(rtt, bandwidth_mbps);
+        let target = std::cmp::min(
+            (bdp as f64 * self.headroom).ceil() as u64,
+            self.max_buffer as u64,
+        ) as i32;
+        let sndbuf = std::cmp::max(current.sndbuf, target);
+        let rcvbuf = std::cmp::max(current.rcvbuf, target);
+        // the flow window counts packets of MSS - 28 bytes; it limits the data in flight and
+        // UDT caps UDT_RCVBUF at it, so it has to cover the larger buffer
+        let packet = i64::from(std::cmp::max(current.mss - 28, 1));
+        let window = (i64::from(std::cmp::max(sndbuf, rcvbuf)) + packet - 1) / packet;
+        Recommendation {
+            fc: std::cmp::max(
+                current.fc,
+                std::cmp::min(window, i64::from(i32::MAX)) as i32,
+            ),
+            sndbuf,
+            rcvbuf,
+            udp_sndbuf: std::cmp::max(current.udp_sndbuf, target),
+            udp_rcvbuf: std::cmp::max(current.udp_rcvbuf, target),
+        }
+    }
+}
+
+fn bdp_bytes(rtt: Duration, bandwidth_mbps: f64) -> u64 {
+    (bandwidth_mbps * 1_000_000.0 / 8.0 * rtt.as_secs_f64()).ceil() as u64
+}
+
+fn median(samples: &mut [f64]) -> Option<f64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(samples[samples.len() / 2])
+}
+
+fn log_decision(decisions: &mut Vec<String>, decision: String) {
+    debug!("tuning: {}", decision);
+    decisions.push(decision);
+}
+
+type Setter = fn(&UdtSocket, i32) -> Result<(), UdtError>;
+
+/// Window and buffer sizes chosen by the tuner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recommendation {
+    /// `UDT_FC`, in packets
+    pub fc: i32,
+    /// `UDT_SNDBUF`, in bytes
+    pub sndbuf: i32,
+    /// `UDT_RCVBUF`, in bytes
+    pub rcvbuf: i32,
+    /// `UDP_SNDBUF`, in bytes
+    pub udp_sndbuf: i32,
+    /// `UDP_RCVBUF`, in bytes
+    pub udp_rcvbuf: i32,
+}
+
+impl Recommendation {
+    fn from_snapshot(snapshot: &OptionSnapshot) -> Recommendation {
+        Recommendation {
+            fc: snapshot.fc,
+            sndbuf: snapshot.sndbuf,
+            rcvbuf: snapshot.rcvbuf,
+            udp_sndbuf: snapshot.udp_sndbuf,
+            udp_rcvbuf: snapshot.udp_rcvbuf,
+        }
+    }
+
+    /// Copies these settings into `snapshot`, e.g. to apply them together with other options.
+    pub fn apply_to(&self, snapshot: &mut OptionSnapshot) {
+        snapshot.fc = self.fc;
+        snapshot.sndbuf = self.sndbuf;
+        snapshot.rcvbuf = self.rcvbuf;
+        snapshot.udp_sndbuf = self.udp_sndbuf;
+        snapshot.udp_rcvbuf = self.udp_rcvbuf;
+    }
+
+    /// Sets these options on a socket that is not bound or connected yet.
+    pub fn apply(&self, sock: &UdtSocket) -> Result<(), UdtError> {
+        let mut options = sock.options()?;
+        self.apply_to(&mut options);
+        sock.apply_options(&options)
+    }
+}
+
+/// The result of tuning a connection
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    /// The median RTT measured, or zero if none was
+    pub rtt: Duration,
+    /// The median bandwidth estimate, in Mb/s, or zero if none was measured
+    pub bandwidth_mbps: f64,
+    /// The bandwidth-delay product, in bytes
+    pub bdp_bytes: u64,
+    /// The settings to use for this path
+    pub recommended: Recommendation,
+    /// The options that were raised on the tuned connection.  UDT4 refuses them all once a
+    /// socket is connected, so this is normally empty.
+    pub adjusted: Vec<&'static str>,
+    /// A description of every decision, in order
+    pub decisions: Vec<String>,
+}
+
+#[test]
+fn test_tuning_long_fat_path() {
+    let current = crate::snapshot::defaults();
+    // 10Gb/s with a 100ms RTT holds 125MB
+    let rec = TuningConfig::new().recommend(Duration::from_millis(100), 10_000.0, &current);
+    assert_eq!(bdp_bytes(Duration::from_millis(100), 10_000.0), 125_000_000);
+    assert_eq!(rec.sndbuf, 250_000_000);
+    assert_eq!(rec.rcvbuf, 250_000_000);
+    assert_eq!(rec.udp_rcvbuf, 250_000_000);
+    // enough 1472-byte packets to cover the buffers
+    assert_eq!(rec.fc, 169_837);
+
+    // capped at the maximum buffer size
+    let rec = TuningConfig::new().max_buffer(64 * 1024 * 1024).recommend(
+        Duration::from_millis(100),
+        10_000.0,
+        &current,
+    );
+    assert_eq!(rec.sndbuf, 64 * 1024 * 1024);
+
+    // 100Gb/s at 200ms is more than an i32 can count, so it stops at the largest buffer
+    let rec = TuningConfig::new().max_buffer(usize::MAX).recommend(
+        Duration::from_millis(200),
+        100_000.0,
+        &current,
+    );
+    assert_eq!(rec.rcvbuf, i32::MAX);
+    assert_eq!(rec.fc, 1_458_889);
+}
+
+#[test]
+fn test_tuning_never_shrinks() {
+    let current = crate::snapshot::defaults();
+    // a LAN needs far less than the defaults
+    let rec = TuningConfig::new().recommend(Duration::from_micros(200), 1_000.0, &current);
+    assert_eq!(rec, Recommendation::from_snapshot(&current));
+
+    let mut applied = current.clone();
+    let rec = TuningConfig::new().recommend(Duration::from_millis(50), 5_000.0, &current);
+    rec.apply_to(&mut applied);
+    let changed: Vec<_> = current.diff(&applied).iter().map(|d| d.option).collect();
+    assert_eq!(
+        changed,
+        [
+            "UDT_FC",
+            "UDT_SNDBUF",
+            "UDT_RCVBUF",
+            "UDP_SNDBUF",
+            "UDP_RCVBUF"
+        ]
+    );
+}
@@ -0,0 +1,439 @@
+//! Typed message channels using serde
+//!
+//! A `TypedChannel<T>` sends and receives values of type `T` instead of byte buffers.  Every
+//! message carries a short header with the encoding and a schema version chosen by the
+//! application, so that a peer running an incompatible version of `T` is detected instead of
+//! producing garbage.
+//!
+//! Messages are carried by any [`MessageTransport`](trait.MessageTransport.html): a
+//! `FramedStream` over a `Stream` socket, or a `FragmentSocket` over a `Datagram` socket.
+//!
+//! Values are encoded with bincode (the `bincode` feature) or CBOR (the `cbor` feature).  When
+//! both are enabled the sender picks one with [`with_codec`](struct.TypedChannel.html#method.with_codec),
+//! and the receiver accepts either.
+//!
+//! A message that can't be decoded, has the wrong schema version, or was rejected by the
+//! transport (for example because its checksum didn't match) is reported as an error but has
+//! been fully consumed, so the channel can keep receiving.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use serde::{Deserialize, Serialize};
+//! use udt::*;
+//! use udt::framed::FramedStream;
+//! use udt::typed::TypedChannel;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! enum Request {
+//!     Get { key: String },
+//!     Put { key: String, value: Vec<u8> },
+//! }
+//!
+//! fn main() {
+//!     init();
+//!     let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//!     let mut requests = TypedChannel::<Request, _>::new(FramedStream::new(stream), 2);
+//!     requests.send(&Request::Get { key: "motd".into() }).unwrap();
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::fragment::{FragmentError, FragmentSocket};
+use crate::framed::{FrameError, FramedStream};
+
+const HEADER_LEN: usize = 5;
+
+/// Something that sends and receives whole messages
+///
+/// This is implemented for `FramedStream` and `FragmentSocket`, and can be implemented for other
+/// message-oriented transports.  Implementations return `TypedError::Rejected` if only one
+/// message was affected, and `TypedError::Io` if the transport can't be used any more.
+pub trait MessageTransport {
+    /// Sends one message.
+    fn send_message(&mut self, msg: &[u8]) -> Result<(), TypedError>;
+
+    /// Receives the next message.  Returns `None` if the transport was closed cleanly.
+    fn recv_message(&mut self) -> Result<Option<Vec<u8>>, TypedError>;
+}
+
+fn frame_err(e: FrameError) -> TypedError {
+    match e {
+        // the whole frame has been read by the time its checksum is checked
+        e @ FrameError::Checksum => TypedError::Rejected(e.into()),
+        e => TypedError::Io(e.into()),
+    }
+}
+
+impl<S: Read + Write> MessageTransport for FramedStream<S> {
+    fn send_message(&mut self, msg: &[u8]) -> Result<(), TypedError> {
+        self.send_frame(msg).map_err(|e| match e {
+            e @ FrameError::TooLarge { .. } => TypedError::Rejected(e.into()),
+            e => frame_err(e),
+        })
+    }
+
+    fn recv_message(&mut self) -> Result<Option<Vec<u8>>, TypedError> {
+        self.recv_frame().map_err(frame_err)
+    }
+}
+
+fn fragment_err(e: FragmentError) -> TypedError {
+    match e {
+        FragmentError::Udt(e) => TypedError::Io(e.into()),
+        // a bad fragment only spoils the message it belongs to
+        e => TypedError::Rejected(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+impl MessageTransport for FragmentSocket {
+    fn send_message(&mut self, msg: &[u8]) -> Result<(), TypedError> {
+        self.send(msg).map_err(fragment_err)
+    }
+
+    fn recv_message(&mut self) -> Result<Option<Vec<u8>>, TypedError> {
+        match self.recv() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(FragmentError::Udt(ref e)) if e.err_code == crate::raw::ECONNLOST => Ok(None),
+            Err(e) => Err(fragment_err(e)),
+        }
+    }
+}
+
+impl<M: MessageTransport + ?Sized> MessageTransport for &mut M {
+    fn send_message(&mut self, msg: &[u8]) -> Result<(), TypedError> {
+        (**self).send_message(msg)
+    }
+
+    fn recv_message(&mut self) -> Result<Option<Vec<u8>>, TypedError> {
+        (**self).recv_message()
+    }
+}
+
+/// How values are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// bincode 1 with its default options (requires the `bincode` feature)
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// CBOR (requires the `cbor` feature)
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Default for Codec {
+    /// bincode if it is enabled, CBOR otherwise
+    fn default() -> Codec {
+        #[cfg(feature = "bincode")]
+        return Codec::Bincode;
+        #[cfg(not(feature = "bincode"))]
+        return Codec::Cbor;
+    }
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => 1,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            #[cfg(feature = "bincode")]
+            1 => Some(Codec::Bincode),
+            #[cfg(feature = "cbor")]
+            2 => Some(Codec::Cbor),
+            _ => None,
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T, out: &mut Vec<u8>) -> Result<(), String> {
+        match self {
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serialize_into(out, value).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::ser::into_writer(value, out).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, String> {
+        match self {
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::deserialize(data).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::de::from_reader(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Errors from a `TypedChannel`
+///
+/// Only `Io` and `Closed` mean that the channel can't be used any more.
+#[derive(Debug)]
+pub enum TypedError {
+    /// The peer uses a different schema version
+    SchemaMismatch {
+        /// The version this channel was created with
+        expected: u32,
+        /// The version of the received message
+        found: u32,
+    },
+    /// The message was encoded with a codec that isn't enabled on this side
+    UnknownCodec(u8),
+    /// The message couldn't be decoded as a `T`
+    Decode(String),
+    /// The value couldn't be encoded
+    Encode(String),
+    /// The message was too short to contain a header
+    Truncated,
+    /// The transport rejected a single message, e.g. a received frame whose checksum didn't
+    /// match or a message too large to send.  It was dropped, and the channel can still be used.
+    Rejected(io::Error),
+    /// The peer closed the connection
+    Closed,
+    /// The transport failed
+    Io(io::Error),
+}
+
+impl fmt::Display for TypedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TypedError::SchemaMismatch { expected, found } => write!(
+                f,
+                "schema version mismatch: expected {}, got {}",
+                expected, found
+            ),
+            TypedError::UnknownCodec(id) => write!(f, "unknown codec id {}", id),
+            TypedError::Decode(ref msg) => write!(f, "could not decode message: {}", msg),
+            TypedError::Encode(ref msg) => write!(f, "could not encode message: {}", msg),
+            TypedError::Truncated => write!(f, "message too short"),
+            TypedError::Rejected(ref e) => write!(f, "message rejected: {}", e),
+            TypedError::Closed => write!(f, "connection closed"),
+            TypedError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TypedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            TypedError::Rejected(ref e) | TypedError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TypedError {
+    fn from(e: io::Error) -> TypedError {
+        TypedError::Io(e)
+    }
+}
+
+impl TypedError {
+    /// Returns true if the error only concerned one message, and the channel can still be used.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(*self, TypedError::Io(_) | TypedError::Closed)
+    }
+}
+
+/// Sends and receives values of type `T` over a `MessageTransport`
+///
+/// # Wire format
+///
+/// Each message starts with a codec id byte (1 for bincode, 2 for CBOR) and the schema version
+/// as a 32-bit big-endian integer, followed by the encoded value.
+pub struct TypedChannel<T, M> {
+    transport: M,
+    schema_version: u32,
+    codec: Codec,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T, M: fmt::Debug> fmt::Debug for TypedChannel<T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedChannel")
+            .field("transport", &self.transport)
+            .field("schema_version", &self.schema_version)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+impl<T, M> TypedChannel<T, M>
+where
+    T: Serialize + DeserializeOwned,
+    M: MessageTransport,
+{
+    /// Creates a channel using the default codec.
+    ///
+    /// `schema_version` should be changed whenever `T` changes incompatibly; messages with a
+    /// different version are rejected with `TypedError::SchemaMismatch`.
+    pub fn new(transport: M, schema_version: u32) -> TypedChannel<T, M> {
+        TypedChannel {
+            transport,
+            schema_version,
+            codec: Codec::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the codec used for sending.  Any enabled codec is accepted when receiving.
+    pub fn with_codec(mut self, codec: Codec) -> TypedChannel<T, M> {
+        self.codec = codec;
+        self
+    }
+
+    /// Returns the underlying transport.
+    pub fn get_ref(&self) -> &M {
+        &self.transport
+    }
+
+    /// Returns the underlying transport.
+    pub fn into_inner(self) -> M {
+        self.transport
+    }
+
+    /// Encodes and sends a value.
+    pub fn send(&mut self, value: &T) -> Result<(), TypedError> {
+        let mut msg = Vec::with_capacity(64);
+        msg.push(self.codec.id());
+        msg.extend_from_slice(&self.schema_version.to_be_bytes());
+        self.codec
+            .encode(value, &mut msg)
+            .map_err(TypedError::Encode)?;
+        self.transport.send_message(&msg)?;
+        Ok(())
+    }
+
+    /// Receives and decodes the next value.
+    ///
+    /// Returns `TypedError::Closed` if the peer closed the connection.
+    pub fn recv(&mut self) -> Result<T, TypedError> {
+        let msg = match self.transport.recv_message()? {
+            Some(msg) => msg,
+            None => return Err(TypedError::Closed),
+        };
+        if msg.len() < HEADER_LEN {
+            return Err(TypedError::Truncated);
+        }
+        let codec = Codec::from_id(msg[0]).ok_or(TypedError::UnknownCodec(msg[0]))?;
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&msg[1..HEADER_LEN]);
+        let version = u32::from_be_bytes(version);
+        if version != self.schema_version {
+            return Err(TypedError::SchemaMismatch {
+                expected: self.schema_version,
+                found: version,
+            });
+        }
+        codec.decode(&msg[HEADER_LEN..]).map_err(TypedError::Decode)
+    }
+}
+
+#[test]
+fn test_typed_channel() {
+    use std::collections::VecDeque;
+
+    #[derive(Default)]
+    struct Queue(VecDeque<Vec<u8>>);
+
+    impl MessageTransport for Queue {
+        fn send_message(&mut self, msg: &[u8]) -> Result<(), TypedError> {
+            self.0.push_back(msg.to_vec());
+            Ok(())
+        }
+
+        fn recv_message(&mut self) -> Result<Option<Vec<u8>>, TypedError> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    let mut queue = Queue::default();
+    {
+        let mut v1 = TypedChannel::<(String, u64), _>::new(&mut queue, 1);
+        v1.send(&("one".to_string(), 1)).unwrap();
+        v1.send(&("two".to_string(), 2)).unwrap();
+    }
+    TypedChannel::<(String, u64), _>::new(&mut queue, 2)
+        .send(&("three".to_string(), 3))
+        .unwrap();
+    queue.0.push_back(vec![1, 0]);
+    queue.0.push_back(vec![0xee, 0, 0, 0, 1]);
+
+    let mut v1 = TypedChannel::<(String, u64), _>::new(queue, 1);
+    assert_eq!(v1.recv().unwrap(), ("one".to_string(), 1));
+
+    // a message of the wrong type doesn't affect later ones
+    v1.transport
+        .0
+        .push_front(vec![Codec::default().id(), 0, 0, 0, 1, 0xff]);
+    match v1.recv() {
+        Err(ref e @ TypedError::Decode(_)) => assert!(e.is_recoverable()),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(v1.recv().unwrap(), ("two".to_string(), 2));
+
+    match v1.recv() {
+        Err(TypedError::SchemaMismatch {
+            expected: 1,
+            found: 2,
+        }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(v1.recv(), Err(TypedError::Truncated)));
+    assert!(matches!(v1.recv(), Err(TypedError::UnknownCodec(0xee))));
+    assert!(matches!(v1.recv(), Err(TypedError::Closed)));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_typed_channel_after_bad_checksum() {
+    use crate::framed::FrameConfig;
+
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut raw = a.try_clone().unwrap();
+    let config = FrameConfig::new().checksum(true);
+    let mut tx = TypedChannel::<String, _>::new(config.wrap(a), 1);
+    let mut rx = TypedChannel::<String, _>::new(config.wrap(b), 1);
+
+    tx.send(&"first".to_string()).unwrap();
+    // a whole frame whose payload doesn't match its checksum
+    raw.write_all(&[5, Codec::default().id(), 0, 0, 0, 1, 0, 0, 0, 0])
+        .unwrap();
+    tx.send(&"second".to_string()).unwrap();
+    drop((tx, raw));
+
+    assert_eq!(rx.recv().unwrap(), "first");
+    match rx.recv() {
+        Err(ref e @ TypedError::Rejected(_)) => assert!(e.is_recoverable()),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(rx.recv().unwrap(), "second");
+    assert!(matches!(rx.recv(), Err(TypedError::Closed)));
+}
+
+#[cfg(all(unix, feature = "bincode", feature = "cbor"))]
+#[test]
+fn test_typed_channel_mixed_codecs() {
+    let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut tx = TypedChannel::<Vec<i32>, _>::new(FramedStream::new(a), 7);
+    let mut rx = TypedChannel::<Vec<i32>, _>::new(FramedStream::new(b), 7);
+    tx.send(&vec![1, 2, 3]).unwrap();
+    let mut tx = tx.with_codec(Codec::Cbor);
+    tx.send(&vec![-4]).unwrap();
+    drop(tx);
+    assert_eq!(rx.recv().unwrap(), vec![1, 2, 3]);
+    assert_eq!(rx.recv().unwrap(), vec![-4]);
+    assert!(matches!(rx.recv(), Err(TypedError::Closed)));
+}
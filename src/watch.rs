@@ -0,0 +1,287 @@
+//! Notifications of socket state changes
+//!
+//! UDT has no way to be told when a socket changes state, so a `StateWatcher` polls the state
+//! of every watched socket on a background thread and reports each change, either to a callback
+//! or over a channel.  Health checks and tests can then react to a connection breaking instead
+//! of sleeping and checking.
+//!
+//! Because states are polled, a state that lasts less than the poll interval can be skipped: a
+//! socket may be reported going from `CONNECTED` straight to `CLOSED`.  A socket is no longer
+//! watched once it reaches `NONEXIST`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use udt::*;
+//! use udt::watch::WatchConfig;
+//!
+//! init();
+//! let stream = UdtStream::connect("192.0.2.10:9000".parse().unwrap()).unwrap();
+//! let (watcher, changes) = WatchConfig::new()
+//!     .interval(Duration::from_millis(50))
+//!     .channel();
+//! watcher.watch(*stream.socket());
+//! for change in changes {
+//!     println!("{:?}: {:?} -> {:?}", change.socket, change.from, change.to);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{UdtSocket, UdtStatus};
+
+/// A socket moving from one state to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateChange {
+    /// The socket that changed state
+    pub socket: UdtSocket,
+    /// The state it was last seen in
+    pub from: UdtStatus,
+    /// The state it is in now
+    pub to: UdtStatus,
+}
+
+/// Settings for a `StateWatcher`
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    interval: Duration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> WatchConfig {
+        WatchConfig::new()
+    }
+}
+
+impl WatchConfig {
+    /// Creates the default configuration, which polls every 100ms.
+    pub fn new() -> WatchConfig {
+        WatchConfig {
+            interval: Duration::from_millis(100),
+        }
+    }
+
+    /// Sets how often the watched sockets are polled.
+    pub fn interval(mut self, interval: Duration) -> WatchConfig {
+        self.interval = std::cmp::max(interval, Duration::from_millis(1));
+        self
+    }
+
+    /// Starts a watcher that calls `callback` for every change, on the watcher's thread.
+    pub fn callback<F>(self, callback: F) -> StateWatcher
+    where
+        F: FnMut(StateChange) + Send + 'static,
+    {
+        self.start(|s: UdtSocket| s.getstate(), callback)
+    }
+
+    /// Starts a watcher that sends every change to the returned channel.
+    ///
+    /// The watcher keeps running if the receiver is dropped.
+    pub fn channel(self) -> (StateWatcher, Receiver<StateChange>) {
+        let (tx, rx) = mpsc::channel();
+        let watcher = self.callback(move |change| {
+            let _ = tx.send(change);
+        });
+        (watcher, rx)
+    }
+
+    fn start<P, F>(self, probe: P, mut callback: F) -> StateWatcher
+    where
+        P: Fn(UdtSocket) -> UdtStatus + Send + Sync + 'static,
+        F: FnMut(StateChange) + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            sockets: Mutex::new(HashMap::new()),
+            probe: Box::new(probe),
+            stop: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                while !shared.stop.load(Ordering::SeqCst) {
+                    for change in shared.poll() {
+                        callback(change);
+                    }
+                    thread::park_timeout(self.interval);
+                }
+            })
+        };
+        StateWatcher {
+            shared,
+            thread: Some(thread),
+        }
+    }
+}
+
+struct Shared {
+    // the last state seen for each watched socket
+    sockets: Mutex<HashMap<UdtSocket, UdtStatus>>,
+    probe: Box<dyn Fn(UdtSocket) -> UdtStatus + Send + Sync>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn poll(&self) -> Vec<StateChange> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let mut changes = Vec::new();
+        sockets.retain(|&socket, last| {
+            let state = (self.probe)(socket);
+            if state != *last {
+                changes.push(StateChange {
+                    socket,
+                    from: *last,
+                    to: state,
+                });
+                *last = state;
+            }
+            state != UdtStatus::NONEXIST
+        });
+        changes
+    }
+}
+
+/// Watches sockets for state changes on a background thread
+///
+/// The thread stops when the `StateWatcher` is dropped.
+pub struct StateWatcher {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl StateWatcher {
+    /// Starts watching `socket`.  Its current state is not reported, only later changes.
+    pub fn watch(&self, socket: UdtSocket) {
+        let state = (self.shared.probe)(socket);
+        self.shared.sockets.lock().unwrap().insert(socket, state);
+    }
+
+    /// Stops watching `socket`.
+    pub fn unwatch(&self, socket: UdtSocket) {
+        self.shared.sockets.lock().unwrap().remove(&socket);
+    }
+
+    /// Returns the last state seen for `socket`, or `None` if it isn't watched.
+    pub fn state(&self, socket: UdtSocket) -> Option<UdtStatus> {
+        self.shared.sockets.lock().unwrap().get(&socket).cloned()
+    }
+
+    /// Returns the number of sockets being watched.
+    pub fn len(&self) -> usize {
+        self.shared.sockets.lock().unwrap().len()
+    }
+
+    /// Returns true if no sockets are being watched.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl std::fmt::Debug for StateWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StateWatcher")
+            .field("sockets", &*self.shared.sockets.lock().unwrap())
+            .finish()
+    }
+}
+
+impl Drop for StateWatcher {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+type FakeTable = Arc<Mutex<HashMap<UdtSocket, UdtStatus>>>;
+
+// a fake socket table the watcher polls instead of UDT
+#[cfg(test)]
+fn fake() -> (
+    FakeTable,
+    impl Fn(UdtSocket) -> UdtStatus + Send + Sync + 'static,
+) {
+    let table = Arc::new(Mutex::new(HashMap::new()));
+    let probe = {
+        let table = table.clone();
+        move |s| {
+            *table
+                .lock()
+                .unwrap()
+                .get(&s)
+                .unwrap_or(&UdtStatus::NONEXIST)
+        }
+    };
+    (table, probe)
+}
+
+#[test]
+fn test_watch_reports_transitions() {
+    let (table, probe) = fake();
+    let sock = UdtSocket::wrap_raw(7);
+    table.lock().unwrap().insert(sock, UdtStatus::CONNECTING);
+
+    let (tx, rx) = mpsc::channel();
+    let watcher = WatchConfig::new()
+        .interval(Duration::from_millis(1))
+        .start(probe, move |c| tx.send(c).unwrap());
+    watcher.watch(sock);
+
+    let path = [
+        UdtStatus::CONNECTED,
+        UdtStatus::BROKEN,
+        UdtStatus::CLOSED,
+        UdtStatus::NONEXIST,
+    ];
+    let mut from = UdtStatus::CONNECTING;
+    for &to in path.iter() {
+        table.lock().unwrap().insert(sock, to);
+        let change = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            change,
+            StateChange {
+                socket: sock,
+                from,
+                to
+            }
+        );
+        from = to;
+    }
+    // NONEXIST is final
+    let start = std::time::Instant::now();
+    while !watcher.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(watcher.state(sock), None);
+}
+
+#[test]
+fn test_watch_unwatch_and_drop() {
+    let (table, probe) = fake();
+    let sock = UdtSocket::wrap_raw(9);
+    table.lock().unwrap().insert(sock, UdtStatus::CONNECTED);
+
+    let (tx, rx) = mpsc::channel();
+    let watcher = WatchConfig::new()
+        .interval(Duration::from_secs(3600))
+        .start(probe, move |c| tx.send(c).unwrap());
+    watcher.watch(sock);
+    assert_eq!(watcher.state(sock), Some(UdtStatus::CONNECTED));
+    watcher.unwatch(sock);
+    table.lock().unwrap().insert(sock, UdtStatus::BROKEN);
+    // dropping wakes the thread even with a long interval
+    let start = std::time::Instant::now();
+    drop(watcher);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(rx.try_recv().is_err());
+}
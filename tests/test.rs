@@ -27,8 +27,16 @@ fn test_getsockopt() {
     assert_eq!(sock.getsockopt(UdtOpts::UDT_RCVSYN).unwrap(), true);
     assert_eq!(sock.getsockopt(UdtOpts::UDT_FC).unwrap(), 25600 as i32);
     assert_eq!(sock.getsockopt(UdtOpts::UDT_RENDEZVOUS).unwrap(), false);
-    assert_eq!(sock.getsockopt(UdtOpts::UDT_SNDTIMEO).unwrap(), -1);
-    assert_eq!(sock.getsockopt(UdtOpts::UDT_RCVTIMEO).unwrap(), -1);
+    assert_eq!(sock.getsockopt(UdtOpts::UDT_SNDTIMEO).unwrap(), None);
+    assert_eq!(sock.getsockopt(UdtOpts::UDT_RCVTIMEO).unwrap(), None);
+    assert_eq!(
+        sock.getsockopt(UdtOpts::UDT_LINGER).unwrap(),
+        Some(std::time::Duration::from_secs(180))
+    );
+    assert_eq!(
+        sock.getsockopt(UdtOpts::UDT_STATE).unwrap(),
+        UdtStatus::INIT
+    );
 }
 
 #[test]
@@ -40,6 +48,40 @@ fn test_setsockopt() {
     assert_eq!(sock.getsockopt(UdtOpts::UDT_MSS).unwrap(), 1500);
     sock.setsockopt(UdtOpts::UDT_MSS, 1400).unwrap();
     assert_eq!(sock.getsockopt(UdtOpts::UDT_MSS).unwrap(), 1400);
+
+    let timeout = Some(std::time::Duration::from_millis(250));
+    sock.setsockopt(UdtOpts::UDT_RCVTIMEO, timeout).unwrap();
+    assert_eq!(sock.getsockopt(UdtOpts::UDT_RCVTIMEO).unwrap(), timeout);
+    sock.setsockopt(UdtOpts::UDT_LINGER, None).unwrap();
+    assert_eq!(sock.getsockopt(UdtOpts::UDT_LINGER).unwrap(), None);
+}
+
+#[test]
+fn test_option_snapshot() {
+    use std::time::Duration;
+
+    init();
+    let mut configured = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    do_platform_specific_init(&mut configured);
+    configured.setsockopt(UdtOpts::UDT_MSS, 1400).unwrap();
+    configured
+        .setsockopt(UdtOpts::UDT_RCVBUF, 2 * 1024 * 1024)
+        .unwrap();
+    configured
+        .setsockopt(UdtOpts::UDT_RCVTIMEO, Some(Duration::from_secs(2)))
+        .unwrap();
+    let expected = configured.options().unwrap();
+    assert_eq!(expected.mss, 1400);
+    assert_eq!(expected.state, UdtStatus::INIT);
+
+    let fresh = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    let diffs = expected.diff(&fresh.options().unwrap());
+    let names: Vec<_> = diffs.iter().map(|d| d.option).collect();
+    assert!(names.contains(&"UDT_MSS"), "{:?}", names);
+    assert!(names.contains(&"UDT_RCVTIMEO"), "{:?}", names);
+
+    fresh.apply_options(&expected).unwrap();
+    assert_eq!(expected.diff(&fresh.options().unwrap()), vec![]);
 }
 
 #[test]
@@ -461,3 +503,1190 @@ fn test_epoll3() {
     });
     server.join().unwrap();
 }
+
+fn localhost_listener() -> UdtListener {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+    let mut sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    do_platform_specific_init(&mut sock);
+    let localhost = Ipv4Addr::new(127, 0, 0, 1);
+    sock.bind(SocketAddr::V4(SocketAddrV4::new(localhost, 0)))
+        .unwrap();
+    sock.listen(5).unwrap();
+    UdtListener::from_socket(sock)
+}
+
+#[test]
+fn test_listener_incoming() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let closer = listener.closer();
+
+    let server = spawn(move || {
+        let mut count = 0;
+        for conn in listener.incoming() {
+            let (mut stream, peer) = conn.unwrap();
+            debug!("Server recieved connection from {:?}", peer);
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+            count += 1;
+        }
+        count
+    });
+
+    for _ in 0..2 {
+        let mut stream = UdtStream::connect(addr).unwrap();
+        stream.write_all(b"hello").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    closer.close();
+    assert_eq!(server.join().unwrap(), 2);
+}
+
+#[test]
+fn test_accept_timeout() {
+    use std::time::{Duration, Instant};
+
+    init();
+
+    let listener = localhost_listener();
+    let start = Instant::now();
+    let err = listener
+        .accept_timeout(Duration::from_millis(300))
+        .unwrap_err();
+    assert_eq!(err.err_code, 6003); // ETIMEOUT
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    let addr = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || UdtStream::connect(addr).unwrap());
+    let (_stream, peer) = listener.accept_timeout(Duration::from_secs(5)).unwrap();
+    debug!("Server recieved connection from {:?}", peer);
+    client.join().unwrap();
+}
+
+#[test]
+fn test_close_wakes_accept() {
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    init();
+
+    let listener = localhost_listener();
+    let closer = listener.closer();
+
+    let server = spawn(move || listener.accept().unwrap_err().err_code);
+    sleep(Duration::from_millis(200));
+    closer.close();
+    assert_eq!(server.join().unwrap(), ELISTENERCLOSED);
+}
+
+// binds the client to `src` so that tests can use several loopback source addresses
+#[cfg(target_os = "linux")]
+fn connect_from(src: std::net::Ipv4Addr, addr: std::net::SocketAddr) -> UdtStream {
+    use std::net::{SocketAddr, SocketAddrV4};
+
+    let mut sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    do_platform_specific_init(&mut sock);
+    sock.bind(SocketAddr::V4(SocketAddrV4::new(src, 0)))
+        .unwrap();
+    sock.connect(addr).unwrap();
+    UdtStream::from_socket(sock)
+}
+
+// accepts through `policy` until no connection arrives for a while
+#[cfg(target_os = "linux")]
+fn admit_all(
+    policy: &admission::AdmissionPolicy,
+    listener: &UdtListener,
+) -> Vec<admission::Admitted> {
+    use std::time::Duration;
+
+    let mut admitted = Vec::new();
+    loop {
+        match policy.accept_timeout(listener, Duration::from_millis(1500)) {
+            Ok((conn, peer)) => {
+                debug!("Admitted connection from {:?}", peer);
+                admitted.push(conn);
+            }
+            Err(ref e) if e.err_code == 6003 => return admitted,
+            Err(e) => panic!("accept failed: {}", e),
+        }
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_admission_policy() {
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+    use udt::admission::AdmissionPolicy;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let policy = AdmissionPolicy::new()
+        .allow("127.0.0.0/29".parse().unwrap())
+        .deny("127.0.0.2".parse().unwrap())
+        .max_per_source(1);
+
+    let (done_tx, done_rx) = channel();
+    let clients = spawn(move || {
+        let _denied = connect_from(Ipv4Addr::new(127, 0, 0, 2), addr);
+        let _outsider = connect_from(Ipv4Addr::new(127, 0, 0, 9), addr);
+        let _first = connect_from(Ipv4Addr::new(127, 0, 0, 3), addr);
+        let _second = connect_from(Ipv4Addr::new(127, 0, 0, 3), addr);
+        // keep all connections open until the server has looked at them
+        done_rx.recv().unwrap();
+    });
+
+    let admitted = admit_all(&policy, &listener);
+    assert_eq!(admitted.len(), 1);
+    assert_eq!(
+        admitted[0].peer_addr().unwrap().ip(),
+        Ipv4Addr::new(127, 0, 0, 3)
+    );
+
+    let stats = policy.stats();
+    assert_eq!(stats.admitted, 1);
+    assert_eq!(stats.denied, 1);
+    assert_eq!(stats.not_allowed, 1);
+    assert_eq!(stats.too_many_from_source, 1);
+    assert_eq!(stats.rejected(), 3);
+
+    let source = Ipv4Addr::new(127, 0, 0, 3).into();
+    assert_eq!(policy.open_connections(source), 1);
+    drop(admitted);
+    assert_eq!(policy.open_connections(source), 0);
+
+    done_tx.send(()).unwrap();
+    clients.join().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_admission_rate_limit() {
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::admission::AdmissionPolicy;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let policy = AdmissionPolicy::new().rate_limit(2, Duration::from_secs(60));
+
+    let (done_tx, done_rx) = channel();
+    let clients = spawn(move || {
+        let conns: Vec<_> = (1..4)
+            .map(|i| connect_from(Ipv4Addr::new(127, 0, 0, i), addr))
+            .collect();
+        done_rx.recv().unwrap();
+        drop(conns);
+    });
+
+    let admitted = admit_all(&policy, &listener);
+    assert_eq!(admitted.len(), 2);
+    assert_eq!(policy.stats().rate_limited, 1);
+
+    done_tx.send(()).unwrap();
+    clients.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "auth")]
+fn test_auth_handshake() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use udt::auth::{Handshake, HandshakeError};
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let handshake = Handshake::new(b"sekrit").version("test/1");
+
+        // the first client knows the key
+        let (stream, _) = listener.accept().unwrap();
+        let (mut stream, version) = handshake.accept(stream).unwrap();
+        assert_eq!(version, "test/1");
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // the second one doesn't
+        let (stream, _) = listener.accept().unwrap();
+        match handshake.accept(stream) {
+            Err(HandshakeError::AuthFailed) => {}
+            other => panic!("expected AuthFailed, got {:?}", other.map(|r| r.1)),
+        }
+    });
+
+    let good = Handshake::new(b"sekrit")
+        .version("test/2")
+        .version("test/1");
+    let (mut stream, version) = good.connect(UdtStream::connect(addr).unwrap()).unwrap();
+    assert_eq!(version, "test/1");
+    stream.write_all(b"hello").unwrap();
+
+    let bad = Handshake::new(b"guess").version("test/1");
+    match bad.connect(UdtStream::connect(addr).unwrap()) {
+        Err(HandshakeError::AuthFailed) => {}
+        other => panic!("expected AuthFailed, got {:?}", other.map(|r| r.1)),
+    }
+
+    server.join().unwrap();
+}
+
+#[cfg(feature = "tls")]
+fn tls_configs() -> (
+    std::sync::Arc<udt::tls::rustls::ClientConfig>,
+    std::sync::Arc<udt::tls::rustls::ServerConfig>,
+) {
+    use std::sync::Arc;
+    use udt::tls::rustls::pki_types::PrivatePkcs8KeyDer;
+    use udt::tls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+    // a fresh self-signed certificate for every test run
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert_der = cert.cert.der().clone();
+    let key_der = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+    let server = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der.clone()], key_der.into())
+        .unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert_der).unwrap();
+    let client = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    (Arc::new(client), Arc::new(server))
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_tls_stream() {
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use udt::tls::rustls::pki_types::ServerName;
+
+    init();
+
+    let (client_config, server_config) = tls_configs();
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut tls = udt::tls::accept(server_config, stream).unwrap();
+        let mut buf = [0u8; 5];
+        tls.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        tls.write_all(b"world").unwrap();
+
+        // the client closes the session cleanly
+        let mut rest = Vec::new();
+        tls.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut tls = udt::tls::connect(client_config, name, stream).unwrap();
+    assert!(tls.connection().peer_certificates().is_some());
+    tls.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    tls.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"world");
+    tls.shutdown().unwrap();
+
+    server.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_tls_untrusted_certificate() {
+    use std::convert::TryFrom;
+    use std::thread::spawn;
+    use udt::tls::rustls::pki_types::ServerName;
+
+    init();
+
+    // the client trusts a different self-signed certificate than the one the server uses
+    let (client_config, _) = tls_configs();
+    let (_, server_config) = tls_configs();
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        assert!(udt::tls::accept(server_config, stream).is_err());
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let err = udt::tls::connect(client_config, name, stream).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    server.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "aead")]
+fn test_aead_datagrams() {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::aead::{AeadConfig, AeadError};
+
+    init();
+
+    let mut sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    do_platform_specific_init(&mut sock);
+    let localhost = Ipv4Addr::new(127, 0, 0, 1);
+    sock.bind(SocketAddr::V4(SocketAddrV4::new(localhost, 0)))
+        .unwrap();
+    sock.listen(5).unwrap();
+    let addr = sock.getsockname().unwrap();
+
+    let server = spawn(move || {
+        let config = AeadConfig::new().psk(b"sekrit");
+
+        let (peer, _) = sock.accept().unwrap();
+        let secure = config.accept(peer).unwrap();
+        let mut buf = [0u8; 64];
+        let msg = secure.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..msg.len], b"first");
+        assert_eq!((msg.seq, msg.skipped, msg.late), (1, 0, false));
+        let msg = secure.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..msg.len], b"second");
+        assert_eq!(msg.seq, 2);
+        secure.send(b"reply").unwrap();
+
+        // a client with the wrong key is rejected during setup
+        let (peer, _) = sock.accept().unwrap();
+        match config.accept(peer) {
+            Err(AeadError::Handshake(_)) => {}
+            other => panic!("expected a handshake failure, got {:?}", other),
+        }
+        sock.close().unwrap();
+    });
+
+    let client = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    client.connect(addr).unwrap();
+    let secure = AeadConfig::new().psk(b"sekrit").connect(client).unwrap();
+    assert_eq!(secure.send(b"first").unwrap(), 1);
+    let seq = secure
+        .send_ttl(b"second", Some(Duration::from_secs(5)), false)
+        .unwrap();
+    assert_eq!(seq, 2);
+    let mut buf = [0u8; 64];
+    let msg = secure.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..msg.len], b"reply");
+    assert_eq!(secure.stats().sent, 2);
+    assert_eq!(secure.stats().received, 1);
+
+    let client = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    client.connect(addr).unwrap();
+    // the server hangs up as soon as it sees our confirmation, before sending its own
+    match AeadConfig::new().psk(b"guess").connect(client) {
+        Err(AeadError::Handshake(_)) | Err(AeadError::Udt(_)) => {}
+        other => panic!("expected a handshake failure, got {:?}", other),
+    }
+
+    server.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "compression")]
+fn test_compressed_stream() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use udt::compression::{Algorithm, CompressionConfig};
+
+    init();
+
+    let csv: Vec<u8> = (0..20_000)
+        .flat_map(|i| format!("{},sensor-{},{}\n", i, i % 7, i * 3).into_bytes())
+        .collect();
+    let expected = csv.clone();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = CompressionConfig::new().accept(stream).unwrap();
+        let mut data = vec![0u8; expected.len()];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(data, expected);
+        stream.write_all(b"done").unwrap();
+        stream.flush().unwrap();
+        let (_, stats) = stream.perfmon().unwrap();
+        stats
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let mut stream = CompressionConfig::new().connect(stream).unwrap();
+    assert_eq!(stream.algorithm(), Algorithm::Zstd);
+    stream.write_all(&csv).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"done");
+
+    let (perf, stats) = stream.perfmon().unwrap();
+    assert!(perf.pkt_sent_total > 0);
+    assert_eq!(stats.raw_sent, csv.len() as u64);
+    assert!(stats.send_ratio() > 3.0, "ratio {}", stats.send_ratio());
+    let server_stats = server.join().unwrap();
+    assert_eq!(server_stats.wire_received, stats.wire_sent);
+    assert_eq!(server_stats.raw_received, stats.raw_sent);
+}
+
+#[test]
+fn test_keepalive_over_udt() {
+    use std::io::{Read, Write};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+    use udt::keepalive::KeepaliveConfig;
+
+    init();
+
+    let config = KeepaliveConfig::new().interval(Duration::from_millis(20));
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server_config = config.clone();
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = server_config.wrap(stream);
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        // stay connected but silent, apart from the heartbeats
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+
+    let mut stream = config.wrap(UdtStream::connect(addr).unwrap());
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    sleep(Duration::from_millis(500));
+    assert!(stream.peer_timeout().is_none());
+    assert!(stream.stats().pongs_received > 0);
+    assert!(stream.rtt().unwrap() < Duration::from_secs(1));
+
+    drop(stream);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_mux_over_udt() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use udt::mux::MuxConfig;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mux = MuxConfig::new().server(stream);
+        let mut handlers = Vec::new();
+        for _ in 0..4 {
+            let mut chan = mux.accept().unwrap();
+            handlers.push(spawn(move || {
+                let mut data = Vec::new();
+                chan.read_to_end(&mut data).unwrap();
+                chan.write_all(&data).unwrap();
+            }));
+        }
+        for h in handlers {
+            h.join().unwrap();
+        }
+    });
+
+    let mux = MuxConfig::new().client(UdtStream::connect(addr).unwrap());
+    let clients: Vec<_> = (0..4u8)
+        .map(|i| {
+            let mut chan = mux.open().unwrap();
+            spawn(move || {
+                let data = vec![i; 100_000 * (i as usize + 1)];
+                chan.write_all(&data).unwrap();
+                chan.close();
+                let mut echoed = Vec::new();
+                chan.read_to_end(&mut echoed).unwrap();
+                assert!(echoed == data);
+            })
+        })
+        .collect();
+    for c in clients {
+        c.join().unwrap();
+    }
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_split_stream() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        // echo with a reader thread and a writer thread sharing one connection
+        let (stream, _) = listener.accept().unwrap();
+        let (mut read, mut write) = stream.split();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reader = spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                match read.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => tx.send(buf[..n].to_vec()).unwrap(),
+                }
+            }
+        });
+        for chunk in rx {
+            write.write_all(&chunk).unwrap();
+        }
+        reader.join().unwrap();
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let mut reader = stream.try_clone().unwrap();
+    let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let writer = spawn(move || {
+        let mut stream = stream;
+        stream.write_all(&data).unwrap();
+        // the clone keeps the socket open after this handle is dropped
+    });
+    let mut reply = vec![0u8; expected.len()];
+    reader.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+    writer.join().unwrap();
+    assert!(reader.write_all(b"more").is_ok());
+    reader.close().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn test_bounded_sender() {
+    use std::io::Read;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+    use udt::backpressure::BackpressureConfig;
+
+    init();
+
+    let listener = localhost_listener();
+    // a small receive window so the sender backs up while the server isn't reading
+    listener
+        .socket()
+        .setsockopt(UdtOpts::UDT_RCVBUF, 256 * 1024)
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        sleep(Duration::from_millis(500));
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        data.len()
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let high_water = 512 * 1024;
+    let sender = BackpressureConfig::new()
+        .high_water(high_water)
+        .wrap(stream);
+    let block = vec![1u8; 64 * 1024];
+    for _ in 0..128 {
+        sender.send(&block).unwrap();
+        // at most one chunk (high - low, 256 KiB) and a packet over the mark
+        assert!(sender.pending_send_bytes().unwrap() <= high_water + 256 * 1024 + 1500);
+    }
+    assert!(sender.pauses() > 0);
+    let stream = sender.into_inner();
+    assert_eq!(stream.available_bytes().unwrap(), 0);
+    assert_eq!(
+        stream.flush_and_close(Duration::from_secs(30)).unwrap(),
+        None
+    );
+    assert_eq!(server.join().unwrap(), 128 * 64 * 1024);
+}
+
+#[test]
+fn test_state_watcher() {
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::watch::WatchConfig;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || listener.accept().unwrap().0);
+    let client = UdtStream::connect(addr).unwrap();
+    let accepted = server.join().unwrap();
+    assert_eq!(
+        accepted
+            .socket()
+            .wait_for_state(&[UdtStatus::CONNECTED], Duration::from_secs(5))
+            .unwrap(),
+        UdtStatus::CONNECTED
+    );
+
+    let (watcher, changes) = WatchConfig::new()
+        .interval(Duration::from_millis(10))
+        .channel();
+    let sock = *accepted.socket();
+    watcher.watch(sock);
+    assert_eq!(watcher.state(sock), Some(UdtStatus::CONNECTED));
+
+    // the peer going away breaks the accepted socket
+    client.close().unwrap();
+    let change = changes.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!((change.socket, change.from), (sock, UdtStatus::CONNECTED));
+    assert_ne!(change.to, UdtStatus::CONNECTED);
+
+    drop(accepted);
+    sock.wait_for_state(
+        &[UdtStatus::CLOSED, UdtStatus::NONEXIST],
+        Duration::from_secs(10),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_bdp_tuning() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::tuning::TuningConfig;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+    });
+
+    let stream = UdtStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let traffic = spawn(move || {
+        let block = vec![0u8; 64 * 1024];
+        for _ in 0..256 {
+            writer.write_all(&block).unwrap();
+        }
+    });
+    let before = stream.socket().options().unwrap();
+    let tuning = TuningConfig::new()
+        .sample_time(Duration::from_millis(500))
+        .sample_interval(Duration::from_millis(20))
+        .tune(stream.socket())
+        .unwrap();
+    assert!(!tuning.decisions.is_empty());
+    // UDT refuses to change these options once the socket is connected
+    assert!(tuning.adjusted.is_empty());
+    assert!(tuning.recommended.fc >= before.fc);
+    assert!(tuning.recommended.rcvbuf >= before.rcvbuf);
+    traffic.join().unwrap();
+    stream.close().unwrap();
+    server.join().unwrap();
+
+    let next = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    tuning.recommended.apply(&next).unwrap();
+    assert_eq!(
+        next.getsockopt(UdtOpts::UDT_FC).unwrap(),
+        tuning.recommended.fc
+    );
+    next.close().unwrap();
+}
+
+#[test]
+fn test_mtu_probe() {
+    use std::io::{Read, Write};
+    use std::net::UdpSocket;
+    use std::thread::spawn;
+    use udt::mtu::MtuConfig;
+
+    init();
+
+    let config = MtuConfig::new().candidates(&[1400, 1200]);
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = udp.local_addr().unwrap();
+    let server = {
+        let config = config.clone();
+        spawn(move || {
+            let (listener, path) = config.listen(udp, 1).unwrap();
+            assert_eq!(path.mss, 1400);
+            assert_eq!(
+                listener.socket().getsockopt(UdtOpts::UDT_MSS).unwrap(),
+                1400
+            );
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"hello");
+        })
+    };
+
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (mut stream, path) = config.connect(udp, addr).unwrap();
+    assert_eq!(path.peer, addr);
+    assert_eq!(path.mss, 1400);
+    assert_eq!(stream.socket().getsockopt(UdtOpts::UDT_MSS).unwrap(), 1400);
+    stream.write_all(b"hello").unwrap();
+    server.join().unwrap();
+    stream.close().unwrap();
+}
+
+#[test]
+fn test_buffer_diagnostics() {
+    use udt::diagnostics::{self, BufferWarning};
+
+    init();
+
+    let sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Stream).unwrap();
+    let mut wanted = sock.options().unwrap();
+    wanted.udp_rcvbuf = 512 * 1024 * 1024;
+    sock.apply_options(&wanted).unwrap();
+
+    let report = diagnostics::check_buffers(&sock, &wanted).unwrap();
+    assert_eq!(report.buffers.len(), 4);
+    // Linux caps UDP buffers at rmem_max, which is far below 512 MiB by default
+    let limit = report.limits.rmem_max.filter(|&l| l < 512 * 1024 * 1024);
+    if let Some(limit) = limit {
+        assert!(report.warnings.contains(&BufferWarning::KernelLimit {
+            option: "UDP_RCVBUF",
+            requested: 512 * 1024 * 1024,
+            limit,
+            sysctl: "net.core.rmem_max",
+        }));
+        assert!(report
+            .sysctl
+            .iter()
+            .any(|s| s.to_string() == "net.core.rmem_max=536870912"));
+    }
+    sock.close().unwrap();
+}
+
+#[test]
+fn test_flush_and_close() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use std::time::Duration;
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        data.len()
+    });
+
+    let mut stream = UdtStream::connect(addr).unwrap();
+    stream.write_all(&vec![7u8; 4_000_000]).unwrap();
+    assert_eq!(
+        stream.flush_and_close(Duration::from_secs(30)).unwrap(),
+        None
+    );
+    assert_eq!(server.join().unwrap(), 4_000_000);
+}
+
+#[test]
+fn test_fragmented_datagrams() {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::thread::spawn;
+    use udt::fragment::FragmentConfig;
+
+    init();
+
+    let config = FragmentConfig::new().fragment_size(8000);
+    let mut sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    do_platform_specific_init(&mut sock);
+    let localhost = Ipv4Addr::new(127, 0, 0, 1);
+    sock.bind(SocketAddr::V4(SocketAddrV4::new(localhost, 0)))
+        .unwrap();
+    sock.listen(5).unwrap();
+    let addr = sock.getsockname().unwrap();
+
+    let server_config = config.clone();
+    let server = spawn(move || {
+        let (peer, _) = sock.accept().unwrap();
+        let peer = server_config.wrap(peer);
+        assert_eq!(peer.recv().unwrap(), b"");
+        let big = peer.recv().unwrap();
+        assert_eq!(big.len(), 1_000_000);
+        assert!(big.iter().enumerate().all(|(i, &b)| b == i as u8));
+        assert_eq!(peer.stats().messages_received, 2);
+        sock.close().unwrap();
+    });
+
+    let client = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    client.connect(addr).unwrap();
+    let client = config.wrap(client);
+    client.send(b"").unwrap();
+    let big: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+    client.send(&big).unwrap();
+    assert_eq!(client.stats().fragments_sent, 1 + 125);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_connection_pool() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::pool::{PoolConfig, PoolError};
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        // a single connection serves every request
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 4];
+        while stream.read_exact(&mut buf).is_ok() {
+            stream.write_all(&buf).unwrap();
+        }
+    });
+
+    let pool = PoolConfig::new()
+        .max_per_peer(1)
+        .checkout_timeout(Duration::from_millis(100))
+        .build();
+    for i in 0..10u32 {
+        let mut conn = pool.get(addr).unwrap();
+        conn.write_all(&i.to_be_bytes()).unwrap();
+        let mut reply = [0u8; 4];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(u32::from_be_bytes(reply), i);
+    }
+    let stats = pool.stats();
+    assert_eq!((stats.connects, stats.reuses), (1, 9));
+    assert_eq!(pool.idle(addr), 1);
+
+    let conn = pool.get(addr).unwrap();
+    assert!(matches!(pool.get(addr), Err(PoolError::Exhausted(_))));
+    conn.discard();
+    assert_eq!(pool.open(addr), 0);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_pubsub_fanout() {
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+    use udt::pubsub::{BrokerConfig, Publisher, Subscriber};
+
+    init();
+
+    let broker = BrokerConfig::new()
+        .bind("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let addr = broker.local_addr().unwrap();
+
+    let patterns = ["quotes.>", "quotes.*.IBM", "trades.>"];
+    let subs: Vec<Subscriber> = patterns
+        .iter()
+        .map(|p| {
+            let sub = Subscriber::connect(addr).unwrap();
+            sub.subscribe(p).unwrap();
+            sub
+        })
+        .collect();
+
+    // subscriptions are asynchronous, wait until the broker has seen all of them
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while broker.subscribers().len() < patterns.len() {
+        assert!(Instant::now() < deadline, "subscriptions never arrived");
+        sleep(Duration::from_millis(10));
+    }
+
+    let publisher = Publisher::connect(addr).unwrap();
+    let ttl = Some(Duration::from_secs(10));
+    publisher
+        .publish("quotes.nyse.IBM", b"142.17", ttl)
+        .unwrap();
+    publisher.publish("quotes.nyse.GE", b"11.50", ttl).unwrap();
+    publisher.publish("trades.nyse.GE", b"100", None).unwrap();
+
+    let mut subs = subs.into_iter();
+    let all_quotes = subs.next().unwrap();
+    let ibm = subs.next().unwrap();
+    let trades = subs.next().unwrap();
+
+    let reader = spawn(move || {
+        let a = all_quotes.recv().unwrap();
+        let b = all_quotes.recv().unwrap();
+        (a.topic, b.topic)
+    });
+    let msg = ibm.recv().unwrap();
+    assert_eq!(msg.topic, "quotes.nyse.IBM");
+    assert_eq!(msg.payload, b"142.17");
+    assert_eq!(trades.recv().unwrap().payload, b"100");
+    assert_eq!(
+        reader.join().unwrap(),
+        ("quotes.nyse.IBM".to_string(), "quotes.nyse.GE".to_string())
+    );
+
+    assert_eq!(broker.published(), 3);
+    let delivered: u64 = broker.subscribers().iter().map(|s| s.delivered).sum();
+    assert_eq!(delivered, 4);
+    broker.close();
+}
+
+#[test]
+fn test_resilient_stream() {
+    use std::io::{Read, Write};
+    use std::thread::spawn;
+    use std::time::{Duration, Instant};
+    use udt::resilient::ResilientConfig;
+
+    init();
+
+    let config = ResilientConfig::new();
+    let listener = config.listen(localhost_listener());
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let mut stream = listener.accept().unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 1_000_000);
+        assert!(data.iter().enumerate().all(|(i, &b)| b == i as u8));
+    });
+
+    // a client that never sends its handshake doesn't hold up the next one
+    let silent = UdtStream::connect(addr).unwrap();
+    let start = Instant::now();
+    let mut stream = config.connect(addr).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(4));
+    assert_ne!(stream.session_id(), 0);
+    let data: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+    stream.write_all(&data).unwrap();
+    stream.close().unwrap();
+    server.join().unwrap();
+    drop(silent);
+}
+
+#[test]
+fn test_rpc_over_udt() {
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::sync::Arc;
+    use std::thread::spawn;
+    use std::time::Duration;
+    use udt::fragment::FragmentConfig;
+    use udt::rpc::{Client, RpcError, Server};
+
+    init();
+
+    let mut sock = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    do_platform_specific_init(&mut sock);
+    let localhost = Ipv4Addr::new(127, 0, 0, 1);
+    sock.bind(SocketAddr::V4(SocketAddrV4::new(localhost, 0)))
+        .unwrap();
+    sock.listen(5).unwrap();
+    let addr = sock.getsockname().unwrap();
+
+    let server = spawn(move || {
+        let (peer, _) = sock.accept().unwrap();
+        let server = Server::new()
+            .workers(8)
+            .register("echo", |req| Ok(req.payload().to_vec()))
+            .register("len", |req| {
+                Ok((req.payload().len() as u32).to_be_bytes().to_vec())
+            });
+        server.serve(FragmentConfig::new().wrap(peer)).unwrap();
+        sock.close().unwrap();
+    });
+
+    let conn = UdtSocket::new(SocketFamily::AFInet, SocketType::Datagram).unwrap();
+    conn.connect(addr).unwrap();
+    let client = Arc::new(Client::new(FragmentConfig::new().wrap(conn)));
+
+    let callers: Vec<_> = (0..300u32)
+        .map(|i| {
+            let client = client.clone();
+            spawn(move || {
+                let t = Duration::from_secs(30);
+                let payload = i.to_be_bytes();
+                assert_eq!(client.call("echo", &payload, t).unwrap(), payload);
+                let big = vec![0u8; 1000 * i as usize];
+                let len = client.call("len", &big, t).unwrap();
+                assert_eq!(len, (big.len() as u32).to_be_bytes());
+            })
+        })
+        .collect();
+    for c in callers {
+        c.join().unwrap();
+    }
+
+    let err = client
+        .call("missing", b"", Duration::from_secs(30))
+        .unwrap_err();
+    assert!(matches!(err, RpcError::NoSuchMethod(_)));
+    assert_eq!(client.pending_calls(), 0);
+
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_server_event_loop() {
+    use std::io::{self, Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::spawn;
+    use udt::server::{Connection, ConnectionHandler, ServerConfig};
+
+    #[derive(Default)]
+    struct Counters {
+        connected: AtomicUsize,
+        closed: AtomicUsize,
+    }
+
+    struct Echo(Arc<Counters>);
+
+    impl ConnectionHandler for Echo {
+        type State = usize;
+
+        fn on_connect(&self, _: &Connection) -> io::Result<usize> {
+            self.0.connected.fetch_add(1, Ordering::SeqCst);
+            Ok(0)
+        }
+
+        fn on_readable(&self, mut conn: &Connection, echoed: &mut usize) -> io::Result<()> {
+            let mut buf = [0u8; 4096];
+            match conn.read(&mut buf)? {
+                0 => conn.close(),
+                n => {
+                    conn.write_all(&buf[..n])?;
+                    *echoed += n;
+                }
+            }
+            Ok(())
+        }
+
+        fn on_close(&self, _: &Connection, echoed: usize, error: Option<io::Error>) {
+            assert!(error.is_none(), "{:?}", error);
+            assert_eq!(echoed, 10_000);
+            self.0.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    init();
+
+    let counters = Arc::new(Counters::default());
+    let server = ServerConfig::new()
+        .workers(4)
+        .serve(localhost_listener(), Echo(counters.clone()))
+        .unwrap();
+    let addr = server.local_addr();
+
+    let clients: Vec<_> = (0..8u8)
+        .map(|c| {
+            spawn(move || {
+                let mut stream = UdtStream::connect(addr).unwrap();
+                let data = vec![c; 10_000];
+                stream.write_all(&data).unwrap();
+                let mut reply = vec![0u8; data.len()];
+                stream.read_exact(&mut reply).unwrap();
+                assert_eq!(reply, data);
+                // keep the connection open until the server shuts down
+                stream
+            })
+        })
+        .collect();
+    let streams: Vec<_> = clients.into_iter().map(|c| c.join().unwrap()).collect();
+    assert_eq!(counters.connected.load(Ordering::SeqCst), 8);
+    assert_eq!(counters.closed.load(Ordering::SeqCst), 0);
+
+    let report = server.shutdown().unwrap();
+    assert_eq!((report.drained, report.abandoned), (8, 0));
+    assert_eq!(counters.closed.load(Ordering::SeqCst), 8);
+    assert!(UdtStream::connect(addr).is_err());
+    drop(streams);
+}
+
+#[test]
+#[cfg(any(feature = "bincode", feature = "cbor"))]
+fn test_typed_channel() {
+    use serde::{Deserialize, Serialize};
+    use std::thread::spawn;
+    use udt::framed::FramedStream;
+    use udt::typed::{TypedChannel, TypedError};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        values: Vec<f64>,
+    }
+
+    init();
+
+    let listener = localhost_listener();
+    let addr = listener.local_addr().unwrap();
+    let server = spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut chan = TypedChannel::<Reading, _>::new(FramedStream::new(stream), 1);
+        let first = chan.recv().unwrap();
+        assert_eq!(first.sensor, "t1");
+        // a sender built against another schema is rejected without closing the connection
+        match chan.recv() {
+            Err(TypedError::SchemaMismatch { found: 2, .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(chan.recv().unwrap().values, vec![3.5]);
+        assert!(matches!(chan.recv(), Err(TypedError::Closed)));
+    });
+
+    let mut framed = FramedStream::new(UdtStream::connect(addr).unwrap());
+    let reading = |values: Vec<f64>| Reading {
+        sensor: "t1".to_string(),
+        values,
+    };
+    TypedChannel::new(&mut framed, 1)
+        .send(&reading(vec![1.0, 2.0]))
+        .unwrap();
+    TypedChannel::new(&mut framed, 2)
+        .send(&reading(vec![]))
+        .unwrap();
+    TypedChannel::new(&mut framed, 1)
+        .send(&reading(vec![3.5]))
+        .unwrap();
+    drop(framed);
+
+    server.join().unwrap();
+}